@@ -1 +1 @@
-RUST_BACKTRACE=1 cargo run ./wired-cd.torrent download.out
+RUST_BACKTRACE=1 cargo run download ./wired-cd.torrent
@@ -9,11 +9,10 @@ pub enum Statement<'mainbuf> {
 
 pub fn parse<'mainbuf>(buf: &'mainbuf Vec<u8>) -> Result<Vec<Statement<'mainbuf>>, String> {
     let mut idx: usize = 0;
-    let mut statements: Vec<Statement> = Vec::new();
-    statements.reserve(5);
+    let mut statements: Vec<Statement> = Vec::with_capacity(5);
 
     while idx < buf.len() {
-        let res = handle_statement(&buf[idx..]).unwrap();
+        let res = handle_statement(&buf[idx..])?;
         statements.push(res.0);
         idx += res.1 + 1;
     }
@@ -21,6 +20,15 @@ pub fn parse<'mainbuf>(buf: &'mainbuf Vec<u8>) -> Result<Vec<Statement<'mainbuf>
     Ok(statements)
 }
 
+// The first statement of buf and how many bytes it took, whatever follows is left alone
+pub fn parse_first<'mainbuf>(buf: &'mainbuf [u8]) -> Result<(Statement<'mainbuf>, usize), String> {
+    if buf.is_empty() {
+        return Err(String::from("got no statements"));
+    }
+    let (st, end) = handle_statement(buf)?;
+    Ok((st, end + 1))
+}
+
 fn handle_statement<'mainbuf>(buf: &'mainbuf [u8]) -> Result<(Statement<'mainbuf>, usize), String> {
     let mut idx = 0;
 
@@ -36,25 +44,24 @@ fn handle_statement<'mainbuf>(buf: &'mainbuf [u8]) -> Result<(Statement<'mainbuf
                 return Err(String::from("integer has no end"));
             }
             let end = idx;
-            return Ok((
-                Statement::Integer(
-                    str::from_utf8(&buf[begin..end])
-                        .unwrap()
-                        .parse::<i64>()
-                        .unwrap(),
-                ),
-                idx,
-            ));
+            let num = str::from_utf8(&buf[begin..end])
+                .ok()
+                .and_then(|n| n.parse::<i64>().ok())
+                .ok_or(String::from("integer is not a number"))?;
+            return Ok((Statement::Integer(num), idx));
         }
         b'l' => {
             // List
             idx += 1;
             let mut l: Vec<Statement<'mainbuf>> = Vec::new();
             while idx < buf.len() && buf[idx] != b'e' {
-                let res = handle_statement(&buf[idx..]).unwrap();
+                let res = handle_statement(&buf[idx..])?;
                 l.push(res.0);
                 idx += res.1 + 1;
             }
+            if idx >= buf.len() {
+                return Err(String::from("list has no end"));
+            }
             return Ok((Statement::List(l), idx));
         }
         b'd' => {
@@ -62,16 +69,22 @@ fn handle_statement<'mainbuf>(buf: &'mainbuf [u8]) -> Result<(Statement<'mainbuf
             idx += 1;
             let mut m: HashMap<&'mainbuf [u8], Statement<'mainbuf>> = HashMap::new();
             while idx < buf.len() && buf[idx] != b'e' {
-                let key = handle_statement(&buf[idx..]).unwrap();
+                let key = handle_statement(&buf[idx..])?;
                 idx += key.1 + 1;
                 if let Statement::ByteString(key_str) = key.0 {
-                    let value = handle_statement(&buf[idx..]).unwrap();
+                    if idx >= buf.len() {
+                        return Err(String::from("dictionary value is missing"));
+                    }
+                    let value = handle_statement(&buf[idx..])?;
                     idx += value.1 + 1;
                     m.insert(key_str, value.0);
                 } else {
                     return Err(String::from("Dictionary key must be string"));
                 }
             }
+            if idx >= buf.len() {
+                return Err(String::from("dictionary has no end"));
+            }
             return Ok((Statement::Dictionary(m), idx));
         }
         _ => {
@@ -94,17 +107,20 @@ fn handle_statement<'mainbuf>(buf: &'mainbuf [u8]) -> Result<(Statement<'mainbuf
             let strlen = str::from_utf8(&buf[begin..end])
                 .unwrap()
                 .parse::<usize>()
-                .unwrap();
+                .map_err(|_| String::from("string length is not a number"))?;
+            if strlen > buf.len() - idx {
+                return Err(String::from("string is longer than the buffer"));
+            }
             let s = Statement::ByteString(&buf[idx..idx + strlen]);
-            idx += strlen - 1;
+            // Empty strings are valid, e.g. the file keys of v2 file trees
+            idx = idx + strlen - 1;
             return Ok((s, idx));
         }
     }
 }
 
 pub fn marshal(st: &Statement) -> Vec<u8> {
-    let mut buf = Vec::new();
-    buf.reserve(500);
+    let mut buf = Vec::with_capacity(500);
 
     marshal_statement(&mut buf, st);
 
@@ -0,0 +1,179 @@
+use std::io;
+
+use crate::util::easy_err;
+
+// One bit per piece, the high bit of the first byte is piece 0. Same layout as
+// the bitfield message.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Bitfield {
+    bits: Vec<u8>,
+    len: u32,
+}
+
+impl Bitfield {
+    pub fn new(len: u32) -> Self {
+        Bitfield {
+            bits: vec![0; len.div_ceil(8) as usize],
+            len: len,
+        }
+    }
+
+    pub fn full(len: u32) -> Self {
+        let mut b = Bitfield {
+            bits: vec![0xff; len.div_ceil(8) as usize],
+            len: len,
+        };
+        b.clear_spare_bits();
+        b
+    }
+
+    pub fn from_pieces(len: u32, pieces: impl IntoIterator<Item = u32>) -> Self {
+        let mut b = Bitfield::new(len);
+        for p in pieces {
+            b.set(p);
+        }
+        b
+    }
+
+    // Parses the payload of a bitfield message, it has to fit the piece count exactly
+    pub fn from_bytes(bytes: &[u8], len: u32) -> Result<Self, io::Error> {
+        if bytes.len() != len.div_ceil(8) as usize {
+            return Err(easy_err("bitfield length doesn't match the piece count"));
+        }
+        let b = Bitfield {
+            bits: bytes.to_vec(),
+            len: len,
+        };
+        if b.spare_bits()
+            .is_some_and(|(byte, mask)| b.bits[byte] & mask != 0)
+        {
+            return Err(easy_err("bitfield has spare bits set"));
+        }
+        Ok(b)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bits
+    }
+
+    // Number of pieces, not of set bits
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+
+    pub fn has(&self, piece: u32) -> bool {
+        piece < self.len && self.bits[(piece / 8) as usize] & mask(piece) != 0
+    }
+
+    // Pieces beyond the end are ignored, callers check indexes coming from peers
+    pub fn set(self: &mut Self, piece: u32) {
+        if piece < self.len {
+            self.bits[(piece / 8) as usize] |= mask(piece);
+        }
+    }
+
+    pub fn clear(self: &mut Self, piece: u32) {
+        if piece < self.len {
+            self.bits[(piece / 8) as usize] &= !mask(piece);
+        }
+    }
+
+    // Number of set bits
+    pub fn count(&self) -> u32 {
+        self.bits.iter().map(|b| b.count_ones()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bits.iter().all(|b| *b == 0)
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.count() == self.len
+    }
+
+    // The set pieces in ascending order
+    pub fn iter(&self) -> impl Iterator<Item = u32> + '_ {
+        self.bits.iter().enumerate().flat_map(|(i, byte)| {
+            (0..8)
+                .filter(move |bit| byte & (0x80 >> bit) != 0)
+                .map(move |bit| i as u32 * 8 + bit)
+        })
+    }
+
+    // Pieces set in both, the result is as long as the shorter one
+    pub fn intersection(&self, other: &Bitfield) -> Bitfield {
+        let len = self.len.min(other.len);
+        let mut b = Bitfield {
+            bits: self
+                .bits
+                .iter()
+                .zip(&other.bits)
+                .map(|(a, b)| a & b)
+                .collect(),
+            len: len,
+        };
+        b.clear_spare_bits();
+        b
+    }
+
+    // Index of the last byte and the mask of its unused bits
+    fn spare_bits(&self) -> Option<(usize, u8)> {
+        let used = self.len % 8;
+        if used == 0 {
+            return None;
+        }
+        Some((self.bits.len() - 1, 0xff >> used))
+    }
+
+    fn clear_spare_bits(self: &mut Self) {
+        if let Some((byte, mask)) = self.spare_bits() {
+            self.bits[byte] &= !mask;
+        }
+    }
+}
+
+fn mask(piece: u32) -> u8 {
+    0x80 >> (piece % 8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bitfield() {
+        let b = Bitfield::from_pieces(19, (0..19).filter(|p| *p != 3));
+        assert_eq!(b.as_bytes(), [0b11101111, 0b11111111, 0b11100000]);
+        assert_eq!(b.count(), 18);
+        assert!(!b.has(3) && b.has(18) && !b.has(19));
+        assert_eq!(Bitfield::from_bytes(b.as_bytes(), 19).unwrap(), b);
+
+        // No extra byte when the pieces fill the last one
+        assert_eq!(Bitfield::full(16).as_bytes(), [0xff, 0xff]);
+        assert_eq!(Bitfield::new(0).as_bytes(), []);
+        assert!(Bitfield::full(16).is_full());
+
+        assert!(Bitfield::from_bytes(&[0xff, 0xff], 9).is_err());
+        assert!(Bitfield::from_bytes(&[0xff, 0x80], 9).is_ok());
+        assert!(Bitfield::from_bytes(&[0xff, 0xc0], 9).is_err());
+        assert!(Bitfield::from_bytes(&[0xff], 16).is_err());
+
+        let other = Bitfield::from_pieces(19, [1, 3, 5, 18]);
+        let both = b.intersection(&other);
+        assert_eq!(both.iter().collect::<Vec<u32>>(), vec![1, 5, 18]);
+        assert_eq!(
+            Bitfield::full(9)
+                .intersection(&Bitfield::full(4))
+                .as_bytes(),
+            [0xf0]
+        );
+
+        let mut b = Bitfield::new(10);
+        assert!(b.is_empty());
+        b.set(9);
+        b.set(10);
+        assert_eq!(b.iter().collect::<Vec<u32>>(), vec![9]);
+        b.clear(9);
+        assert!(b.is_empty());
+    }
+}
@@ -0,0 +1,228 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    time,
+};
+
+use crate::peer::Peer;
+
+// Candidates are forgotten after this many connection attempts failed in a row
+const MAX_FAILURES: u32 = 5;
+// Doubles with every failed attempt
+const RECONNECT_DELAY: time::Duration = time::Duration::from_secs(5);
+const MAX_RECONNECT_DELAY: time::Duration = time::Duration::from_secs(300);
+
+// Where we learned about a peer
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PeerSource {
+    Tracker,
+    #[allow(dead_code)] // no dht yet
+    Dht,
+    #[allow(dead_code)] // no pex yet
+    Pex,
+    // Connected to us, we don't know its listen port
+    Incoming,
+}
+
+impl PeerSource {
+    fn score(self) -> i32 {
+        match self {
+            PeerSource::Tracker => 3,
+            PeerSource::Dht | PeerSource::Pex => 2,
+            PeerSource::Incoming => 1,
+        }
+    }
+}
+
+struct Candidate {
+    // Taken while the pool is connected or connecting to the peer
+    peer: Option<Peer>,
+    source: PeerSource,
+    // Completed handshakes
+    successes: u32,
+    // Failed attempts since the last success
+    failures: u32,
+    retry_at: Option<time::Instant>,
+}
+
+impl Candidate {
+    // Peers we talked to before are tried first, peers that keep failing last
+    fn score(&self) -> i32 {
+        self.source.score() + 4 * self.successes.min(3) as i32 - 2 * self.failures as i32
+    }
+}
+
+// Every peer address of a torrent, whether it's connected or waiting for the next attempt.
+// Addresses are only known once, no matter how often trackers return them.
+pub struct CandidateList {
+    candidates: HashMap<SocketAddr, Candidate>,
+}
+
+impl CandidateList {
+    pub fn new() -> Self {
+        CandidateList {
+            candidates: HashMap::new(),
+        }
+    }
+
+    // Returns false if the address is known already
+    pub fn add(self: &mut Self, peer: Peer, source: PeerSource) -> bool {
+        if let Some(c) = self.candidates.get_mut(&peer.addr()) {
+            if source.score() > c.source.score() {
+                c.source = source;
+            }
+            return false;
+        }
+        self.candidates.insert(
+            peer.addr(),
+            Candidate {
+                peer: Some(peer),
+                source: source,
+                successes: 0,
+                failures: 0,
+                retry_at: None,
+            },
+        );
+        true
+    }
+
+    // For peers that connected to us
+    pub fn add_connected(self: &mut Self, addr: SocketAddr, source: PeerSource) {
+        self.candidates.entry(addr).or_insert(Candidate {
+            peer: None,
+            source: source,
+            successes: 0,
+            failures: 0,
+            retry_at: None,
+        });
+    }
+
+    // Hands out the best candidate that may be connected now. It's given back
+    // with failed or closed.
+    pub fn next(self: &mut Self, now: time::Instant) -> Option<Peer> {
+        let c = self
+            .candidates
+            .values_mut()
+            .filter(|c| c.peer.is_some() && c.retry_at.is_none_or(|t| t <= now))
+            .max_by_key(|c| c.score())?;
+        c.peer.take()
+    }
+
+    // The handshake went through
+    pub fn connected(self: &mut Self, addr: SocketAddr) {
+        if let Some(c) = self.candidates.get_mut(&addr) {
+            c.successes += 1;
+            c.failures = 0;
+        }
+    }
+
+    // The connection attempt failed, the peer is tried again later unless it failed too often
+    pub fn failed(self: &mut Self, peer: Peer) {
+        let addr = peer.addr();
+        let c = match self.candidates.get_mut(&addr) {
+            Some(c) => c,
+            None => return,
+        };
+        c.failures += 1;
+        if c.failures >= MAX_FAILURES {
+            println!("forgetting peer {}, failed too many times", addr);
+            self.candidates.remove(&addr);
+            return;
+        }
+        c.retry_at = Some(time::Instant::now() + reconnect_delay(c.failures));
+        c.peer = Some(peer);
+    }
+
+    // A connected peer was disconnected and may be connected again later
+    pub fn closed(self: &mut Self, peer: Peer) {
+        let addr = peer.addr();
+        match self.candidates.get_mut(&addr) {
+            // Its port is the one it connected from, not the one it listens on
+            Some(c) if c.source == PeerSource::Incoming => {
+                self.candidates.remove(&addr);
+            }
+            Some(c) => {
+                c.retry_at = Some(time::Instant::now() + RECONNECT_DELAY);
+                c.peer = Some(peer);
+            }
+            None => {}
+        }
+    }
+
+    pub fn remove(self: &mut Self, addr: &SocketAddr) {
+        self.candidates.remove(addr);
+    }
+
+    pub fn remove_ip(self: &mut Self, ip: IpAddr) {
+        self.candidates.retain(|addr, _| addr.ip() != ip);
+    }
+
+    // Forgets the candidates we aren't connected to, i.e. once we are seeding
+    pub fn drop_unconnected(self: &mut Self) {
+        self.candidates.retain(|_, c| c.peer.is_none());
+    }
+
+    // Lifts the backoff of every candidate
+    pub fn retry_now(self: &mut Self) {
+        for c in self.candidates.values_mut() {
+            c.retry_at = None;
+        }
+    }
+}
+
+// Waiting time before the next attempt after the given number of failed ones
+fn reconnect_delay(failures: u32) -> time::Duration {
+    let factor = 1 << failures.saturating_sub(1).min(16);
+    (RECONNECT_DELAY * factor).min(MAX_RECONNECT_DELAY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_candidates() {
+        let mut list = CandidateList::new();
+        let now = time::Instant::now();
+        assert!(list.add(Peer::new(0x0a000001, 6881), PeerSource::Dht));
+        assert!(!list.add(Peer::new(0x0a000001, 6881), PeerSource::Tracker));
+        assert!(list.add(Peer::new(0x0a000002, 6881), PeerSource::Dht));
+
+        // The tracker upgraded the first one
+        let first = list.next(now).unwrap();
+        assert_eq!(first.addr().ip(), IpAddr::from([10, 0, 0, 1]));
+        let second = list.next(now).unwrap();
+        assert!(list.next(now).is_none());
+
+        // Failed peers wait, connected ones are preferred afterwards
+        list.failed(first);
+        assert!(list.next(now).is_none());
+        list.connected(second.addr());
+        list.closed(second);
+        list.retry_now();
+        assert_eq!(
+            list.next(now).unwrap().addr().ip(),
+            IpAddr::from([10, 0, 0, 2])
+        );
+
+        // It failed once already
+        let mut first = list.next(now).unwrap();
+        for _ in 2..MAX_FAILURES {
+            list.failed(first);
+            list.retry_now();
+            first = list.next(now).unwrap();
+        }
+        list.failed(first);
+        assert!(list.candidates.len() == 1);
+
+        // Incoming peers aren't connected again
+        let addr = SocketAddr::from(([10, 0, 0, 3], 50000));
+        list.add_connected(addr, PeerSource::Incoming);
+        list.closed(Peer::new(0x0a000003, 50000));
+        assert!(!list.candidates.contains_key(&addr));
+
+        assert_eq!(reconnect_delay(1), RECONNECT_DELAY);
+        assert_eq!(reconnect_delay(3), RECONNECT_DELAY * 4);
+        assert_eq!(reconnect_delay(30), MAX_RECONNECT_DELAY);
+    }
+}
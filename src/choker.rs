@@ -0,0 +1,147 @@
+use std::time;
+
+use mio::Token;
+
+pub const DEFAULT_UPLOAD_SLOTS: usize = 4;
+pub const OPTIMISTIC_UNCHOKE_INTERVAL: time::Duration = time::Duration::from_secs(30);
+// Peers connected this recently are preferred for the optimistic unchoke,
+// they have nothing to offer yet and would never be unchoked otherwise
+const NEW_PEER_AGE: time::Duration = time::Duration::from_secs(60);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SeedChokeMode {
+    // Unchoke the peers we upload to the fastest
+    FastestUpload,
+    // Give every interested peer a turn
+    RoundRobin,
+}
+
+pub struct ChokeCandidate {
+    pub token: Token,
+    pub interested: bool,
+    pub snubbed: bool,
+    // Bytes per second the peer sent us
+    pub download_rate: usize,
+    // Bytes per second we sent the peer
+    pub upload_rate: usize,
+    pub connected_at: time::Instant,
+    pub last_unchoked_at: Option<time::Instant>,
+}
+
+// https://www.bittorrent.org/beps/bep_0003.html#choking-and-optimistic-unchoking
+// One of the slots is reserved for the optimistic unchoke, the others go to the
+// best peers. While downloading those are the ones sending us the most data,
+// snubbed peers only get the optimistic slot.
+pub struct Choker {
+    pub slots: usize,
+    pub seed_mode: SeedChokeMode,
+    optimistic: Option<Token>,
+    last_rotation: Option<time::Instant>,
+}
+
+impl Choker {
+    pub fn new(slots: usize) -> Self {
+        Self {
+            slots: slots,
+            seed_mode: SeedChokeMode::FastestUpload,
+            optimistic: None,
+            last_rotation: None,
+        }
+    }
+
+    // Returns the peers to unchoke, every other peer is to be choked
+    pub fn decide(self: &mut Self, candidates: &[ChokeCandidate], seeding: bool) -> Vec<Token> {
+        let mut ranked: Vec<&ChokeCandidate> = candidates
+            .iter()
+            .filter(|c| c.interested && (seeding || !c.snubbed))
+            .collect();
+        if !seeding {
+            ranked.sort_by_key(|c| std::cmp::Reverse(c.download_rate));
+        } else {
+            match self.seed_mode {
+                SeedChokeMode::FastestUpload => {
+                    ranked.sort_by_key(|c| std::cmp::Reverse(c.upload_rate))
+                }
+                // The ones that waited the longest go first
+                SeedChokeMode::RoundRobin => ranked.sort_by_key(|c| c.last_unchoked_at),
+            }
+        }
+
+        let mut unchoke: Vec<Token> = ranked
+            .iter()
+            .take(self.slots.saturating_sub(1))
+            .map(|c| c.token)
+            .collect();
+        if self.slots == 0 {
+            return unchoke;
+        }
+
+        let keep_optimistic = self.optimistic.is_some_and(|t| {
+            !unchoke.contains(&t) && candidates.iter().any(|c| c.token == t && c.interested)
+        }) && self
+            .last_rotation
+            .is_some_and(|t| t.elapsed() < OPTIMISTIC_UNCHOKE_INTERVAL);
+
+        if !keep_optimistic {
+            self.optimistic = candidates
+                .iter()
+                .filter(|c| c.interested && !unchoke.contains(&c.token))
+                .min_by_key(|c| (c.connected_at.elapsed() >= NEW_PEER_AGE, c.last_unchoked_at))
+                .map(|c| c.token);
+            self.last_rotation = Some(time::Instant::now());
+        }
+
+        if let Some(t) = self.optimistic {
+            unchoke.push(t);
+        }
+
+        unchoke
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(token: usize, download_rate: usize, connected_secs_ago: u64) -> ChokeCandidate {
+        ChokeCandidate {
+            token: Token(token),
+            interested: true,
+            snubbed: false,
+            download_rate: download_rate,
+            upload_rate: 0,
+            connected_at: time::Instant::now() - time::Duration::from_secs(connected_secs_ago),
+            last_unchoked_at: None,
+        }
+    }
+
+    #[test]
+    fn test_leeching_choke() {
+        let mut choker = Choker::new(3);
+        let mut candidates = vec![
+            candidate(1, 100, 300),
+            candidate(2, 500, 300),
+            candidate(3, 300, 300),
+            candidate(4, 0, 300),
+            candidate(5, 0, 5),
+        ];
+        candidates[1].snubbed = true;
+        candidates[3].last_unchoked_at = Some(time::Instant::now());
+
+        // Two regular slots for the best non-snubbed uploaders, the new peer is optimistic
+        let unchoke = choker.decide(&candidates, false);
+        assert_eq!(unchoke, vec![Token(3), Token(1), Token(5)]);
+
+        // The optimistic unchoke sticks until it is rotated
+        candidates[4].connected_at -= NEW_PEER_AGE;
+        candidates[4].last_unchoked_at = Some(time::Instant::now());
+        assert_eq!(choker.decide(&candidates, false)[2], Token(5));
+
+        choker.last_rotation = Some(time::Instant::now() - OPTIMISTIC_UNCHOKE_INTERVAL);
+        assert_eq!(choker.decide(&candidates, false)[2], Token(2));
+
+        // Uninterested peers are never unchoked
+        candidates.iter_mut().for_each(|c| c.interested = false);
+        assert!(choker.decide(&candidates, false).is_empty());
+    }
+}
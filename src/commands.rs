@@ -0,0 +1,602 @@
+use std::{
+    fs::{self, File},
+    io::{self, Read},
+    path::Path,
+    sync::{Arc, mpsc},
+    thread, time,
+};
+
+use crate::{
+    candidates::PeerSource,
+    config::Config,
+    connection_limits::ConnectionLimits,
+    hasher::{DEFAULT_HASH_THREADS, HashOutcome, HashThreads, Hasher},
+    magnet::Magnet,
+    metadata,
+    peer::Peer,
+    session::Session,
+    torrent::{self, Torrent},
+    udp,
+    util::easy_err,
+};
+
+pub const USAGE: &str = "usage: bittorrent <command> [options]
+
+commands:
+  download <torrent file or magnet link> [output dir]
+  seed <torrent file> [data file]
+  info <torrent file>
+  create <file> <output torrent file> [tracker url...]
+  verify <torrent file> [data file]
+  scrape <torrent file>
+  help
+
+options:
+  --config <file>      read settings from a toml file, see src/config.rs
+  --download-dir <dir> --port <port> --interface <ip> --encryption <disabled|enabled|forced>
+  --max-connections <n> --max-half-open <n>
+  --torrent-max-connections <n> --torrent-max-half-open <n>
+  --upload-rate <bytes/s> --download-rate <bytes/s>
+  --torrent-upload-rate <bytes/s> --torrent-download-rate <bytes/s>
+  --peer-upload-rate <bytes/s> --peer-download-rate <bytes/s>
+  --connect-timeout <s> --tracker-timeout <s> --choke-interval <s>
+  --upload-slots <n> --seed-choke-mode <fastest_upload|round_robin>
+  --io-threads <n> --write-cache-size <bytes> --fsync <never|after_write|on_complete>
+  --[no-]utp --[no-]dht --[no-]pex --[no-]lsd
+
+while download or seed run, stdin takes one console command per line";
+
+pub const CONSOLE_USAGE: &str = "console commands:
+  pause                disconnect all peers of the torrent
+  resume               connect to its peers again
+  remove               stop the torrent, downloaded data is kept
+  recheck              hash the data on disk again
+  cancel-recheck       stop a running recheck
+  connections <n> <n>  set the connection and half open limits of the torrent
+  status               print whether the torrent is complete and the recheck progress";
+
+// Wrong arguments, the usage is printed along with the error
+pub fn usage_err(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+// Runs the command named by the first argument, options are parsed out already
+pub fn run(config: &Config, args: &[String]) -> Result<(), io::Error> {
+    let (command, args) = args.split_first().ok_or(usage_err("command is missing"))?;
+    match command.as_str() {
+        "download" => download(config, args),
+        "seed" => seed(config, args),
+        "info" => info(args),
+        "create" => create(args),
+        "verify" => verify(config, args),
+        "scrape" => scrape(config, args),
+        "help" => {
+            println!("{}\n\n{}", USAGE, CONSOLE_USAGE);
+            Ok(())
+        }
+        c => Err(usage_err(&format!("unknown command {}", c))),
+    }
+}
+
+fn download(config: &Config, args: &[String]) -> Result<(), io::Error> {
+    let [source, rest @ ..] = args else {
+        return Err(usage_err("download needs a torrent file or magnet link"));
+    };
+    let dir = match rest {
+        [] => config.download_dir.clone(),
+        [dir] => dir.into(),
+        _ => return Err(usage_err("too many arguments")),
+    };
+
+    let mut session = start_session(config)?;
+    let (torr, peers) = if source.starts_with("magnet:") {
+        let magnet = Magnet::parse(source).map_err(|e| usage_err(&e.to_string()))?;
+        let peers = find_peers(
+            &magnet.trackers,
+            magnet.info_hash,
+            config,
+            session.peer_id(),
+        );
+        (
+            fetch_torrent(&magnet, &peers, config, session.peer_id())?,
+            peers,
+        )
+    } else {
+        let torr = read_torrent(source)?;
+        let peers = find_peers(
+            &torr.announce_urls,
+            torr.info_hash,
+            config,
+            session.peer_id(),
+        );
+        (torr, peers)
+    };
+
+    fs::create_dir_all(&dir)?;
+    let file_name = dir.join(file_name_of(&torr)).display().to_string();
+
+    // An existing file is kept so that a previous download can be resumed
+    let resume = fs::exists(&file_name)?;
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&file_name)?;
+    file.set_len(torr.total_size)?;
+
+    add_torrent(&mut session, config, &torr, &file_name)?;
+    if resume {
+        session.recheck_torrent(&torr.info_hash)?;
+    }
+    if peers.is_empty() {
+        println!("no tracker returned peers, waiting for incoming connections");
+    }
+    session.connect_peers(&torr.info_hash, peers, PeerSource::Tracker)?;
+
+    if !run_torrent(&mut session, &torr.info_hash, true)? {
+        return Err(easy_err(&format!(
+            "{} was removed before it was downloaded",
+            torr.name
+        )));
+    }
+    println!("downloaded {} to {}", torr.name, file_name);
+    Ok(())
+}
+
+// Serves data that is on disk already, runs until it's interrupted or removed
+fn seed(config: &Config, args: &[String]) -> Result<(), io::Error> {
+    let (torr, file_name) = torrent_and_data(config, args, "seed")?;
+    if !fs::exists(&file_name)? {
+        return Err(easy_err(&format!("{} doesn't exist", file_name)));
+    }
+
+    let mut session = start_session(config)?;
+    add_torrent(&mut session, config, &torr, &file_name)?;
+    session.recheck_torrent(&torr.info_hash)?;
+    let peers = find_peers(
+        &torr.announce_urls,
+        torr.info_hash,
+        config,
+        session.peer_id(),
+    );
+    session.connect_peers(&torr.info_hash, peers, PeerSource::Tracker)?;
+    run_torrent(&mut session, &torr.info_hash, false)?;
+    Ok(())
+}
+
+fn info(args: &[String]) -> Result<(), io::Error> {
+    let [file_name] = args else {
+        return Err(usage_err("info needs a torrent file"));
+    };
+    let torr = read_torrent(file_name)?;
+
+    println!("name:       {}", torr.name);
+    println!("info hash:  {}", hex(&torr.info_hash));
+    if let Some(v2) = &torr.v2 {
+        println!("v2 hash:    {}", hex(&v2.info_hash));
+    }
+    println!("size:       {} bytes", torr.total_size);
+    println!(
+        "pieces:     {} of {} bytes",
+        torr.get_total_piece_count(),
+        torr.piece_len
+    );
+    println!("files:");
+    for f in torr.files.iter().filter(|f| !f.pad) {
+        println!("  {} ({} bytes)", f.path.join("/"), f.length);
+    }
+    println!("trackers:");
+    for url in &torr.announce_urls {
+        println!("  {}", url);
+    }
+    if !torr.url_list.is_empty() || !torr.http_seeds.is_empty() {
+        println!("web seeds:");
+        for url in torr.url_list.iter().chain(&torr.http_seeds) {
+            println!("  {}", url);
+        }
+    }
+    Ok(())
+}
+
+// Only single files, that's all the storage supports
+fn create(args: &[String]) -> Result<(), io::Error> {
+    let [input, output, trackers @ ..] = args else {
+        return Err(usage_err(
+            "create needs a file and the torrent file to write",
+        ));
+    };
+    let path = Path::new(input);
+    if !path.is_file() {
+        return Err(easy_err(&format!("{} is not a file", input)));
+    }
+    if fs::exists(output)? {
+        return Err(easy_err(&format!("{} exists already", output)));
+    }
+    let name = path
+        .file_name()
+        .ok_or(easy_err("file has no name"))?
+        .to_string_lossy()
+        .to_string();
+
+    let size = path.metadata()?.len();
+    let mut file = io::BufReader::new(File::open(path)?);
+    let metainfo =
+        torrent::create_metainfo(&name, &mut file, trackers, torrent::default_piece_len(size))?;
+    fs::write(output, &metainfo)?;
+
+    let torr = Torrent::parse(metainfo)?;
+    println!("created {} with info hash {}", output, hex(&torr.info_hash));
+    Ok(())
+}
+
+// Hashes the data on disk, fails if any piece doesn't match
+fn verify(config: &Config, args: &[String]) -> Result<(), io::Error> {
+    let (torr, file_name) = torrent_and_data(config, args, "verify")?;
+    let file = File::open(&file_name)
+        .map_err(|e| easy_err(&format!("failed to open {}: {}", file_name, e)))?;
+    if file.metadata()?.len() < torr.total_size {
+        return Err(easy_err(&format!(
+            "{} is smaller than the torrent",
+            file_name
+        )));
+    }
+
+    let threads = HashThreads::new(DEFAULT_HASH_THREADS);
+    let mut hasher = Hasher::new(threads.sender());
+    hasher.start_recheck(Arc::new(file), &torr);
+    let mut failed = 0;
+    loop {
+        for r in hasher.results() {
+            match r.outcome {
+                HashOutcome::Passed | HashOutcome::Cancelled => {}
+                HashOutcome::Failed(_) => failed += 1,
+                HashOutcome::ReadFailed(e) => return Err(e),
+            }
+        }
+        if hasher.recheck_progress().is_none() {
+            break;
+        }
+        thread::sleep(time::Duration::from_millis(10));
+    }
+
+    let total = torr.get_total_piece_count();
+    println!("{} of {} pieces are complete", total - failed, total);
+    if failed > 0 {
+        return Err(easy_err(&format!(
+            "{} of {} pieces don't match",
+            failed, total
+        )));
+    }
+    Ok(())
+}
+
+fn scrape(config: &Config, args: &[String]) -> Result<(), io::Error> {
+    let [file_name] = args else {
+        return Err(usage_err("scrape needs a torrent file"));
+    };
+    let torr = read_torrent(file_name)?;
+
+    let mut answered = false;
+    for url in &torr.announce_urls {
+        let addr = match udp_tracker_addr(url) {
+            Some(a) => a,
+            None => {
+                println!("{}: skipped, only udp trackers are supported", url);
+                continue;
+            }
+        };
+        let stats = udp::Tracker::new(config.tracker_timeout).and_then(|mut t| {
+            t.initiate(addr)?;
+            t.scrape(torr.info_hash)
+        });
+        match stats {
+            Ok(s) => {
+                answered = true;
+                println!(
+                    "{}: {} seeders, {} leechers, {} completed",
+                    url, s.seeders, s.leechers, s.completed
+                );
+            }
+            Err(e) => println!("{}: failed, {}", url, e),
+        }
+    }
+    if !answered {
+        return Err(easy_err("no tracker answered"));
+    }
+    Ok(())
+}
+
+fn read_torrent(file_name: &str) -> Result<Torrent, io::Error> {
+    let mut content = Vec::new();
+    File::open(file_name)
+        .and_then(|mut f| f.read_to_end(&mut content))
+        .map_err(|e| easy_err(&format!("failed to read {}: {}", file_name, e)))?;
+    Torrent::parse(content.trim_ascii_end().to_vec())
+        .map_err(|e| easy_err(&format!("failed to parse {}: {}", file_name, e)))
+}
+
+// The torrent and its data file, which is in the download dir unless given
+fn torrent_and_data(
+    config: &Config,
+    args: &[String],
+    command: &str,
+) -> Result<(Torrent, String), io::Error> {
+    let (file_name, data) = match args {
+        [f] => (f, None),
+        [f, data] => (f, Some(data)),
+        [] => return Err(usage_err(&format!("{} needs a torrent file", command))),
+        _ => return Err(usage_err("too many arguments")),
+    };
+    let torr = read_torrent(file_name)?;
+    let data = match data {
+        Some(d) => d.clone(),
+        None => config
+            .download_dir
+            .join(file_name_of(&torr))
+            .display()
+            .to_string(),
+    };
+    Ok((torr, data))
+}
+
+// Names that would end up outside of the download dir are replaced by the info hash
+fn file_name_of(torr: &Torrent) -> String {
+    match torr.name.as_str() {
+        "" | "." | ".." => hex(&torr.info_hash),
+        n if n.contains('/') => hex(&torr.info_hash),
+        n => n.to_string(),
+    }
+}
+
+fn start_session(config: &Config) -> Result<Session, io::Error> {
+    let peer_id = create_peer_id(&config.peer_id_prefix);
+    println!("created peer id {}", String::from_utf8_lossy(&peer_id));
+
+    let mut session = Session::new(peer_id, config.listen_addr())?;
+    session.set_disk_config(config.disk)?;
+    session.set_connection_limits(config.limits)?;
+    session.set_default_torrent_connection_limits(config.torrent_limits)?;
+    session.set_rate_limit(config.rate_limit);
+    session.set_count_overhead(config.count_overhead);
+    session.set_encryption_policy(config.encryption);
+    session.set_outgoing_utp(config.utp);
+    session.set_connect_timeout(config.connect_timeout);
+    session.set_choke_interval(config.choke_interval);
+    for (name, enabled) in [
+        ("dht", config.dht),
+        ("pex", config.pex),
+        ("lsd", config.lsd),
+    ] {
+        if enabled {
+            println!("{} is not supported yet, ignoring it", name);
+        }
+    }
+    Ok(session)
+}
+
+// Applies the settings every torrent has its own copy of
+fn add_torrent(
+    session: &mut Session,
+    config: &Config,
+    torr: &Torrent,
+    file_name: &str,
+) -> Result<(), io::Error> {
+    let info_hash = session.add_torrent(torr.clone(), file_name.to_string())?;
+    session.set_torrent_rate_limit(&info_hash, config.torrent_rate_limit)?;
+    session.set_peer_rate_limit(&info_hash, config.peer_rate_limit)?;
+    session.set_upload_slots(&info_hash, config.upload_slots)?;
+    session.set_seed_choke_mode(&info_hash, config.seed_choke_mode)?;
+    Ok(())
+}
+
+// Runs the session until the torrent is downloaded, or for good if not until_complete.
+// Returns false if the torrent was removed from the console first.
+fn run_torrent(
+    session: &mut Session,
+    info_hash: &[u8; 20],
+    until_complete: bool,
+) -> Result<bool, io::Error> {
+    let console = spawn_console();
+    loop {
+        if until_complete && session.is_complete(info_hash)? {
+            return Ok(true);
+        }
+        session.run_once()?;
+        for line in console.try_iter() {
+            match console_command(session, info_hash, &line) {
+                Ok(true) => {}
+                Ok(false) => return Ok(false),
+                Err(e) => println!("{}\n{}", e, CONSOLE_USAGE),
+            }
+        }
+    }
+}
+
+// Lines typed on stdin, read on their own thread so that the session never waits for them
+fn spawn_console() -> mpsc::Receiver<String> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for line in io::stdin().lines() {
+            match line {
+                Ok(l) => {
+                    if tx.send(l).is_err() {
+                        return;
+                    }
+                }
+                Err(_) => return,
+            }
+        }
+    });
+    rx
+}
+
+// Returns false once the torrent is removed
+fn console_command(
+    session: &mut Session,
+    info_hash: &[u8; 20],
+    line: &str,
+) -> Result<bool, io::Error> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    match words.as_slice() {
+        [] => {}
+        ["pause"] => session.pause_torrent(info_hash)?,
+        ["resume"] => session.resume_torrent(info_hash)?,
+        ["connections", max, half_open] => {
+            let limits = ConnectionLimits {
+                max_connections: max
+                    .parse()
+                    .map_err(|_| easy_err("invalid connection limit"))?,
+                max_half_open: half_open
+                    .parse()
+                    .map_err(|_| easy_err("invalid half open limit"))?,
+            };
+            session.set_torrent_connection_limits(info_hash, limits)?
+        }
+        ["recheck"] => session.recheck_torrent(info_hash)?,
+        ["cancel-recheck"] => session.cancel_recheck(info_hash)?,
+        ["status"] => {
+            let state = match session.is_complete(info_hash)? {
+                true => "complete",
+                false => "incomplete",
+            };
+            match session.recheck_progress(info_hash)? {
+                Some((checked, total)) => {
+                    println!("{}, rechecked {} of {} pieces", state, checked, total)
+                }
+                None => println!("{}, not rechecking", state),
+            }
+        }
+        ["remove"] => {
+            session.remove_torrent(info_hash)?;
+            return Ok(false);
+        }
+        _ => {
+            return Err(easy_err(&format!(
+                "unknown console command {}",
+                line.trim()
+            )));
+        }
+    }
+    Ok(true)
+}
+
+// Peers of the first udp tracker that knows any
+fn find_peers(
+    announce_urls: &[String],
+    info_hash: [u8; 20],
+    config: &Config,
+    peer_id: [u8; 20],
+) -> Vec<Peer> {
+    for url in announce_urls {
+        let addr = match udp_tracker_addr(url) {
+            Some(a) => a,
+            None => {
+                println!("skipping non udp tracker {}", url);
+                continue;
+            }
+        };
+        println!("announcing to {}", url);
+        let peers = udp::Tracker::new(config.tracker_timeout).and_then(|mut t| {
+            t.initiate(addr)?;
+            t.announce(info_hash, &peer_id, udp::EVENT_STARTED, config.port)
+        });
+        match peers {
+            Ok(p) if !p.is_empty() => return p,
+            Ok(_) => println!("{} has no peers", url),
+            Err(e) => println!("failed to announce to {} {}", url, e),
+        }
+    }
+    Vec::new()
+}
+
+// The torrent of a magnet link, its info dict comes from the peers
+fn fetch_torrent(
+    magnet: &Magnet,
+    peers: &[Peer],
+    config: &Config,
+    peer_id: [u8; 20],
+) -> Result<Torrent, io::Error> {
+    if peers.is_empty() {
+        return Err(easy_err("no tracker of the magnet link returned peers"));
+    }
+    let info = metadata::fetch(
+        peers,
+        magnet.info_hash,
+        peer_id,
+        config.encryption,
+        config.connect_timeout,
+    )?;
+    let torr = Torrent::parse(torrent::metainfo_of_info(&info, &magnet.trackers)?)?;
+    // Info dicts that aren't bencoded canonically would hash differently once parsed
+    if torr.info_hash != magnet.info_hash {
+        return Err(easy_err("metadata is not bencoded canonically"));
+    }
+    println!("got the metadata of {}", torr.name);
+    Ok(torr)
+}
+
+// host:port of udp://host:port/announce
+fn udp_tracker_addr(url: &str) -> Option<&str> {
+    let rest = url.strip_prefix("udp://")?;
+    rest.split('/').next()
+}
+
+// The prefix, cut at 20 bytes, followed by random digits
+fn create_peer_id(prefix: &str) -> [u8; 20] {
+    let mut random = [0_u8; 20];
+    getrandom::getrandom(&mut random).expect("no randomness for the peer id");
+
+    let mut v: Vec<u8> = prefix.bytes().take(20).collect();
+    let mut random = random.into_iter();
+    while v.len() < 20 {
+        v.push(random.next().unwrap() % 10 + b'0');
+    }
+
+    v.try_into().unwrap()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::tests::{any_addr, temp_file};
+    use crate::torrent::tests::single_file_torrent;
+
+    #[test]
+    fn test_create_peer_id() {
+        let id = create_peer_id("dips-001-");
+        assert_eq!(id[..9], *b"dips-001-");
+        assert!(id[9..].iter().all(|b| b.is_ascii_digit()));
+        assert_eq!(
+            create_peer_id("a-prefix-that-is-way-too-long"),
+            *b"a-prefix-that-is-way"
+        );
+    }
+
+    #[test]
+    fn test_console_command() {
+        let file_name = temp_file("console-test", 16384);
+        let mut session = Session::new([b'a'; 20], any_addr()).unwrap();
+        let info_hash = session
+            .add_torrent(single_file_torrent(16384, 16384), file_name.clone())
+            .unwrap();
+
+        assert!(console_command(&mut session, &info_hash, "pause").unwrap());
+        assert!(console_command(&mut session, &info_hash, " resume ").unwrap());
+        assert!(console_command(&mut session, &info_hash, "").unwrap());
+        assert!(console_command(&mut session, &info_hash, "stop now").is_err());
+        assert!(console_command(&mut session, &info_hash, "connections 10 2").unwrap());
+        assert!(console_command(&mut session, &info_hash, "connections 10 x").is_err());
+        assert!(console_command(&mut session, &info_hash, "recheck").unwrap());
+        assert!(session.recheck_progress(&info_hash).unwrap().is_some());
+        assert!(console_command(&mut session, &info_hash, "status").unwrap());
+        assert!(console_command(&mut session, &info_hash, "cancel-recheck").unwrap());
+        assert!(session.recheck_progress(&info_hash).unwrap().is_none());
+        assert!(!console_command(&mut session, &info_hash, "remove").unwrap());
+        assert!(console_command(&mut session, &info_hash, "pause").is_err());
+        std::fs::remove_file(&file_name).unwrap();
+    }
+}
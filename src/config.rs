@@ -0,0 +1,457 @@
+use std::{
+    fs, io,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    time,
+};
+
+use toml::{Table, Value};
+
+use crate::{
+    choker::{DEFAULT_UPLOAD_SLOTS, SeedChokeMode},
+    connection_limits::{ConnectionLimits, DEFAULT_SESSION_LIMITS, DEFAULT_TORRENT_LIMITS},
+    disk::{DiskConfig, FsyncPolicy},
+    mse::EncryptionPolicy,
+    peer_pool::{DEFAULT_CHOKE_INTERVAL, DEFAULT_CONNECT_TIMEOUT},
+    rate_limit::RateLimit,
+    torrent::DEFAULT_BLOCK_LENGTH,
+    udp::DEFAULT_TRACKER_TIMEOUT,
+    util::easy_err,
+};
+
+// The rest of the 20 bytes are random digits, at least this many
+const MIN_PEER_ID_DIGITS: usize = 8;
+
+// Command line options and the config keys they set
+const OPTIONS: [(&str, &str); 22] = [
+    ("--download-dir", "download_dir"),
+    ("--port", "network.port"),
+    ("--interface", "network.interface"),
+    ("--encryption", "network.encryption"),
+    ("--max-connections", "limits.max_connections"),
+    ("--max-half-open", "limits.max_half_open"),
+    (
+        "--torrent-max-connections",
+        "limits.torrent_max_connections",
+    ),
+    ("--torrent-max-half-open", "limits.torrent_max_half_open"),
+    ("--upload-rate", "limits.upload_rate"),
+    ("--download-rate", "limits.download_rate"),
+    ("--torrent-upload-rate", "limits.torrent_upload_rate"),
+    ("--torrent-download-rate", "limits.torrent_download_rate"),
+    ("--peer-upload-rate", "limits.peer_upload_rate"),
+    ("--peer-download-rate", "limits.peer_download_rate"),
+    ("--connect-timeout", "timeouts.connect"),
+    ("--tracker-timeout", "timeouts.tracker"),
+    ("--choke-interval", "timeouts.choke_interval"),
+    ("--upload-slots", "choking.upload_slots"),
+    ("--seed-choke-mode", "choking.seed_mode"),
+    ("--io-threads", "disk.io_threads"),
+    ("--write-cache-size", "disk.write_cache_size"),
+    ("--fsync", "disk.fsync"),
+];
+// Enabled with --<name>, disabled with --no-<name>
+const TOGGLES: [&str; 4] = ["dht", "pex", "lsd", "utp"];
+
+// Everything that can be set in the config file, e.g.
+//
+//   download_dir = "downloads"
+//   [network]
+//   port = 6881
+//   encryption = "forced"
+//   [limits]
+//   max_connections = 100
+//   upload_rate = 50000
+//   [timeouts]
+//   connect = 5
+//   [choking]
+//   upload_slots = 8
+//   seed_mode = "round_robin"
+//   [disk]
+//   write_cache_size = 8388608
+//   fsync = "never"
+//
+// Rates are bytes per second, 0 is unlimited. Timeouts are seconds.
+// limits.count_overhead counts message framing against the rates too.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    pub download_dir: PathBuf,
+    pub peer_id_prefix: String,
+
+    pub port: u16,
+    pub interface: IpAddr,
+    pub encryption: EncryptionPolicy,
+    // Outgoing connections try uTP first, incoming ones are always accepted
+    pub utp: bool,
+    pub dht: bool,
+    pub pex: bool,
+    pub lsd: bool,
+
+    pub limits: ConnectionLimits,
+    pub torrent_limits: ConnectionLimits,
+    pub rate_limit: RateLimit,
+    // Every torrent and every peer is limited on its own
+    pub torrent_rate_limit: RateLimit,
+    pub peer_rate_limit: RateLimit,
+    pub count_overhead: bool,
+
+    pub connect_timeout: time::Duration,
+    pub tracker_timeout: time::Duration,
+    pub choke_interval: time::Duration,
+
+    // One of the slots is the optimistic unchoke
+    pub upload_slots: usize,
+    pub seed_choke_mode: SeedChokeMode,
+
+    // The write cache size is in bytes
+    pub disk: DiskConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            download_dir: PathBuf::from("."),
+            peer_id_prefix: "dips-001-".to_string(),
+            port: 6881,
+            interface: IpAddr::from([0, 0, 0, 0]),
+            encryption: EncryptionPolicy::default(),
+            utp: false,
+            dht: false,
+            pex: false,
+            lsd: false,
+            limits: DEFAULT_SESSION_LIMITS,
+            torrent_limits: DEFAULT_TORRENT_LIMITS,
+            rate_limit: RateLimit::default(),
+            torrent_rate_limit: RateLimit::default(),
+            peer_rate_limit: RateLimit::default(),
+            count_overhead: false,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            tracker_timeout: DEFAULT_TRACKER_TIMEOUT,
+            choke_interval: DEFAULT_CHOKE_INTERVAL,
+            upload_slots: DEFAULT_UPLOAD_SLOTS,
+            seed_choke_mode: SeedChokeMode::FastestUpload,
+            disk: DiskConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    // Keys missing from the file keep their defaults, unknown ones are an error
+    pub fn parse(text: &str) -> Result<Config, io::Error> {
+        let table: Table = text
+            .parse()
+            .map_err(|e: toml::de::Error| easy_err(&format!("invalid config: {}", e.message())))?;
+
+        let mut config = Config::default();
+        for (key, value) in &table {
+            match value {
+                Value::Table(section) => {
+                    for (k, v) in section {
+                        config.set(&format!("{}.{}", key, k), v)?;
+                    }
+                }
+                v => config.set(key, v)?,
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn load(path: &str) -> Result<Config, io::Error> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| easy_err(&format!("failed to read config {}: {}", path, e)))?;
+        Config::parse(&text)
+    }
+
+    // Loads the file given with --config, if any, and applies the other options on top.
+    // Returns the validated config and the arguments that aren't options.
+    pub fn from_args(args: &[String]) -> Result<(Config, Vec<String>), io::Error> {
+        let mut config = match args.iter().position(|a| a == "--config") {
+            Some(i) => {
+                let path = args.get(i + 1).ok_or(easy_err("--config needs a value"))?;
+                Config::load(path)?
+            }
+            None => Config::default(),
+        };
+
+        let mut rest = Vec::new();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            if arg == "--config" {
+                args.next();
+            } else if let Some((_, key)) = OPTIONS.iter().find(|(opt, _)| opt == arg) {
+                let value = args
+                    .next()
+                    .ok_or(easy_err(&format!("{} needs a value", arg)))?;
+                config.set(key, &Value::String(value.clone()))?;
+            } else if let Some(name) = toggle(arg) {
+                config.set(
+                    &format!("network.{}", name),
+                    &Value::Boolean(!arg.starts_with("--no-")),
+                )?;
+            } else if arg.starts_with("--") {
+                return Err(easy_err(&format!("unknown option {}", arg)));
+            } else {
+                rest.push(arg.clone());
+            }
+        }
+
+        config.validate()?;
+        Ok((config, rest))
+    }
+
+    // Values given on the command line are strings, numbers and flags are parsed from them
+    fn set(self: &mut Self, key: &str, value: &Value) -> Result<(), io::Error> {
+        match key {
+            "download_dir" => self.download_dir = PathBuf::from(string(key, value)?),
+            "peer_id_prefix" => self.peer_id_prefix = string(key, value)?,
+            "network.port" => {
+                self.port = u16::try_from(integer(key, value)?)
+                    .map_err(|_| easy_err("network.port has to be at most 65535"))?
+            }
+            "network.interface" => {
+                self.interface = string(key, value)?
+                    .parse()
+                    .map_err(|_| easy_err("network.interface has to be an ip address"))?
+            }
+            "network.encryption" => {
+                self.encryption = match string(key, value)?.as_str() {
+                    "disabled" => EncryptionPolicy::Disabled,
+                    "enabled" => EncryptionPolicy::Enabled,
+                    "forced" => EncryptionPolicy::Forced,
+                    _ => {
+                        return Err(easy_err(
+                            "network.encryption has to be disabled, enabled or forced",
+                        ));
+                    }
+                }
+            }
+            "network.utp" => self.utp = boolean(key, value)?,
+            "network.dht" => self.dht = boolean(key, value)?,
+            "network.pex" => self.pex = boolean(key, value)?,
+            "network.lsd" => self.lsd = boolean(key, value)?,
+            "limits.max_connections" => self.limits.max_connections = integer(key, value)? as usize,
+            "limits.max_half_open" => self.limits.max_half_open = integer(key, value)? as usize,
+            "limits.torrent_max_connections" => {
+                self.torrent_limits.max_connections = integer(key, value)? as usize
+            }
+            "limits.torrent_max_half_open" => {
+                self.torrent_limits.max_half_open = integer(key, value)? as usize
+            }
+            "limits.upload_rate" => self.rate_limit.upload = rate(key, value)?,
+            "limits.download_rate" => self.rate_limit.download = rate(key, value)?,
+            "limits.torrent_upload_rate" => self.torrent_rate_limit.upload = rate(key, value)?,
+            "limits.torrent_download_rate" => self.torrent_rate_limit.download = rate(key, value)?,
+            "limits.peer_upload_rate" => self.peer_rate_limit.upload = rate(key, value)?,
+            "limits.peer_download_rate" => self.peer_rate_limit.download = rate(key, value)?,
+            "limits.count_overhead" => self.count_overhead = boolean(key, value)?,
+            "timeouts.connect" => self.connect_timeout = seconds(key, value)?,
+            "timeouts.tracker" => self.tracker_timeout = seconds(key, value)?,
+            "timeouts.choke_interval" => self.choke_interval = seconds(key, value)?,
+            "choking.upload_slots" => self.upload_slots = integer(key, value)? as usize,
+            "choking.seed_mode" => {
+                self.seed_choke_mode = match string(key, value)?.as_str() {
+                    "fastest_upload" => SeedChokeMode::FastestUpload,
+                    "round_robin" => SeedChokeMode::RoundRobin,
+                    _ => {
+                        return Err(easy_err(
+                            "choking.seed_mode has to be fastest_upload or round_robin",
+                        ));
+                    }
+                }
+            }
+            "disk.io_threads" => self.disk.io_threads = integer(key, value)? as usize,
+            "disk.write_cache_size" => self.disk.write_cache_size = integer(key, value)? as usize,
+            "disk.fsync" => {
+                self.disk.fsync = match string(key, value)?.as_str() {
+                    "never" => FsyncPolicy::Never,
+                    "after_write" => FsyncPolicy::AfterWrite,
+                    "on_complete" => FsyncPolicy::OnComplete,
+                    _ => {
+                        return Err(easy_err(
+                            "disk.fsync has to be never, after_write or on_complete",
+                        ));
+                    }
+                }
+            }
+            _ => return Err(easy_err(&format!("unknown config key {}", key))),
+        }
+        Ok(())
+    }
+
+    // The download dir is created when downloading if it doesn't exist yet
+    pub fn validate(&self) -> Result<(), io::Error> {
+        if self.download_dir.exists() && !self.download_dir.is_dir() {
+            return Err(easy_err(&format!(
+                "download_dir {} is not a directory",
+                self.download_dir.display()
+            )));
+        }
+        if self.peer_id_prefix.len() > 20 - MIN_PEER_ID_DIGITS
+            || !self.peer_id_prefix.bytes().all(|b| b.is_ascii_graphic())
+        {
+            return Err(easy_err(
+                "peer_id_prefix has to be at most 12 printable ascii characters",
+            ));
+        }
+        self.limits.validate()?;
+        self.torrent_limits.validate()?;
+        if self.torrent_limits.max_connections > self.limits.max_connections {
+            return Err(easy_err(
+                "limits.torrent_max_connections can't be above limits.max_connections",
+            ));
+        }
+        if self.connect_timeout.is_zero()
+            || self.tracker_timeout.is_zero()
+            || self.choke_interval.is_zero()
+        {
+            return Err(easy_err("timeouts have to be at least a second"));
+        }
+        if self.upload_slots == 0 {
+            return Err(easy_err("choking.upload_slots has to be at least 1"));
+        }
+        if self.disk.io_threads == 0 {
+            return Err(easy_err("disk.io_threads has to be at least 1"));
+        }
+        if self.disk.write_cache_size < DEFAULT_BLOCK_LENGTH as usize {
+            return Err(easy_err(&format!(
+                "disk.write_cache_size has to be at least {} bytes",
+                DEFAULT_BLOCK_LENGTH
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn listen_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.interface, self.port)
+    }
+}
+
+// The name of a --<name> or --no-<name> flag
+fn toggle(arg: &str) -> Option<&str> {
+    let name = arg.strip_prefix("--")?;
+    let name = name.strip_prefix("no-").unwrap_or(name);
+    TOGGLES.iter().find(|t| **t == name).copied()
+}
+
+fn string(key: &str, value: &Value) -> Result<String, io::Error> {
+    match value {
+        Value::String(s) => Ok(s.clone()),
+        _ => Err(easy_err(&format!("{} has to be a string", key))),
+    }
+}
+
+fn integer(key: &str, value: &Value) -> Result<u64, io::Error> {
+    let n = match value {
+        Value::Integer(n) => u64::try_from(*n).ok(),
+        Value::String(s) => s.parse().ok(),
+        _ => None,
+    };
+    n.ok_or(easy_err(&format!("{} has to be a positive number", key)))
+}
+
+fn boolean(key: &str, value: &Value) -> Result<bool, io::Error> {
+    match value {
+        Value::Boolean(b) => Ok(*b),
+        Value::String(s) if s == "true" || s == "false" => Ok(s == "true"),
+        _ => Err(easy_err(&format!("{} has to be true or false", key))),
+    }
+}
+
+// 0 is unlimited
+fn rate(key: &str, value: &Value) -> Result<Option<u64>, io::Error> {
+    Ok(Some(integer(key, value)?).filter(|r| *r > 0))
+}
+
+fn seconds(key: &str, value: &Value) -> Result<time::Duration, io::Error> {
+    Ok(time::Duration::from_secs(integer(key, value)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(s: &str) -> Vec<String> {
+        s.split_whitespace().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn test_config() {
+        let config = Config::parse(
+            r#"
+            peer_id_prefix = "test-"
+            [network]
+            port = 7000
+            interface = "127.0.0.1"
+            encryption = "forced"
+            utp = true
+            [limits]
+            max_connections = 100
+            torrent_max_half_open = 4
+            upload_rate = 1000
+            download_rate = 0
+            peer_upload_rate = 500
+            count_overhead = true
+            [timeouts]
+            tracker = 15
+            [choking]
+            seed_mode = "round_robin"
+            [disk]
+            fsync = "after_write"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.listen_addr(),
+            SocketAddr::from(([127, 0, 0, 1], 7000))
+        );
+        assert_eq!(config.encryption, EncryptionPolicy::Forced);
+        assert!(config.utp && !config.dht);
+        assert_eq!(config.limits.max_connections, 100);
+        assert_eq!(
+            config.limits.max_half_open,
+            DEFAULT_SESSION_LIMITS.max_half_open
+        );
+        assert_eq!(config.torrent_limits.max_half_open, 4);
+        assert_eq!(config.rate_limit.upload, Some(1000));
+        assert_eq!(config.rate_limit.download, None);
+        assert_eq!(config.peer_rate_limit.upload, Some(500));
+        assert_eq!(config.torrent_rate_limit, RateLimit::default());
+        assert!(config.count_overhead);
+        assert_eq!(config.tracker_timeout, time::Duration::from_secs(15));
+        assert_eq!(config.seed_choke_mode, SeedChokeMode::RoundRobin);
+        assert_eq!(config.upload_slots, DEFAULT_UPLOAD_SLOTS);
+        assert_eq!(config.disk.fsync, FsyncPolicy::AfterWrite);
+        assert!(config.validate().is_ok());
+
+        assert!(Config::parse("port = 7000").is_err());
+        assert!(Config::parse("[network]\nport = 70000").is_err());
+        assert!(Config::parse("[network]\nport = \"abc\"").is_err());
+        assert!(Config::parse("[network]\nencryption = \"maybe\"").is_err());
+        assert!(Config::parse("[limits]\nmax_connections = -1").is_err());
+        assert!(Config::parse("[network\n").is_err());
+
+        // Options override the file, everything else is left over
+        let (config, rest) = Config::from_args(&args(
+            "download a.torrent --port 7001 --no-utp --dht --max-connections 80 --torrent-download-rate 9000 out",
+        ))
+        .unwrap();
+        assert_eq!(rest, args("download a.torrent out"));
+        assert_eq!(config.port, 7001);
+        assert!(!config.utp && config.dht);
+        assert_eq!(config.limits.max_connections, 80);
+        assert_eq!(config.torrent_rate_limit.download, Some(9000));
+
+        assert!(Config::from_args(&args("--port")).is_err());
+        assert!(Config::from_args(&args("--frobnicate")).is_err());
+        assert!(Config::from_args(&args("--max-connections 0")).is_err());
+        assert!(Config::from_args(&args("--torrent-max-connections 300")).is_err());
+        assert!(Config::from_args(&args("--connect-timeout 0")).is_err());
+        assert!(Config::from_args(&args("--upload-slots 0")).is_err());
+        assert!(Config::from_args(&args("--seed-choke-mode random")).is_err());
+        assert!(Config::from_args(&args("--io-threads 0")).is_err());
+        assert!(Config::from_args(&args("--write-cache-size 100")).is_err());
+        assert!(Config::from_args(&args("--download-dir /nonexistent/dir")).is_ok());
+        assert!(Config::from_args(&args("--download-dir Cargo.toml")).is_err());
+        assert!(Config::from_args(&args("--config /nonexistent/config.toml")).is_err());
+    }
+}
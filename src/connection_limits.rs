@@ -0,0 +1,181 @@
+use std::{io, time};
+
+use mio::Token;
+
+use crate::util::easy_err;
+
+// Peers connected this recently aren't replaced, they haven't had a chance to show what they're worth
+const MIN_PEER_AGE: time::Duration = time::Duration::from_secs(60);
+
+// Limits of the whole session or of a single torrent. Half open connections
+// are the ones whose connect or handshake hasn't finished yet.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ConnectionLimits {
+    pub max_connections: usize,
+    pub max_half_open: usize,
+}
+
+pub const DEFAULT_SESSION_LIMITS: ConnectionLimits = ConnectionLimits {
+    max_connections: 200,
+    max_half_open: 16,
+};
+
+pub const DEFAULT_TORRENT_LIMITS: ConnectionLimits = ConnectionLimits {
+    max_connections: 64,
+    max_half_open: 8,
+};
+
+impl ConnectionLimits {
+    pub fn validate(&self) -> Result<(), io::Error> {
+        if self.max_connections == 0 || self.max_half_open == 0 {
+            return Err(easy_err("connection limits have to be at least 1"));
+        }
+        if self.max_half_open > self.max_connections {
+            return Err(easy_err(
+                "half open limit can't be above the connection limit",
+            ));
+        }
+        Ok(())
+    }
+
+    // What's left once the given connections are open
+    pub fn free(&self, connections: usize, half_open: usize) -> ConnectionSlots {
+        ConnectionSlots {
+            connections: self.max_connections.saturating_sub(connections),
+            half_open: self.max_half_open.saturating_sub(half_open),
+        }
+    }
+}
+
+// Connections that may still be opened. The session counts what's left of
+// its limits before handing them to the torrents, which take from them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ConnectionSlots {
+    pub connections: usize,
+    pub half_open: usize,
+}
+
+impl ConnectionSlots {
+    // The lower of both counts
+    pub fn min(self, other: ConnectionSlots) -> ConnectionSlots {
+        ConnectionSlots {
+            connections: self.connections.min(other.connections),
+            half_open: self.half_open.min(other.half_open),
+        }
+    }
+
+    pub fn can_connect(&self) -> bool {
+        self.connections > 0 && self.half_open > 0
+    }
+
+    pub fn take_connect(self: &mut Self) {
+        self.connections = self.connections.saturating_sub(1);
+        self.half_open = self.half_open.saturating_sub(1);
+    }
+
+    // Incoming connections are established already
+    pub fn take_incoming(self: &mut Self) {
+        self.connections = self.connections.saturating_sub(1);
+    }
+}
+
+pub struct ReplaceCandidate {
+    pub token: Token,
+    // Whether either side is interested in the other
+    pub useful: bool,
+    // Bytes per second we get from the peer, or send it once we are seeding
+    pub rate: usize,
+    pub connected_at: time::Instant,
+}
+
+// Picks the peer to disconnect to make room for another one. Peers nobody is
+// interested in go first, then the slowest. With a min age, younger peers are kept.
+pub fn worst_peer(
+    peers: &[ReplaceCandidate],
+    min_age: Option<time::Duration>,
+    now: time::Instant,
+) -> Option<Token> {
+    peers
+        .iter()
+        .filter(|p| min_age.is_none_or(|age| now.duration_since(p.connected_at) >= age))
+        .min_by_key(|p| (p.useful, p.rate, std::cmp::Reverse(p.connected_at)))
+        .map(|p| p.token)
+}
+
+// Only peers that were given a chance are replaced by new ones
+pub fn replaceable_peer(peers: &[ReplaceCandidate], now: time::Instant) -> Option<Token> {
+    worst_peer(peers, Some(MIN_PEER_AGE), now)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(
+        token: usize,
+        useful: bool,
+        rate: usize,
+        connected_secs_ago: u64,
+    ) -> ReplaceCandidate {
+        ReplaceCandidate {
+            token: Token(token),
+            useful: useful,
+            rate: rate,
+            connected_at: time::Instant::now() - time::Duration::from_secs(connected_secs_ago),
+        }
+    }
+
+    #[test]
+    fn test_worst_peer() {
+        let now = time::Instant::now();
+        let peers = vec![
+            candidate(1, true, 100, 600),
+            candidate(2, true, 10, 600),
+            candidate(3, false, 1000, 600),
+            candidate(4, false, 0, 10),
+        ];
+        // The new peer is kept, of the others the useless one goes first
+        assert_eq!(replaceable_peer(&peers, now), Some(Token(3)));
+        assert_eq!(worst_peer(&peers, None, now), Some(Token(4)));
+        assert_eq!(replaceable_peer(&peers[1..2], now), Some(Token(2)));
+        assert_eq!(replaceable_peer(&peers[3..], now), None);
+        assert_eq!(replaceable_peer(&[], now), None);
+
+        let limits = ConnectionLimits {
+            max_connections: 10,
+            max_half_open: 2,
+        };
+        let mut slots = limits.free(8, 1);
+        assert!(slots.can_connect());
+        slots.take_connect();
+        assert!(!slots.can_connect());
+        slots.take_incoming();
+        assert_eq!(slots.connections, 0);
+        assert_eq!(
+            limits.free(12, 0).min(DEFAULT_TORRENT_LIMITS.free(0, 0)),
+            ConnectionSlots {
+                connections: 0,
+                half_open: 2
+            }
+        );
+
+        assert!(DEFAULT_SESSION_LIMITS.validate().is_ok());
+        assert!(DEFAULT_TORRENT_LIMITS.validate().is_ok());
+        assert!(
+            ConnectionLimits {
+                max_connections: 0,
+                max_half_open: 0
+            }
+            .validate()
+            .is_err()
+        );
+        assert!(
+            ConnectionLimits {
+                max_connections: 4,
+                max_half_open: 8
+            }
+            .validate()
+            .is_err()
+        );
+    }
+}
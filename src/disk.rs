@@ -0,0 +1,586 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs, io,
+    os::unix::fs::FileExt,
+    sync::{Arc, Mutex, mpsc},
+    thread, time,
+};
+
+pub const DEFAULT_IO_THREADS: usize = 2;
+pub const DEFAULT_WRITE_CACHE_SIZE: usize = 32 * 1024 * 1024;
+// Verified data is written once this much of it is cached, so that
+// neighbouring pieces end up in a single write
+const FLUSH_THRESHOLD: usize = 1024 * 1024;
+const FLUSH_INTERVAL: time::Duration = time::Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FsyncPolicy {
+    Never,
+    // Every write is synced before it counts as done
+    AfterWrite,
+    // The file is synced once the torrent is complete
+    OnComplete,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DiskConfig {
+    pub io_threads: usize,
+    pub write_cache_size: usize,
+    pub fsync: FsyncPolicy,
+}
+
+impl Default for DiskConfig {
+    fn default() -> Self {
+        Self {
+            io_threads: DEFAULT_IO_THREADS,
+            write_cache_size: DEFAULT_WRITE_CACHE_SIZE,
+            fsync: FsyncPolicy::OnComplete,
+        }
+    }
+}
+
+pub struct DiskJob {
+    file: Arc<fs::File>,
+    kind: DiskJobKind,
+    done: mpsc::Sender<DiskResult>,
+}
+
+enum DiskJobKind {
+    Write {
+        offset: u64,
+        data: Arc<Vec<u8>>,
+        sync: bool,
+    },
+    Sync,
+    Read {
+        piece: u32,
+        read: DiskRead,
+    },
+}
+
+enum DiskResult {
+    Written(u64, Result<(), io::Error>),
+    Synced(Result<(), io::Error>),
+    Read(u32, Result<Vec<u8>, io::Error>),
+}
+
+// Returned by DiskIo::tick, the data is gone and has to be downloaded again
+pub enum DiskFailure {
+    // Pieces with blocks in the failed write
+    Write(Vec<u32>, io::Error),
+    // Any write since the last sync might be lost
+    Sync(io::Error),
+}
+
+// Threads doing the blocking file io of every torrent in the session
+pub struct DiskThreads {
+    jobs: Option<mpsc::Sender<DiskJob>>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+impl DiskThreads {
+    pub fn new(count: usize) -> Self {
+        let (jobs, rx) = mpsc::channel::<DiskJob>();
+        let rx = Arc::new(Mutex::new(rx));
+
+        let mut workers = Vec::new();
+        for _ in 0..count.max(1) {
+            let rx = rx.clone();
+            workers.push(thread::spawn(move || {
+                loop {
+                    let job = match rx.lock().unwrap().recv() {
+                        Ok(j) => j,
+                        // Every sender is gone, the session is shutting down
+                        Err(_) => return,
+                    };
+                    let result = match job.kind {
+                        DiskJobKind::Write { offset, data, sync } => {
+                            let mut r = job.file.write_all_at(&data, offset);
+                            if r.is_ok() && sync {
+                                r = job.file.sync_data();
+                            }
+                            DiskResult::Written(offset, r)
+                        }
+                        DiskJobKind::Sync => DiskResult::Synced(job.file.sync_all()),
+                        DiskJobKind::Read { piece, read } => DiskResult::Read(piece, read.read()),
+                    };
+                    // The torrent might have been removed in the meantime
+                    let _ = job.done.send(result);
+                }
+            }));
+        }
+
+        Self {
+            jobs: Some(jobs),
+            workers: workers,
+        }
+    }
+
+    pub fn sender(&self) -> mpsc::Sender<DiskJob> {
+        self.jobs.as_ref().unwrap().clone()
+    }
+}
+
+impl Drop for DiskThreads {
+    fn drop(&mut self) {
+        self.jobs.take();
+        for w in self.workers.drain(..) {
+            if w.join().is_err() {
+                println!("disk thread panicked");
+            }
+        }
+    }
+}
+
+// A range of the file as it is once the cached and in-flight writes land. The
+// file is only read if the cache doesn't cover the range, that read blocks and
+// is left to the disk or hash threads.
+pub struct DiskRead {
+    file: Option<Arc<fs::File>>,
+    offset: u64,
+    len: usize,
+    // Cached data by position in the range, later parts are newer
+    parts: Vec<(usize, Vec<u8>)>,
+}
+
+impl DiskRead {
+    pub fn from_file(file: Arc<fs::File>, offset: u64, len: usize) -> Self {
+        Self {
+            file: Some(file),
+            offset: offset,
+            len: len,
+            parts: Vec::new(),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn from_data(data: Vec<u8>) -> Self {
+        Self {
+            file: None,
+            offset: 0,
+            len: data.len(),
+            parts: vec![(0, data)],
+        }
+    }
+
+    // Whether reading doesn't touch the file
+    pub fn is_cached(&self) -> bool {
+        self.file.is_none()
+    }
+
+    pub fn read(self: &Self) -> Result<Vec<u8>, io::Error> {
+        let mut buf = vec![0_u8; self.len];
+        if let Some(file) = &self.file {
+            file.read_exact_at(&mut buf, self.offset)?;
+        }
+        for (pos, data) in &self.parts {
+            buf[*pos..*pos + data.len()].copy_from_slice(data);
+        }
+        Ok(buf)
+    }
+}
+
+struct CachedBlock {
+    piece: u32,
+    data: Vec<u8>,
+    verified: bool,
+}
+
+struct InFlightWrite {
+    data: Arc<Vec<u8>>,
+    pieces: Vec<u32>,
+}
+
+// Write-back cache in front of a torrent's file. Received blocks are kept in
+// memory and written by the disk threads later, verified pieces first.
+// Reads see cached and in-flight data, so callers never have to wait for a write.
+pub struct DiskIo {
+    file: Arc<fs::File>,
+    jobs: mpsc::Sender<DiskJob>,
+    done_tx: mpsc::Sender<DiskResult>,
+    done: mpsc::Receiver<DiskResult>,
+    config: DiskConfig,
+
+    // Blocks not handed to the disk threads yet, by file offset
+    cache: BTreeMap<u64, CachedBlock>,
+    cache_size: usize,
+    verified_size: usize,
+    // Writes the disk threads are working on, by file offset
+    in_flight: BTreeMap<u64, InFlightWrite>,
+    in_flight_size: usize,
+    last_flush: time::Instant,
+    // A sync waits for the writes before it, the disk threads run jobs in any order
+    sync_pending: bool,
+    syncing: bool,
+    // Pieces the disk threads are reading, and the reads collected by tick
+    reading: HashSet<u32>,
+    finished_reads: Vec<(u32, Result<Vec<u8>, io::Error>)>,
+}
+
+impl DiskIo {
+    pub fn new(
+        file_name: &str,
+        jobs: mpsc::Sender<DiskJob>,
+        config: DiskConfig,
+    ) -> Result<Self, io::Error> {
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .read(true)
+            .truncate(false)
+            .open(file_name)?;
+        let (done_tx, done) = mpsc::channel();
+
+        Ok(Self {
+            file: Arc::new(file),
+            jobs: jobs,
+            done_tx: done_tx,
+            done: done,
+            config: config,
+            cache: BTreeMap::new(),
+            cache_size: 0,
+            verified_size: 0,
+            in_flight: BTreeMap::new(),
+            in_flight_size: 0,
+            last_flush: time::Instant::now(),
+            sync_pending: false,
+            syncing: false,
+            reading: HashSet::new(),
+            finished_reads: Vec::new(),
+        })
+    }
+
+    pub fn write_block(self: &mut Self, piece: u32, offset: u64, data: Vec<u8>) {
+        self.cache_size += data.len();
+        let old = self.cache.insert(
+            offset,
+            CachedBlock {
+                piece: piece,
+                data: data,
+                verified: false,
+            },
+        );
+        if let Some(b) = old {
+            self.remove_size(&b);
+        }
+    }
+
+    // Data that is written as soon as there is enough of it
+    pub fn piece_verified(self: &mut Self, piece: u32) {
+        for b in self.cache.values_mut() {
+            if b.piece == piece && !b.verified {
+                b.verified = true;
+                self.verified_size += b.data.len();
+            }
+        }
+    }
+
+    // Drops the cached blocks of a piece that failed the hash check
+    pub fn discard_piece(self: &mut Self, piece: u32) {
+        let offsets: Vec<u64> = self
+            .cache
+            .iter()
+            .filter(|(_, b)| b.piece == piece)
+            .map(|(o, _)| *o)
+            .collect();
+        for o in offsets {
+            let b = self.cache.remove(&o).unwrap();
+            self.remove_size(&b);
+        }
+    }
+
+    // Takes the cached and in-flight data now, the file is read later if they don't cover the range
+    pub fn prepare_read(&self, offset: u64, len: usize) -> DiskRead {
+        let end = offset + len as u64;
+        let mut parts = Vec::new();
+        // Cached blocks are newer than in-flight ones
+        let in_flight = self.in_flight.iter().map(|(o, w)| (*o, &w.data[..]));
+        let cached = self.cache.iter().map(|(o, b)| (*o, &b.data[..]));
+        for (o, data) in in_flight.chain(cached) {
+            let start = offset.max(o);
+            let stop = end.min(o + data.len() as u64);
+            if start < stop {
+                parts.push((
+                    (start - offset) as usize,
+                    data[(start - o) as usize..(stop - o) as usize].to_vec(),
+                ));
+            }
+        }
+
+        let mut ranges: Vec<(usize, usize)> =
+            parts.iter().map(|(p, d)| (*p, p + d.len())).collect();
+        ranges.sort();
+        let mut covered = 0;
+        for (start, stop) in ranges {
+            if start > covered {
+                break;
+            }
+            covered = covered.max(stop);
+        }
+
+        DiskRead {
+            file: (covered < len).then(|| self.file.clone()),
+            offset: offset,
+            len: len,
+            parts: parts,
+        }
+    }
+
+    // Reads a piece on the disk threads, the data comes back from finished_reads
+    pub fn read_piece(self: &mut Self, piece: u32, offset: u64, len: usize) {
+        if self.reading.insert(piece) {
+            let read = self.prepare_read(offset, len);
+            self.send(DiskJobKind::Read {
+                piece: piece,
+                read: read,
+            });
+        }
+    }
+
+    pub fn finished_reads(self: &mut Self) -> Vec<(u32, Result<Vec<u8>, io::Error>)> {
+        std::mem::take(&mut self.finished_reads)
+    }
+
+    pub fn file(&self) -> Arc<fs::File> {
+        self.file.clone()
+    }
+
+    // Whether everything received so far has been written, and synced if asked to
+    pub fn is_idle(&self) -> bool {
+        self.cache.is_empty() && self.in_flight.is_empty() && !self.sync_pending && !self.syncing
+    }
+
+    // No new blocks should be requested while this is true
+    pub fn is_full(&self) -> bool {
+        self.cache_size + self.in_flight_size >= self.config.write_cache_size
+    }
+
+    // Collects finished writes and starts new ones when needed
+    pub fn tick(self: &mut Self) -> Vec<DiskFailure> {
+        let mut failures = Vec::new();
+        while let Ok(r) = self.done.try_recv() {
+            match r {
+                DiskResult::Written(offset, result) => {
+                    let w = self.in_flight.remove(&offset).unwrap();
+                    self.in_flight_size -= w.data.len();
+                    if let Err(e) = result {
+                        failures.push(DiskFailure::Write(w.pieces, e));
+                    }
+                }
+                DiskResult::Synced(result) => {
+                    self.syncing = false;
+                    if let Err(e) = result {
+                        failures.push(DiskFailure::Sync(e));
+                    }
+                }
+                DiskResult::Read(piece, result) => {
+                    self.reading.remove(&piece);
+                    self.finished_reads.push((piece, result));
+                }
+            }
+        }
+
+        if self.sync_pending && self.cache.is_empty() && self.in_flight.is_empty() {
+            self.sync_pending = false;
+            self.syncing = true;
+            self.send(DiskJobKind::Sync);
+        }
+
+        if self.cache_size >= self.config.write_cache_size / 2 {
+            // Under pressure unverified blocks go to disk as well
+            self.flush(false);
+        } else if self.verified_size >= FLUSH_THRESHOLD
+            || (self.verified_size > 0 && self.last_flush.elapsed() >= FLUSH_INTERVAL)
+        {
+            self.flush(true);
+        }
+        failures
+    }
+
+    // Writes everything that is cached, e.g. when the torrent is complete
+    pub fn flush_all(self: &mut Self) {
+        self.flush(false);
+    }
+
+    // The file is synced in tick once everything cached is written
+    pub fn sync(self: &mut Self) {
+        if self.config.fsync == FsyncPolicy::OnComplete {
+            self.sync_pending = true;
+        }
+    }
+
+    // Hands contiguous runs of cached blocks to the disk threads as single writes
+    fn flush(self: &mut Self, only_verified: bool) {
+        self.last_flush = time::Instant::now();
+
+        let mut runs: Vec<(u64, Vec<u8>, Vec<u32>)> = Vec::new();
+        let offsets: Vec<u64> = self.cache.keys().copied().collect();
+        for o in offsets {
+            let b = self.cache.get(&o).unwrap();
+            if only_verified && !b.verified {
+                continue;
+            }
+            // Wait for earlier writes to the same place to finish so they can't land out of order
+            if self.overlaps_in_flight(o, b.data.len()) {
+                continue;
+            }
+
+            let b = self.cache.remove(&o).unwrap();
+            self.remove_size(&b);
+            match runs.last_mut() {
+                Some((start, data, pieces)) if *start + data.len() as u64 == o => {
+                    data.extend(b.data);
+                    if !pieces.contains(&b.piece) {
+                        pieces.push(b.piece);
+                    }
+                }
+                _ => runs.push((o, b.data, vec![b.piece])),
+            }
+        }
+
+        let sync = self.config.fsync == FsyncPolicy::AfterWrite;
+        for (offset, data, pieces) in runs {
+            let data = Arc::new(data);
+            self.in_flight_size += data.len();
+            self.in_flight.insert(
+                offset,
+                InFlightWrite {
+                    data: data.clone(),
+                    pieces: pieces,
+                },
+            );
+            self.send(DiskJobKind::Write {
+                offset: offset,
+                data: data,
+                sync: sync,
+            });
+        }
+    }
+
+    fn send(self: &Self, kind: DiskJobKind) {
+        let job = DiskJob {
+            file: self.file.clone(),
+            kind: kind,
+            done: self.done_tx.clone(),
+        };
+        if self.jobs.send(job).is_err() {
+            println!("disk threads are gone");
+        }
+    }
+
+    fn overlaps_in_flight(&self, offset: u64, len: usize) -> bool {
+        self.in_flight
+            .iter()
+            .any(|(o, w)| *o < offset + len as u64 && offset < *o + w.data.len() as u64)
+    }
+
+    fn remove_size(self: &mut Self, b: &CachedBlock) {
+        self.cache_size -= b.data.len();
+        if b.verified {
+            self.verified_size -= b.data.len();
+        }
+    }
+}
+
+impl Drop for DiskIo {
+    fn drop(&mut self) {
+        // Nothing is lost when the torrent is removed, the disk threads finish the writes
+        self.flush(false);
+        if self.sync_pending {
+            // Only the queued jobs hold senders now, recv fails if the disk threads are gone
+            let (tx, _) = mpsc::channel();
+            drop(std::mem::replace(&mut self.done_tx, tx));
+            while !self.in_flight.is_empty() {
+                match self.done.recv() {
+                    Ok(DiskResult::Written(offset, _)) => {
+                        self.in_flight.remove(&offset);
+                    }
+                    Ok(DiskResult::Synced(_)) | Ok(DiskResult::Read(..)) => {}
+                    Err(_) => return,
+                }
+            }
+            self.send(DiskJobKind::Sync);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_back() {
+        let file_name = std::env::temp_dir()
+            .join(format!("disk-test-{}", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        fs::File::create(&file_name).unwrap().set_len(64).unwrap();
+
+        let threads = DiskThreads::new(2);
+        let config = DiskConfig {
+            io_threads: 2,
+            write_cache_size: 48,
+            fsync: FsyncPolicy::AfterWrite,
+        };
+        let mut disk = DiskIo::new(&file_name, threads.sender(), config).unwrap();
+
+        disk.write_block(0, 0, vec![1; 16]);
+        disk.write_block(0, 16, vec![2; 16]);
+        disk.write_block(1, 32, vec![3; 16]);
+        assert!(disk.is_full());
+        // Cached data is readable before it hits the disk, without touching the file
+        let read = disk.prepare_read(8, 16);
+        assert!(read.is_cached());
+        assert_eq!(read.read().unwrap(), [[1; 8], [2; 8]].concat());
+        assert!(!disk.prepare_read(40, 16).is_cached());
+
+        disk.discard_piece(1);
+        assert!(!disk.is_full());
+        disk.piece_verified(0);
+        disk.flush(true);
+        assert!(disk.cache.is_empty());
+        // Both blocks are written at once
+        assert_eq!(disk.in_flight.len(), 1);
+
+        while !disk.in_flight.is_empty() {
+            disk.tick();
+            thread::sleep(time::Duration::from_millis(5));
+        }
+        let mut on_disk = vec![0; 48];
+        fs::File::open(&file_name)
+            .unwrap()
+            .read_exact_at(&mut on_disk, 0)
+            .unwrap();
+        assert_eq!(on_disk, [[1; 16], [2; 16], [0; 16]].concat());
+
+        // Reads on the disk threads see the file and the cache
+        disk.write_block(1, 40, vec![4; 8]);
+        disk.read_piece(1, 24, 24);
+        disk.read_piece(1, 24, 24);
+        let mut reads = Vec::new();
+        while reads.is_empty() {
+            disk.tick();
+            reads = disk.finished_reads();
+            thread::sleep(time::Duration::from_millis(5));
+        }
+        assert_eq!(reads.len(), 1);
+        let (piece, data) = reads.pop().unwrap();
+        assert_eq!(piece, 1);
+        assert_eq!(data.unwrap(), [[2; 8], [0; 8], [4; 8]].concat());
+        disk.discard_piece(1);
+
+        // The sync waits for the writes before it
+        disk.config.fsync = FsyncPolicy::OnComplete;
+        disk.write_block(1, 32, vec![3; 16]);
+        disk.piece_verified(1);
+        disk.flush_all();
+        disk.sync();
+        assert!(!disk.in_flight.is_empty() && !disk.is_idle());
+        while !disk.is_idle() {
+            assert!(disk.tick().is_empty());
+            assert!(!disk.syncing || disk.in_flight.is_empty());
+            thread::sleep(time::Duration::from_millis(5));
+        }
+
+        fs::remove_file(&file_name).unwrap();
+    }
+}
@@ -0,0 +1,346 @@
+use std::{
+    fs, io,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+        mpsc,
+    },
+    thread,
+};
+
+use crate::{
+    disk::DiskRead,
+    merkle::{self, Hash},
+    torrent::{PieceHash, Torrent},
+};
+
+pub const DEFAULT_HASH_THREADS: usize = 2;
+
+pub struct HashJob {
+    piece: u32,
+    source: DiskRead,
+    expected: PieceHash,
+    // Known good v2 block hashes, used to tell which blocks are bad
+    block_hashes: Option<Vec<Hash>>,
+    // Id and cancel flag of the recheck the job belongs to
+    recheck: Option<(u32, Arc<AtomicBool>)>,
+    done: mpsc::Sender<HashResult>,
+}
+
+pub struct HashResult {
+    pub piece: u32,
+    // Id of the recheck, None for downloaded pieces
+    pub recheck: Option<u32>,
+    pub outcome: HashOutcome,
+}
+
+#[derive(Debug)]
+pub enum HashOutcome {
+    Passed,
+    // Indices of the bad blocks, empty if they are unknown
+    Failed(Vec<u32>),
+    Cancelled,
+    ReadFailed(io::Error),
+}
+
+// Threads computing piece hashes for every torrent in the session
+pub struct HashThreads {
+    jobs: Option<mpsc::Sender<HashJob>>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+impl HashThreads {
+    pub fn new(count: usize) -> Self {
+        let (jobs, rx) = mpsc::channel::<HashJob>();
+        let rx = Arc::new(Mutex::new(rx));
+
+        let mut workers = Vec::new();
+        for _ in 0..count.max(1) {
+            let rx = rx.clone();
+            workers.push(thread::spawn(move || {
+                loop {
+                    let job = match rx.lock().unwrap().recv() {
+                        Ok(j) => j,
+                        Err(_) => return,
+                    };
+                    let result = HashResult {
+                        piece: job.piece,
+                        recheck: job.recheck.as_ref().map(|r| r.0),
+                        outcome: hash_piece(&job),
+                    };
+                    let _ = job.done.send(result);
+                }
+            }));
+        }
+
+        Self {
+            jobs: Some(jobs),
+            workers: workers,
+        }
+    }
+
+    pub fn sender(&self) -> mpsc::Sender<HashJob> {
+        self.jobs.as_ref().unwrap().clone()
+    }
+}
+
+impl Drop for HashThreads {
+    fn drop(&mut self) {
+        self.jobs.take();
+        for w in self.workers.drain(..) {
+            if w.join().is_err() {
+                println!("hash thread panicked");
+            }
+        }
+    }
+}
+
+fn hash_piece(job: &HashJob) -> HashOutcome {
+    if job
+        .recheck
+        .as_ref()
+        .is_some_and(|(_, cancel)| cancel.load(Ordering::Relaxed))
+    {
+        return HashOutcome::Cancelled;
+    }
+
+    let data = match job.source.read() {
+        Ok(d) => d,
+        Err(e) => return HashOutcome::ReadFailed(e),
+    };
+
+    match job.expected {
+        PieceHash::V1(expected) => {
+            if sha1_smol::Sha1::from(&data).digest().bytes() == expected {
+                return HashOutcome::Passed;
+            }
+            HashOutcome::Failed(Vec::new())
+        }
+        PieceHash::V2 { root, len, width } => {
+            let blocks = merkle::block_hashes(&data[..data.len().min(len as usize)]);
+            if merkle::root(&blocks, width as usize, merkle::ZERO_HASH) == root {
+                return HashOutcome::Passed;
+            }
+            let bad = match &job.block_hashes {
+                Some(known) => (0..blocks.len())
+                    .filter(|i| known.get(*i) != Some(&blocks[*i]))
+                    .map(|i| i as u32)
+                    .collect(),
+                None => Vec::new(),
+            };
+            HashOutcome::Failed(bad)
+        }
+    }
+}
+
+struct Recheck {
+    id: u32,
+    cancel: Arc<AtomicBool>,
+    total: u32,
+    checked: u32,
+}
+
+// Hands a torrent's pieces to the hash threads and collects the results
+pub struct Hasher {
+    jobs: mpsc::Sender<HashJob>,
+    done_tx: mpsc::Sender<HashResult>,
+    done: mpsc::Receiver<HashResult>,
+    recheck: Option<Recheck>,
+    next_recheck_id: u32,
+}
+
+impl Hasher {
+    pub fn new(jobs: mpsc::Sender<HashJob>) -> Self {
+        let (done_tx, done) = mpsc::channel();
+        Self {
+            jobs: jobs,
+            done_tx: done_tx,
+            done: done,
+            recheck: None,
+            next_recheck_id: 0,
+        }
+    }
+
+    // The piece is read on the hash thread, from the write cache if it covers the piece
+    pub fn verify(
+        self: &mut Self,
+        piece: u32,
+        data: DiskRead,
+        expected: PieceHash,
+        block_hashes: Option<Vec<Hash>>,
+    ) {
+        self.send(piece, data, expected, block_hashes, None);
+    }
+
+    // Hashes every piece as it is on disk, a running recheck is cancelled first
+    pub fn start_recheck(self: &mut Self, file: Arc<fs::File>, torrent: &Torrent) {
+        self.cancel_recheck();
+
+        let id = self.next_recheck_id;
+        self.next_recheck_id += 1;
+        let cancel = Arc::new(AtomicBool::new(false));
+        let total = torrent.get_total_piece_count();
+        for piece in 0..total {
+            let source = DiskRead::from_file(
+                file.clone(),
+                piece as u64 * torrent.piece_len as u64,
+                torrent.get_piece_len(piece) as usize,
+            );
+            let expected = torrent.piece_hash(piece);
+            self.send(piece, source, expected, None, Some((id, cancel.clone())));
+        }
+
+        self.recheck = Some(Recheck {
+            id: id,
+            cancel: cancel,
+            total: total,
+            checked: 0,
+        });
+    }
+
+    // Jobs already queued come back as cancelled
+    pub fn cancel_recheck(self: &mut Self) {
+        if let Some(r) = self.recheck.take() {
+            r.cancel.store(true, Ordering::Relaxed);
+        }
+    }
+
+    // Checked and total piece count of the running recheck
+    pub fn recheck_progress(&self) -> Option<(u32, u32)> {
+        self.recheck.as_ref().map(|r| (r.checked, r.total))
+    }
+
+    pub fn results(self: &mut Self) -> Vec<HashResult> {
+        let mut results = Vec::new();
+        while let Ok(r) = self.done.try_recv() {
+            let id = match r.recheck {
+                Some(id) => id,
+                None => {
+                    results.push(r);
+                    continue;
+                }
+            };
+
+            // Results of cancelled rechecks are dropped
+            let recheck = match &mut self.recheck {
+                Some(recheck) if recheck.id == id => recheck,
+                _ => continue,
+            };
+            recheck.checked += 1;
+            if recheck.checked == recheck.total {
+                self.recheck = None;
+            }
+            results.push(r);
+        }
+        results
+    }
+
+    fn send(
+        self: &Self,
+        piece: u32,
+        source: DiskRead,
+        expected: PieceHash,
+        block_hashes: Option<Vec<Hash>>,
+        recheck: Option<(u32, Arc<AtomicBool>)>,
+    ) {
+        let job = HashJob {
+            piece: piece,
+            source: source,
+            expected: expected,
+            block_hashes: block_hashes,
+            recheck: recheck,
+            done: self.done_tx.clone(),
+        };
+        if self.jobs.send(job).is_err() {
+            println!("hash threads are gone");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::tests::single_file_torrent;
+    use std::{thread, time};
+
+    fn wait_for(hasher: &mut Hasher, count: usize) -> Vec<HashResult> {
+        let mut results = Vec::new();
+        for _ in 0..200 {
+            results.extend(hasher.results());
+            if results.len() >= count {
+                break;
+            }
+            thread::sleep(time::Duration::from_millis(5));
+        }
+        results
+    }
+
+    #[test]
+    fn test_verify_and_recheck() {
+        let threads = HashThreads::new(2);
+        let mut hasher = Hasher::new(threads.sender());
+
+        let data = vec![7_u8; 100];
+        let hash = sha1_smol::Sha1::from(&data).digest().bytes();
+        hasher.verify(
+            0,
+            DiskRead::from_data(data.clone()),
+            PieceHash::V1(hash),
+            None,
+        );
+        hasher.verify(
+            1,
+            DiskRead::from_data(data.clone()),
+            PieceHash::V1([0; 20]),
+            None,
+        );
+
+        let mut results = wait_for(&mut hasher, 2);
+        results.sort_by_key(|r| r.piece);
+        assert!(matches!(results[0].outcome, HashOutcome::Passed));
+        assert!(matches!(results[1].outcome, HashOutcome::Failed(_)));
+
+        // Known block hashes point at the bad block of a v2 piece
+        let blocks = [vec![1_u8; 16384], vec![2_u8; 16384], vec![3_u8; 100]];
+        let known = merkle::block_hashes(&blocks.concat());
+        let v2 = PieceHash::V2 {
+            root: merkle::root(&known, 4, merkle::ZERO_HASH),
+            len: 2 * 16384 + 100,
+            width: 4,
+        };
+        hasher.verify(2, DiskRead::from_data(blocks.concat()), v2, None);
+        let mut corrupt = blocks.concat();
+        corrupt[16384] = 0;
+        hasher.verify(3, DiskRead::from_data(corrupt), v2, Some(known));
+        let mut results = wait_for(&mut hasher, 2);
+        results.sort_by_key(|r| r.piece);
+        assert!(matches!(results[0].outcome, HashOutcome::Passed));
+        assert!(matches!(&results[1].outcome, HashOutcome::Failed(bad) if bad == &[1]));
+
+        let file_name = std::env::temp_dir()
+            .join(format!("hasher-test-{}", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        fs::write(&file_name, [data.clone(), vec![0; 50]].concat()).unwrap();
+        let torrent = Torrent {
+            piece_hashes: vec![hash, hash],
+            ..single_file_torrent(150, 100)
+        };
+        let file = Arc::new(fs::File::open(&file_name).unwrap());
+
+        hasher.start_recheck(file.clone(), &torrent);
+        assert_eq!(hasher.recheck_progress(), Some((0, 2)));
+        let mut results = wait_for(&mut hasher, 2);
+        results.sort_by_key(|r| r.piece);
+        assert!(results.iter().all(|r| r.recheck == Some(0)));
+        assert!(matches!(results[0].outcome, HashOutcome::Passed));
+        assert!(matches!(results[1].outcome, HashOutcome::Failed(_)));
+        assert_eq!(hasher.recheck_progress(), None);
+
+        // Cancelled rechecks report nothing
+        hasher.start_recheck(file, &torrent);
+        hasher.cancel_recheck();
+        assert!(wait_for(&mut hasher, 1).is_empty());
+        fs::remove_file(&file_name).unwrap();
+    }
+}
@@ -0,0 +1,217 @@
+use std::{
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    time,
+};
+
+use crate::util::easy_err;
+
+const MAX_REDIRECTS: usize = 3;
+// Room for the headers and chunk sizes on top of the body
+const MAX_OVERHEAD: usize = 64 * 1024;
+
+pub struct Response {
+    pub status: u16,
+    // Header names are lowercase
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    // Address of the server that answered, after redirects
+    pub addr: SocketAddr,
+}
+
+impl Response {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+// Blocking HTTP/1.1 GET, only meant for the worker threads of web seeds.
+// https isn't supported. Responses with bodies above max_body are rejected.
+pub fn get(
+    url: &str,
+    headers: &[(&str, String)],
+    max_body: usize,
+    timeout: time::Duration,
+) -> Result<Response, io::Error> {
+    let mut url = url.to_string();
+    for _ in 0..=MAX_REDIRECTS {
+        let r = get_once(&url, headers, max_body, timeout)?;
+        if (300..400).contains(&r.status)
+            && let Some(location) = r.header("location")
+        {
+            url = resolve(&url, location)?;
+            continue;
+        }
+        return Ok(r);
+    }
+    Err(easy_err("too many redirects"))
+}
+
+fn get_once(
+    url: &str,
+    headers: &[(&str, String)],
+    max_body: usize,
+    timeout: time::Duration,
+) -> Result<Response, io::Error> {
+    let (authority, path) = split_url(url)?;
+    let addr = match authority.to_socket_addrs() {
+        Ok(mut addrs) => addrs.next().ok_or(easy_err("host has no address"))?,
+        // No port given
+        Err(_) => (authority, 80)
+            .to_socket_addrs()?
+            .next()
+            .ok_or(easy_err("host has no address"))?,
+    };
+
+    let mut conn = TcpStream::connect_timeout(&addr, timeout)?;
+    conn.set_read_timeout(Some(timeout))?;
+    conn.set_write_timeout(Some(timeout))?;
+
+    let mut req = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n",
+        path, authority
+    );
+    for (name, value) in headers {
+        req.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    req.push_str("\r\n");
+    conn.write_all(req.as_bytes())?;
+
+    // The server is untrusted, it could send forever
+    let limit = max_body.saturating_add(MAX_OVERHEAD);
+    let mut buf = Vec::new();
+    conn.take(limit as u64 + 1).read_to_end(&mut buf)?;
+    if buf.len() > limit {
+        return Err(easy_err("response is too large"));
+    }
+    let mut r = parse_response(&buf)?;
+    if r.body.len() > max_body {
+        return Err(easy_err("response is too large"));
+    }
+    r.addr = addr;
+    Ok(r)
+}
+
+// Returns host[:port] and the path with the query
+fn split_url(url: &str) -> Result<(&str, &str), io::Error> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or(easy_err("only http urls are supported"))?;
+    match rest.find('/') {
+        Some(i) => Ok((&rest[..i], &rest[i..])),
+        None => Ok((rest, "/")),
+    }
+}
+
+fn resolve(base: &str, location: &str) -> Result<String, io::Error> {
+    if location.starts_with("http://") || location.starts_with("https://") {
+        return Ok(location.to_string());
+    }
+    let (authority, path) = split_url(base)?;
+    if location.starts_with('/') {
+        return Ok(format!("http://{}{}", authority, location));
+    }
+    let dir = &path[..path.rfind('/').unwrap_or(0) + 1];
+    Ok(format!("http://{}{}{}", authority, dir, location))
+}
+
+fn parse_response(buf: &[u8]) -> Result<Response, io::Error> {
+    let header_end = buf
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or(easy_err("response has no header end"))?;
+    let head = String::from_utf8_lossy(&buf[..header_end]);
+    let mut lines = head.split("\r\n");
+
+    // HTTP/1.1 206 Partial Content
+    let status = lines
+        .next()
+        .and_then(|l| l.split(' ').nth(1))
+        .and_then(|s| s.parse::<u16>().ok())
+        .ok_or(easy_err("invalid status line"))?;
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|l| l.split_once(':'))
+        .map(|(n, v)| (n.trim().to_lowercase(), v.trim().to_string()))
+        .collect();
+
+    let mut r = Response {
+        status: status,
+        headers: headers,
+        body: Vec::new(),
+        addr: SocketAddr::from(([0, 0, 0, 0], 0)),
+    };
+    let body = &buf[header_end + 4..];
+    if r.header("transfer-encoding") == Some("chunked") {
+        r.body = decode_chunked(body)?;
+    } else if let Some(len) = r.header("content-length") {
+        let len = len
+            .parse::<usize>()
+            .map_err(|_| easy_err("invalid content length"))?;
+        if body.len() < len {
+            return Err(easy_err("response body is truncated"));
+        }
+        r.body = body[..len].to_vec();
+    } else {
+        r.body = body.to_vec();
+    }
+    Ok(r)
+}
+
+fn decode_chunked(mut body: &[u8]) -> Result<Vec<u8>, io::Error> {
+    let mut out = Vec::new();
+    loop {
+        let line_end = body
+            .windows(2)
+            .position(|w| w == b"\r\n")
+            .ok_or(easy_err("chunk has no size"))?;
+        let size = String::from_utf8_lossy(&body[..line_end]);
+        // Chunk extensions follow a semicolon
+        let size = usize::from_str_radix(size.split(';').next().unwrap().trim(), 16)
+            .map_err(|_| easy_err("invalid chunk size"))?;
+        body = &body[line_end + 2..];
+        if size == 0 {
+            return Ok(out);
+        }
+        if body.len().saturating_sub(2) < size {
+            return Err(easy_err("chunk is truncated"));
+        }
+        out.extend(&body[..size]);
+        body = &body[size + 2..];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_response() {
+        let r = parse_response(
+            b"HTTP/1.1 206 Partial Content\r\nContent-Length: 3\r\nX-A: b\r\n\r\nabcd",
+        )
+        .unwrap();
+        assert_eq!(r.status, 206);
+        assert_eq!(r.header("x-a"), Some("b"));
+        assert_eq!(r.body, b"abc");
+
+        let r = parse_response(
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n2;x\r\nde\r\n0\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(r.body, b"abcde");
+
+        // Chunk sizes near usize::MAX don't overflow
+        let huge = format!(
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n{:x}\r\nabc\r\n",
+            usize::MAX
+        );
+        assert!(parse_response(huge.as_bytes()).is_err());
+
+        assert!(parse_response(b"HTTP/1.1 200 OK\r\nContent-Length: 9\r\n\r\nabc").is_err());
+        assert_eq!(resolve("http://a:1/x/y?z", "w").unwrap(), "http://a:1/x/w");
+        assert_eq!(resolve("http://a/x/y", "/w").unwrap(), "http://a/w");
+    }
+}
@@ -0,0 +1,117 @@
+use std::io;
+
+use percent_encoding::percent_decode_str;
+
+use crate::util::easy_err;
+
+// https://www.bittorrent.org/beps/bep_0009.html#magnet-uri-format
+#[derive(Debug, PartialEq)]
+pub struct Magnet {
+    pub info_hash: [u8; 20],
+    pub name: Option<String>,
+    pub trackers: Vec<String>,
+}
+
+impl Magnet {
+    pub fn parse(uri: &str) -> Result<Magnet, io::Error> {
+        let query = uri
+            .strip_prefix("magnet:?")
+            .ok_or(easy_err("not a magnet link"))?;
+
+        let mut info_hash = None;
+        let mut name = None;
+        let mut trackers = Vec::new();
+        for param in query.split('&') {
+            let (key, value) = param.split_once('=').unwrap_or((param, ""));
+            let value = percent_decode_str(value).decode_utf8_lossy().to_string();
+            match key {
+                "xt" => {
+                    // Only the first v1 hash is used, v2 ones start with urn:btmh
+                    if let Some(hash) = value.strip_prefix("urn:btih:")
+                        && info_hash.is_none()
+                    {
+                        info_hash = Some(parse_info_hash(hash)?);
+                    }
+                }
+                "dn" => name = Some(value),
+                "tr" => trackers.push(value),
+                _ => {}
+            }
+        }
+
+        Ok(Magnet {
+            info_hash: info_hash.ok_or(easy_err("magnet link has no info hash"))?,
+            name: name,
+            trackers: trackers,
+        })
+    }
+}
+
+// 40 hex or 32 base32 characters
+fn parse_info_hash(s: &str) -> Result<[u8; 20], io::Error> {
+    let bytes = match s.len() {
+        40 => (0..40)
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+            .collect::<Option<Vec<u8>>>(),
+        32 => base32(s),
+        _ => None,
+    };
+    bytes
+        .and_then(|b| b.try_into().ok())
+        .ok_or(easy_err("magnet link has an invalid info hash"))
+}
+
+fn base32(s: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let (mut bits, mut bit_count) = (0_u32, 0);
+    for c in s.bytes() {
+        let v = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        bits = bits << 5 | v as u32;
+        bit_count += 5;
+        if bit_count >= 8 {
+            bit_count -= 8;
+            bytes.push((bits >> bit_count) as u8);
+            bits &= (1 << bit_count) - 1;
+        }
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_magnet() {
+        let m = Magnet::parse(
+            "magnet:?xt=urn:btih:c9e15763f722f23e98a29decdfae341b98d53056&dn=Cosmos%20Laundromat&tr=udp%3A%2F%2Fexplodie.org%3A6969&tr=udp%3A%2F%2Ftracker.opentrackr.org%3A1337",
+        )
+        .unwrap();
+        assert_eq!(m.info_hash[..4], [0xc9, 0xe1, 0x57, 0x63]);
+        assert_eq!(m.name.as_deref(), Some("Cosmos Laundromat"));
+        assert_eq!(
+            m.trackers,
+            vec![
+                "udp://explodie.org:6969",
+                "udp://tracker.opentrackr.org:1337"
+            ]
+        );
+
+        // Same hash in base32
+        let b32 = Magnet::parse("magnet:?xt=urn:btih:ZHQVOY7XELZD5GFCTXWN7LRUDOMNKMCW").unwrap();
+        assert_eq!(b32.info_hash, m.info_hash);
+        assert!(b32.name.is_none() && b32.trackers.is_empty());
+
+        assert!(Magnet::parse("http://example.com").is_err());
+        assert!(Magnet::parse("magnet:?dn=x").is_err());
+        assert!(Magnet::parse("magnet:?xt=urn:btih:c9e15763").is_err());
+        assert!(
+            Magnet::parse("magnet:?xt=urn:btih:zz!15763f722f23e98a29decdfae341b98d53056").is_err()
+        );
+    }
+}
@@ -0,0 +1,213 @@
+use sha2::{Digest, Sha256};
+use std::io;
+
+use crate::{torrent::DEFAULT_BLOCK_LENGTH, util::easy_err};
+
+// https://www.bittorrent.org/beps/bep_0052.html
+// v2 torrents hash every file as a binary tree of SHA-256 hashes. The leaves are
+// the hashes of the file's 16 KiB blocks, the tree is padded with zero hashes up
+// to a power of two. The layer whose nodes cover a whole piece is the piece layer.
+pub type Hash = [u8; 32];
+
+pub const ZERO_HASH: Hash = [0; 32];
+// Header of the hash request, hashes and hash reject messages
+pub const HASH_REQUEST_LEN: usize = 48;
+// Larger requests are rejected, same as libtorrent
+pub const MAX_HASH_REQUEST_LENGTH: u32 = 512;
+
+pub fn hash_data(data: &[u8]) -> Hash {
+    Sha256::digest(data).into()
+}
+
+fn hash_pair(left: &Hash, right: &Hash) -> Hash {
+    let mut h = Sha256::new();
+    h.update(left);
+    h.update(right);
+    h.finalize().into()
+}
+
+// Root of a subtree of the given height that only holds padding
+pub fn pad_hash(height: u32) -> Hash {
+    let mut h = ZERO_HASH;
+    for _ in 0..height {
+        h = hash_pair(&h, &h);
+    }
+    h
+}
+
+// Hashes of the 16 KiB blocks of data, the last one might be shorter
+pub fn block_hashes(data: &[u8]) -> Vec<Hash> {
+    data.chunks(DEFAULT_BLOCK_LENGTH as usize)
+        .map(hash_data)
+        .collect()
+}
+
+// Root of the tree over hashes, padded with pad up to width which is a power of two
+pub fn root(hashes: &[Hash], width: usize, pad: Hash) -> Hash {
+    let mut layer = hashes.to_vec();
+    let mut pad = pad;
+    let mut width = width.max(1);
+    while width > 1 {
+        layer = next_layer(&layer, &pad);
+        pad = hash_pair(&pad, &pad);
+        width /= 2;
+    }
+    layer.first().copied().unwrap_or(pad)
+}
+
+// Uncle hashes from the subtree over length hashes at index up proof_layers layers.
+// The layer is padded with pad up to width.
+pub fn proof(
+    layer: &[Hash],
+    width: usize,
+    pad: Hash,
+    index: usize,
+    length: usize,
+    proof_layers: u32,
+) -> Vec<Hash> {
+    let mut layer = layer.to_vec();
+    let mut pad = pad;
+    let mut idx = index;
+    let mut len = length.max(1);
+    let mut width = width.max(1);
+    while len > 1 {
+        layer = next_layer(&layer, &pad);
+        pad = hash_pair(&pad, &pad);
+        idx /= 2;
+        len /= 2;
+        width /= 2;
+    }
+
+    let mut uncles = Vec::new();
+    for _ in 0..proof_layers {
+        if width <= 1 {
+            break;
+        }
+        uncles.push(layer.get(idx ^ 1).copied().unwrap_or(pad));
+        layer = next_layer(&layer, &pad);
+        pad = hash_pair(&pad, &pad);
+        idx /= 2;
+        width /= 2;
+    }
+    uncles
+}
+
+fn next_layer(layer: &[Hash], pad: &Hash) -> Vec<Hash> {
+    layer
+        .chunks(2)
+        .map(|c| hash_pair(&c[0], c.get(1).unwrap_or(pad)))
+        .collect()
+}
+
+// Payload of the hash request message, hashes and hash reject messages start with it as well
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HashRequest {
+    pub pieces_root: Hash,
+    // 0 are the block hashes, piece layer hashes are further up
+    pub base_layer: u32,
+    pub index: u32,
+    pub length: u32,
+    pub proof_layers: u32,
+}
+
+impl HashRequest {
+    pub fn parse(payload: &[u8]) -> Result<HashRequest, io::Error> {
+        if payload.len() < HASH_REQUEST_LEN {
+            return Err(easy_err("payload too short to be hash request"));
+        }
+        let int = |i: usize| u32::from_be_bytes(payload[i..i + 4].try_into().unwrap());
+
+        let r = HashRequest {
+            pieces_root: payload[0..32].try_into().unwrap(),
+            base_layer: int(32),
+            index: int(36),
+            length: int(40),
+            proof_layers: int(44),
+        };
+        if r.length < 2
+            || !r.length.is_power_of_two()
+            || r.length > MAX_HASH_REQUEST_LENGTH
+            || !r.index.is_multiple_of(r.length)
+        {
+            return Err(easy_err("invalid hash request"));
+        }
+        Ok(r)
+    }
+
+    // The hashes message answering the request, base hashes followed by the uncles
+    pub fn parse_hashes(payload: &[u8]) -> Result<(HashRequest, Vec<Hash>), io::Error> {
+        let r = HashRequest::parse(payload)?;
+        let rest = &payload[HASH_REQUEST_LEN..];
+        if !rest.len().is_multiple_of(32) || rest.len() / 32 < r.length as usize {
+            return Err(easy_err("invalid hashes message"));
+        }
+        Ok((r, rest.chunks(32).map(|h| h.try_into().unwrap()).collect()))
+    }
+
+    pub fn to_bytes(self) -> [u8; HASH_REQUEST_LEN] {
+        let mut b = [0_u8; HASH_REQUEST_LEN];
+
+        b[0..32].copy_from_slice(&self.pieces_root);
+        b[32..36].copy_from_slice(&self.base_layer.to_be_bytes());
+        b[36..40].copy_from_slice(&self.index.to_be_bytes());
+        b[40..44].copy_from_slice(&self.length.to_be_bytes());
+        b[44..48].copy_from_slice(&self.proof_layers.to_be_bytes());
+
+        b
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Inverse of proof, the node the hashes and their uncles lead up to
+    pub fn proof_root(hashes: &[Hash], index: usize, uncles: &[Hash], pad: Hash) -> Hash {
+        let mut node = root(hashes, hashes.len(), pad);
+        let mut idx = index / hashes.len().max(1);
+        for u in uncles {
+            node = if idx.is_multiple_of(2) {
+                hash_pair(&node, u)
+            } else {
+                hash_pair(u, &node)
+            };
+            idx /= 2;
+        }
+        node
+    }
+
+    #[test]
+    fn test_proof() {
+        let leaves: Vec<Hash> = (0..5_u8).map(|i| hash_data(&[i])).collect();
+        let full_root = root(&leaves, 8, ZERO_HASH);
+        assert_eq!(root(&[], 4, ZERO_HASH), pad_hash(2));
+
+        // Two leaves at index 2 plus the uncles up to the root
+        let uncles = proof(&leaves, 8, ZERO_HASH, 2, 2, 2);
+        assert_eq!(uncles.len(), 2);
+        assert_eq!(proof_root(&leaves[2..4], 2, &uncles, ZERO_HASH), full_root);
+        assert_ne!(proof_root(&leaves[0..2], 2, &uncles, ZERO_HASH), full_root);
+
+        // The last subtree is padding only
+        let uncles = proof(&leaves, 8, ZERO_HASH, 4, 4, 5);
+        assert_eq!(uncles.len(), 1);
+        let padded = [leaves[4], ZERO_HASH, ZERO_HASH, ZERO_HASH];
+        assert_eq!(proof_root(&padded, 4, &uncles, ZERO_HASH), full_root);
+
+        let r = HashRequest {
+            pieces_root: full_root,
+            base_layer: 0,
+            index: 4,
+            length: 4,
+            proof_layers: 1,
+        };
+        let mut payload = r.to_bytes().to_vec();
+        assert_eq!(HashRequest::parse(&payload).unwrap(), r);
+        assert!(HashRequest::parse_hashes(&payload).is_err());
+        payload.extend(padded.concat());
+        assert_eq!(HashRequest::parse_hashes(&payload).unwrap().1, padded);
+
+        payload[43] = 3;
+        assert!(HashRequest::parse(&payload).is_err());
+    }
+}
@@ -0,0 +1,342 @@
+use std::io;
+
+use crate::merkle::{HASH_REQUEST_LEN, Hash, HashRequest};
+use crate::torrent::Block;
+use crate::util::easy_err;
+
+// Length prefixes above this are rejected before anything is buffered. Leaves
+// room for the bitfield of a torrent with millions of pieces.
+pub const MAX_FRAME_LEN: usize = 1 << 20;
+
+const CHOKE: u8 = 0;
+const UNCHOKE: u8 = 1;
+const INTERESTED: u8 = 2;
+const NOT_INTERESTED: u8 = 3;
+const HAVE: u8 = 4;
+const BITFIELD: u8 = 5;
+const REQUEST: u8 = 6;
+const PIECE: u8 = 7;
+const CANCEL: u8 = 8;
+const PORT: u8 = 9;
+// https://www.bittorrent.org/beps/bep_0006.html
+const SUGGEST_PIECE: u8 = 13;
+const HAVE_ALL: u8 = 14;
+const HAVE_NONE: u8 = 15;
+const REJECT_REQUEST: u8 = 16;
+const ALLOWED_FAST: u8 = 17;
+// https://www.bittorrent.org/beps/bep_0010.html
+const EXTENDED: u8 = 20;
+// https://www.bittorrent.org/beps/bep_0052.html
+const HASH_REQUEST: u8 = 21;
+const HASHES: u8 = 22;
+const HASH_REJECT: u8 = 23;
+
+// https://wiki.theory.org/BitTorrentSpecification#Messages
+#[derive(Clone, Debug, PartialEq)]
+pub enum PeerMessage {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(u32),
+    Bitfield(Vec<u8>),
+    Request(Block),
+    Piece {
+        piece_index: u32,
+        byte_offset: u32,
+        data: Vec<u8>,
+    },
+    Cancel(Block),
+    Port(u16),
+    SuggestPiece(u32),
+    HaveAll,
+    HaveNone,
+    RejectRequest(Block),
+    AllowedFast(u32),
+    Extended {
+        id: u8,
+        payload: Vec<u8>,
+    },
+    HashRequest(HashRequest),
+    Hashes {
+        request: HashRequest,
+        hashes: Vec<Hash>,
+    },
+    HashReject(HashRequest),
+}
+
+impl PeerMessage {
+    // Returns the message at the start of buf and how many bytes it took, None until it's complete
+    pub fn decode(buf: &[u8]) -> Result<Option<(PeerMessage, usize)>, io::Error> {
+        if buf.len() < 4 {
+            return Ok(None);
+        }
+        let len = u32::from_be_bytes(buf[..4].try_into().unwrap()) as usize;
+        if len > MAX_FRAME_LEN {
+            return Err(easy_err("message exceeds the frame limit"));
+        }
+        if buf.len() < 4 + len {
+            return Ok(None);
+        }
+        if len == 0 {
+            return Ok(Some((PeerMessage::KeepAlive, 4)));
+        }
+
+        let id = buf[4];
+        let p = &buf[5..4 + len];
+        let exact = |n: usize| {
+            if p.len() == n {
+                Ok(())
+            } else {
+                Err(easy_err("invalid message length"))
+            }
+        };
+        let int = |p: &[u8]| u32::from_be_bytes(p[..4].try_into().unwrap());
+
+        let msg = match id {
+            CHOKE => exact(0).map(|_| PeerMessage::Choke)?,
+            UNCHOKE => exact(0).map(|_| PeerMessage::Unchoke)?,
+            INTERESTED => exact(0).map(|_| PeerMessage::Interested)?,
+            NOT_INTERESTED => exact(0).map(|_| PeerMessage::NotInterested)?,
+            HAVE => exact(4).map(|_| PeerMessage::Have(int(p)))?,
+            BITFIELD => PeerMessage::Bitfield(p.to_vec()),
+            REQUEST => {
+                exact(12)?;
+                PeerMessage::Request(Block::parse(p)?)
+            }
+            PIECE => {
+                if p.len() <= 8 {
+                    return Err(easy_err("invalid message length"));
+                }
+                PeerMessage::Piece {
+                    piece_index: int(p),
+                    byte_offset: int(&p[4..]),
+                    data: p[8..].to_vec(),
+                }
+            }
+            CANCEL => {
+                exact(12)?;
+                PeerMessage::Cancel(Block::parse(p)?)
+            }
+            PORT => exact(2).map(|_| PeerMessage::Port(u16::from_be_bytes([p[0], p[1]])))?,
+            SUGGEST_PIECE => exact(4).map(|_| PeerMessage::SuggestPiece(int(p)))?,
+            HAVE_ALL => exact(0).map(|_| PeerMessage::HaveAll)?,
+            HAVE_NONE => exact(0).map(|_| PeerMessage::HaveNone)?,
+            REJECT_REQUEST => {
+                exact(12)?;
+                PeerMessage::RejectRequest(Block::parse(p)?)
+            }
+            ALLOWED_FAST => exact(4).map(|_| PeerMessage::AllowedFast(int(p)))?,
+            EXTENDED => {
+                if p.is_empty() {
+                    return Err(easy_err("invalid message length"));
+                }
+                PeerMessage::Extended {
+                    id: p[0],
+                    payload: p[1..].to_vec(),
+                }
+            }
+            HASH_REQUEST => {
+                exact(HASH_REQUEST_LEN)?;
+                PeerMessage::HashRequest(HashRequest::parse(p)?)
+            }
+            HASHES => {
+                let (request, hashes) = HashRequest::parse_hashes(p)?;
+                PeerMessage::Hashes {
+                    request: request,
+                    hashes: hashes,
+                }
+            }
+            HASH_REJECT => {
+                exact(HASH_REQUEST_LEN)?;
+                PeerMessage::HashReject(HashRequest::parse(p)?)
+            }
+            _ => return Err(easy_err("unknown message type")),
+        };
+        Ok(Some((msg, 4 + len)))
+    }
+
+    // Appends the length prefixed message
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        let start = buf.len();
+        buf.extend([0; 4]);
+        match self {
+            PeerMessage::KeepAlive => {}
+            PeerMessage::Choke => buf.push(CHOKE),
+            PeerMessage::Unchoke => buf.push(UNCHOKE),
+            PeerMessage::Interested => buf.push(INTERESTED),
+            PeerMessage::NotInterested => buf.push(NOT_INTERESTED),
+            PeerMessage::Have(piece) => {
+                buf.push(HAVE);
+                buf.extend(piece.to_be_bytes());
+            }
+            PeerMessage::Bitfield(bits) => {
+                buf.push(BITFIELD);
+                buf.extend(bits);
+            }
+            PeerMessage::Request(b) => {
+                buf.push(REQUEST);
+                buf.extend(b.to_bytes());
+            }
+            PeerMessage::Piece {
+                piece_index,
+                byte_offset,
+                data,
+            } => {
+                buf.push(PIECE);
+                buf.extend(piece_index.to_be_bytes());
+                buf.extend(byte_offset.to_be_bytes());
+                buf.extend(data);
+            }
+            PeerMessage::Cancel(b) => {
+                buf.push(CANCEL);
+                buf.extend(b.to_bytes());
+            }
+            PeerMessage::Port(port) => {
+                buf.push(PORT);
+                buf.extend(port.to_be_bytes());
+            }
+            PeerMessage::SuggestPiece(piece) => {
+                buf.push(SUGGEST_PIECE);
+                buf.extend(piece.to_be_bytes());
+            }
+            PeerMessage::HaveAll => buf.push(HAVE_ALL),
+            PeerMessage::HaveNone => buf.push(HAVE_NONE),
+            PeerMessage::RejectRequest(b) => {
+                buf.push(REJECT_REQUEST);
+                buf.extend(b.to_bytes());
+            }
+            PeerMessage::AllowedFast(piece) => {
+                buf.push(ALLOWED_FAST);
+                buf.extend(piece.to_be_bytes());
+            }
+            PeerMessage::Extended { id, payload } => {
+                buf.push(EXTENDED);
+                buf.push(*id);
+                buf.extend(payload);
+            }
+            PeerMessage::HashRequest(r) => {
+                buf.push(HASH_REQUEST);
+                buf.extend(r.to_bytes());
+            }
+            PeerMessage::Hashes { request, hashes } => {
+                buf.push(HASHES);
+                buf.extend(request.to_bytes());
+                buf.extend(hashes.concat());
+            }
+            PeerMessage::HashReject(r) => {
+                buf.push(HASH_REJECT);
+                buf.extend(r.to_bytes());
+            }
+        }
+        let len = (buf.len() - start - 4) as u32;
+        buf[start..start + 4].copy_from_slice(&len.to_be_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merkle::MAX_HASH_REQUEST_LENGTH;
+    use proptest::prelude::*;
+
+    fn block() -> impl Strategy<Value = Block> {
+        (any::<u32>(), any::<u32>(), any::<u32>()).prop_map(|(p, o, l)| Block::new(p, o, l))
+    }
+
+    // Only valid requests survive decoding
+    fn hash_request() -> impl Strategy<Value = HashRequest> {
+        let max_shift = MAX_HASH_REQUEST_LENGTH.trailing_zeros();
+        (
+            any::<Hash>(),
+            any::<u32>(),
+            1..=max_shift,
+            0..1024_u32,
+            any::<u32>(),
+        )
+            .prop_map(|(root, base, shift, i, proof)| HashRequest {
+                pieces_root: root,
+                base_layer: base,
+                index: i << shift,
+                length: 1 << shift,
+                proof_layers: proof,
+            })
+    }
+
+    fn message() -> impl Strategy<Value = PeerMessage> {
+        let bytes = || prop::collection::vec(any::<u8>(), 0..64);
+        prop_oneof![
+            Just(PeerMessage::KeepAlive),
+            Just(PeerMessage::Choke),
+            Just(PeerMessage::Unchoke),
+            Just(PeerMessage::Interested),
+            Just(PeerMessage::NotInterested),
+            any::<u32>().prop_map(PeerMessage::Have),
+            bytes().prop_map(PeerMessage::Bitfield),
+            block().prop_map(PeerMessage::Request),
+            (
+                any::<u32>(),
+                any::<u32>(),
+                prop::collection::vec(any::<u8>(), 1..64)
+            )
+                .prop_map(|(p, o, data)| PeerMessage::Piece {
+                    piece_index: p,
+                    byte_offset: o,
+                    data: data,
+                }),
+            block().prop_map(PeerMessage::Cancel),
+            any::<u16>().prop_map(PeerMessage::Port),
+            any::<u32>().prop_map(PeerMessage::SuggestPiece),
+            Just(PeerMessage::HaveAll),
+            Just(PeerMessage::HaveNone),
+            block().prop_map(PeerMessage::RejectRequest),
+            any::<u32>().prop_map(PeerMessage::AllowedFast),
+            (any::<u8>(), bytes()).prop_map(|(id, payload)| PeerMessage::Extended {
+                id: id,
+                payload: payload,
+            }),
+            hash_request().prop_map(PeerMessage::HashRequest),
+            (hash_request(), prop::collection::vec(any::<Hash>(), 0..4)).prop_map(|(r, uncles)| {
+                let mut hashes = vec![[7; 32]; r.length as usize];
+                hashes.extend(uncles);
+                PeerMessage::Hashes {
+                    request: r,
+                    hashes: hashes,
+                }
+            }),
+            hash_request().prop_map(PeerMessage::HashReject),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn test_round_trip(msg in message(), trailing in prop::collection::vec(any::<u8>(), 0..8)) {
+            let mut buf = Vec::new();
+            msg.encode(&mut buf);
+            let len = buf.len();
+            prop_assert_eq!(PeerMessage::decode(&buf[..len - 1]).unwrap(), None);
+
+            buf.extend(trailing);
+            prop_assert_eq!(PeerMessage::decode(&buf).unwrap(), Some((msg, len)));
+        }
+
+        #[test]
+        fn test_decode_garbage(buf in prop::collection::vec(any::<u8>(), 0..128)) {
+            if let Ok(Some((_, len))) = PeerMessage::decode(&buf) {
+                prop_assert!(len <= buf.len());
+            }
+        }
+    }
+
+    #[test]
+    fn test_invalid_frames() {
+        let huge = ((MAX_FRAME_LEN + 1) as u32).to_be_bytes();
+        assert!(PeerMessage::decode(&huge).is_err());
+        // Have with a 5 byte payload
+        assert!(PeerMessage::decode(&[0, 0, 0, 6, HAVE, 0, 0, 0, 1, 2]).is_err());
+        assert!(PeerMessage::decode(&[0, 0, 0, 2, CHOKE, 0]).is_err());
+        assert!(PeerMessage::decode(&[0, 0, 0, 9, PIECE, 0, 0, 0, 1, 0, 0, 0, 0]).is_err());
+        assert!(PeerMessage::decode(&[0, 0, 0, 1, 42]).is_err());
+    }
+}
@@ -0,0 +1,385 @@
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+    net::{Ipv4Addr, SocketAddr, TcpStream},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    bencoding::{self, Statement},
+    message::PeerMessage,
+    mse::{self, Cipher, EncryptionPolicy},
+    peer::{HANDSHAKE_LEN, HandshakePacket, Packet, Peer},
+    util::easy_err,
+};
+
+// https://www.bittorrent.org/beps/bep_0009.html
+// The info dict of a magnet link is fetched from one peer at a time in 16 KiB
+// pieces over blocking connections, like the udp tracker does its requests.
+
+const METADATA_PIECE_LEN: usize = 16 * 1024;
+// Way above any real info dict, peers announcing more are skipped
+const MAX_METADATA_SIZE: usize = 16 * 1024 * 1024;
+// Time a peer gets to send all of the metadata
+const FETCH_TIMEOUT: Duration = Duration::from_secs(60);
+const EXTENDED_HANDSHAKE_ID: u8 = 0;
+// Our id for ut_metadata messages, peers answer with it
+const UT_METADATA_ID: u8 = 1;
+const MSG_REQUEST: i64 = 0;
+const MSG_DATA: i64 = 1;
+const MSG_REJECT: i64 = 2;
+
+// The info dict of the first peer whose metadata matches the info hash
+pub fn fetch(
+    peers: &[Peer],
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+    policy: EncryptionPolicy,
+    connect_timeout: Duration,
+) -> Result<Vec<u8>, io::Error> {
+    for p in peers {
+        println!("fetching metadata from {:?}", p);
+        match fetch_from(p, info_hash, peer_id, policy, connect_timeout) {
+            Ok(info) => return Ok(info),
+            Err(e) => println!("failed to fetch metadata from {:?} {}", p, e),
+        }
+    }
+    Err(easy_err("no peer sent the metadata"))
+}
+
+fn fetch_from(
+    p: &Peer,
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+    policy: EncryptionPolicy,
+    connect_timeout: Duration,
+) -> Result<Vec<u8>, io::Error> {
+    let addr = SocketAddr::from((Ipv4Addr::from(p.ip_address), p.port));
+    let mut conn = match policy {
+        EncryptionPolicy::Disabled => Connection::connect(addr, info_hash, None, connect_timeout)?,
+        _ => match Connection::connect(addr, info_hash, Some(policy), connect_timeout) {
+            Ok(c) => c,
+            Err(e) if policy == EncryptionPolicy::Enabled => {
+                // The peer might not support MSE at all
+                println!("mse with {:?} failed, trying plaintext {}", p, e);
+                Connection::connect(addr, info_hash, None, connect_timeout)?
+            }
+            Err(e) => return Err(e),
+        },
+    };
+
+    conn.handshake(info_hash, peer_id)?;
+    let (ut_metadata, size) = conn.receive_extended_handshake()?;
+
+    let mut info = Vec::with_capacity(size);
+    for piece in 0..size.div_ceil(METADATA_PIECE_LEN) {
+        conn.send_message(PeerMessage::Extended {
+            id: ut_metadata,
+            payload: ut_metadata_message(MSG_REQUEST, piece),
+        })?;
+        let data = conn.receive_metadata_piece(ut_metadata, piece)?;
+        if data.len() != METADATA_PIECE_LEN.min(size - info.len()) {
+            return Err(easy_err("metadata piece has the wrong length"));
+        }
+        info.extend(data);
+    }
+
+    if sha1_smol::Sha1::from(&info).digest().bytes() != info_hash {
+        return Err(easy_err("metadata doesn't match the info hash"));
+    }
+    println!("got {} bytes of metadata from {:?}", info.len(), p);
+    Ok(info)
+}
+
+// {"msg_type": msg_type, "piece": piece}
+fn ut_metadata_message(msg_type: i64, piece: usize) -> Vec<u8> {
+    bencoding::marshal(&Statement::Dictionary(HashMap::from([
+        (&b"msg_type"[..], Statement::Integer(msg_type)),
+        (&b"piece"[..], Statement::Integer(piece as i64)),
+    ])))
+}
+
+// The peer's id for ut_metadata and the metadata size
+fn parse_extended_handshake(payload: &Vec<u8>) -> Result<(u8, usize), io::Error> {
+    let statements = bencoding::parse(payload).map_err(|e| easy_err(&e))?;
+    let dict = match statements.first() {
+        Some(Statement::Dictionary(d)) => d,
+        _ => return Err(easy_err("extended handshake is not a dict")),
+    };
+    let id = match dict.get("m".as_bytes()) {
+        Some(Statement::Dictionary(m)) => match m.get("ut_metadata".as_bytes()) {
+            Some(Statement::Integer(id)) if *id > 0 && *id <= u8::MAX as i64 => *id as u8,
+            _ => return Err(easy_err("peer doesn't support ut_metadata")),
+        },
+        _ => return Err(easy_err("extended handshake has no m dict")),
+    };
+    let size = match dict.get("metadata_size".as_bytes()) {
+        Some(Statement::Integer(s)) if *s > 0 && *s as usize <= MAX_METADATA_SIZE => *s as usize,
+        _ => return Err(easy_err("peer has no valid metadata size")),
+    };
+    Ok((id, size))
+}
+
+// The message type and piece of a ut_metadata message, then the data behind the dict
+fn parse_ut_metadata(payload: &[u8]) -> Result<(i64, usize, &[u8]), io::Error> {
+    let (header, len) = bencoding::parse_first(payload).map_err(|e| easy_err(&e))?;
+    let dict = match header {
+        Statement::Dictionary(d) => d,
+        _ => return Err(easy_err("ut_metadata message is not a dict")),
+    };
+    let msg_type = match dict.get("msg_type".as_bytes()) {
+        Some(Statement::Integer(t)) => *t,
+        _ => return Err(easy_err("ut_metadata message has no type")),
+    };
+    let piece = match dict.get("piece".as_bytes()) {
+        Some(Statement::Integer(p)) if *p >= 0 => *p as usize,
+        _ => return Err(easy_err("ut_metadata message has no piece")),
+    };
+    Ok((msg_type, piece, &payload[len..]))
+}
+
+struct Connection {
+    stream: TcpStream,
+    // Keys of the encrypted stream, None for plaintext
+    cipher: Option<Cipher>,
+    // Bytes read from the stream that don't form a whole message yet
+    read_buf: Vec<u8>,
+    deadline: Instant,
+}
+
+impl Connection {
+    // Runs the MSE handshake unless encryption is None
+    fn connect(
+        addr: SocketAddr,
+        info_hash: [u8; 20],
+        encryption: Option<EncryptionPolicy>,
+        timeout: Duration,
+    ) -> Result<Self, io::Error> {
+        let stream = TcpStream::connect_timeout(&addr, timeout)?;
+        let mut conn = Self::new(stream, timeout)?;
+
+        if let Some(policy) = encryption {
+            let mut handshake = mse::Handshake::outgoing(info_hash, policy);
+            let mut output = Vec::new();
+            loop {
+                let established = handshake.step(&mut conn.read_buf, &mut output, &[info_hash])?;
+                conn.stream.write_all(&output)?;
+                output.clear();
+                if let Some(e) = established {
+                    conn.cipher = e.cipher;
+                    break;
+                }
+                conn.fill_read_buf()?;
+            }
+        }
+        Ok(conn)
+    }
+
+    fn new(stream: TcpStream, timeout: Duration) -> Result<Self, io::Error> {
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        Ok(Self {
+            stream: stream,
+            cipher: None,
+            read_buf: Vec::new(),
+            deadline: Instant::now() + FETCH_TIMEOUT,
+        })
+    }
+
+    // Exchanges the plain handshake and sends our extended handshake
+    fn handshake(self: &mut Self, info_hash: [u8; 20], peer_id: [u8; 20]) -> Result<(), io::Error> {
+        self.send(HandshakePacket::new(info_hash, peer_id).build())?;
+        while self.read_buf.len() < HANDSHAKE_LEN {
+            self.fill_read_buf()?;
+        }
+        let p = HandshakePacket::parse(&self.read_buf[..HANDSHAKE_LEN])
+            .ok_or(easy_err("unknown protocol"))?;
+        if p.info_hash() != info_hash {
+            return Err(easy_err("info hash mismatch"));
+        }
+        if !p.supports_extensions() {
+            return Err(easy_err("peer doesn't support the extension protocol"));
+        }
+        self.read_buf.drain(..HANDSHAKE_LEN);
+
+        let m = HashMap::from([(
+            &b"ut_metadata"[..],
+            Statement::Integer(UT_METADATA_ID as i64),
+        )]);
+        let dict = HashMap::from([(&b"m"[..], Statement::Dictionary(m))]);
+        self.send_message(PeerMessage::Extended {
+            id: EXTENDED_HANDSHAKE_ID,
+            payload: bencoding::marshal(&Statement::Dictionary(dict)),
+        })
+    }
+
+    // Other messages sent before it, like the bitfield, are ignored
+    fn receive_extended_handshake(self: &mut Self) -> Result<(u8, usize), io::Error> {
+        loop {
+            if let PeerMessage::Extended {
+                id: EXTENDED_HANDSHAKE_ID,
+                payload,
+            } = self.receive_message()?
+            {
+                return parse_extended_handshake(&payload);
+            }
+        }
+    }
+
+    // Requests of the peer are rejected, we have no metadata to give
+    fn receive_metadata_piece(
+        self: &mut Self,
+        peer_ut_metadata: u8,
+        piece: usize,
+    ) -> Result<Vec<u8>, io::Error> {
+        loop {
+            let payload = match self.receive_message()? {
+                PeerMessage::Extended {
+                    id: UT_METADATA_ID,
+                    payload,
+                } => payload,
+                _ => continue,
+            };
+            match parse_ut_metadata(&payload)? {
+                (MSG_DATA, p, data) if p == piece => return Ok(data.to_vec()),
+                (MSG_DATA, _, _) => return Err(easy_err("got another metadata piece")),
+                (MSG_REJECT, _, _) => return Err(easy_err("peer rejected the metadata request")),
+                (MSG_REQUEST, p, _) => self.send_message(PeerMessage::Extended {
+                    id: peer_ut_metadata,
+                    payload: ut_metadata_message(MSG_REJECT, p),
+                })?,
+                _ => {}
+            }
+        }
+    }
+
+    fn receive_message(self: &mut Self) -> Result<PeerMessage, io::Error> {
+        loop {
+            if let Some((msg, len)) = PeerMessage::decode(&self.read_buf)? {
+                self.read_buf.drain(..len);
+                return Ok(msg);
+            }
+            self.fill_read_buf()?;
+        }
+    }
+
+    fn send_message(self: &mut Self, msg: PeerMessage) -> Result<(), io::Error> {
+        let mut buf = Vec::new();
+        msg.encode(&mut buf);
+        self.send(buf)
+    }
+
+    fn send(self: &mut Self, mut buf: Vec<u8>) -> Result<(), io::Error> {
+        if let Some(c) = &mut self.cipher {
+            c.encrypt.apply(&mut buf);
+        }
+        self.stream.write_all(&buf)
+    }
+
+    fn fill_read_buf(self: &mut Self) -> Result<(), io::Error> {
+        if Instant::now() >= self.deadline {
+            return Err(io::Error::from(io::ErrorKind::TimedOut));
+        }
+        let mut buf = [0; 16 * 1024];
+        let n = self.stream.read(&mut buf)?;
+        if n == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        if let Some(c) = &mut self.cipher {
+            c.decrypt.apply(&mut buf[..n]);
+        }
+        self.read_buf.extend_from_slice(&buf[..n]);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    // A peer that serves info over ut_metadata with its own id 3
+    fn serve(info: Vec<u8>) -> Peer {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut conn = Connection::new(stream, Duration::from_secs(5)).unwrap();
+            while conn.read_buf.len() < HANDSHAKE_LEN {
+                conn.fill_read_buf().unwrap();
+            }
+            let theirs = HandshakePacket::parse(&conn.read_buf[..HANDSHAKE_LEN]).unwrap();
+            conn.read_buf.drain(..HANDSHAKE_LEN);
+            conn.send(HandshakePacket::new(theirs.info_hash(), [2; 20]).build())
+                .unwrap();
+            conn.send_message(PeerMessage::HaveNone).unwrap();
+
+            while let Ok(msg) = conn.receive_message() {
+                let PeerMessage::Extended { id, payload } = msg else {
+                    continue;
+                };
+                if id == EXTENDED_HANDSHAKE_ID {
+                    let m = HashMap::from([(&b"ut_metadata"[..], Statement::Integer(3))]);
+                    let dict = HashMap::from([
+                        (&b"m"[..], Statement::Dictionary(m)),
+                        (&b"metadata_size"[..], Statement::Integer(info.len() as i64)),
+                    ]);
+                    conn.send_message(PeerMessage::Extended {
+                        id: EXTENDED_HANDSHAKE_ID,
+                        payload: bencoding::marshal(&Statement::Dictionary(dict)),
+                    })
+                    .unwrap();
+                    continue;
+                }
+                assert_eq!(id, 3);
+                let (msg_type, piece, _) = parse_ut_metadata(&payload).unwrap();
+                assert_eq!(msg_type, MSG_REQUEST);
+                let start = piece * METADATA_PIECE_LEN;
+                let mut payload = ut_metadata_message(MSG_DATA, piece);
+                payload.extend(&info[start..info.len().min(start + METADATA_PIECE_LEN)]);
+                conn.send_message(PeerMessage::Extended {
+                    id: UT_METADATA_ID,
+                    payload,
+                })
+                .unwrap();
+            }
+        });
+        Peer::new(u32::from(Ipv4Addr::LOCALHOST), port)
+    }
+
+    #[test]
+    fn test_fetch() {
+        // Two pieces, the last one short
+        let pieces = vec![7_u8; 20 * 1000];
+        let info = bencoding::marshal(&Statement::Dictionary(HashMap::from([
+            (&b"name"[..], Statement::ByteString(b"test")),
+            (&b"pieces"[..], Statement::ByteString(&pieces)),
+        ])));
+        let info_hash = sha1_smol::Sha1::from(&info).digest().bytes();
+        let timeout = Duration::from_secs(5);
+
+        let peer = serve(info.clone());
+        let fetched = fetch(
+            &[peer],
+            info_hash,
+            [1; 20],
+            EncryptionPolicy::Disabled,
+            timeout,
+        )
+        .unwrap();
+        assert_eq!(fetched, info);
+
+        // Metadata of another torrent is dropped
+        let peer = serve(info[..info.len() - 1].to_vec());
+        assert!(
+            fetch(
+                &[peer],
+                info_hash,
+                [1; 20],
+                EncryptionPolicy::Disabled,
+                timeout
+            )
+            .is_err()
+        );
+    }
+}
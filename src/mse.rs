@@ -0,0 +1,476 @@
+use num_bigint::BigUint;
+use std::io;
+
+use crate::util::easy_err;
+
+// https://wiki.vuze.com/w/Message_Stream_Encryption
+// Both sides agree on a secret with Diffie-Hellman, then obfuscate the stream
+// with RC4 keys derived from the secret and the info hash (SKEY). Receivers
+// find the torrent by matching the SKEY hash against the ones they serve.
+const PRIME: &[u8] = b"FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000000000090563";
+const GENERATOR: u32 = 2;
+const KEY_LEN: usize = 96;
+const PRIVATE_KEY_LEN: usize = 20;
+const MAX_PAD_LEN: usize = 512;
+// Verification constant, 8 zero bytes
+const VC: [u8; 8] = [0; 8];
+const CRYPTO_PLAINTEXT: u32 = 0x01;
+const CRYPTO_RC4: u32 = 0x02;
+// RC4 keystream bytes thrown away before use
+const RC4_DISCARD: usize = 1024;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum EncryptionPolicy {
+    // Plaintext only, incoming encrypted connections are dropped
+    Disabled,
+    // Encrypted when possible, outgoing connections fall back to plaintext
+    #[default]
+    Enabled,
+    // Encrypted only
+    Forced,
+}
+
+pub struct Rc4 {
+    s: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    pub fn new(key: &[u8]) -> Self {
+        let mut rc4 = Self::schedule(key);
+        rc4.apply(&mut [0; RC4_DISCARD]);
+        rc4
+    }
+
+    fn schedule(key: &[u8]) -> Self {
+        let mut s = [0_u8; 256];
+        for (i, v) in s.iter_mut().enumerate() {
+            *v = i as u8;
+        }
+        let mut j: u8 = 0;
+        for i in 0..256 {
+            j = j.wrapping_add(s[i]).wrapping_add(key[i % key.len()]);
+            s.swap(i, j as usize);
+        }
+
+        Self { s: s, i: 0, j: 0 }
+    }
+
+    // Encrypts and decrypts alike
+    pub fn apply(self: &mut Self, data: &mut [u8]) {
+        for b in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.s[self.i as usize]);
+            self.s.swap(self.i as usize, self.j as usize);
+            let k = self.s[self.s[self.i as usize].wrapping_add(self.s[self.j as usize]) as usize];
+            *b ^= k;
+        }
+    }
+}
+
+// Keys of an established connection, None if plaintext was selected
+pub struct Cipher {
+    pub encrypt: Rc4,
+    pub decrypt: Rc4,
+}
+
+pub struct Established {
+    pub info_hash: [u8; 20],
+    pub cipher: Option<Cipher>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Stage {
+    // Initiator only, our public key isn't sent yet
+    Start,
+    PublicKey,
+    // Looking for the initiator's req1 hash or the receiver's encrypted VC behind the padding
+    Sync,
+    // Receiver only, the obfuscated SKEY hash
+    Skey,
+    // VC, crypto provide and the padding length, or crypto select and the padding length
+    CryptoHeader,
+    Pad(usize),
+    // Receiver only, the initial payload
+    InitialPayload(usize),
+}
+
+// A non-blocking MSE handshake. Bytes read from the connection go in, the ones
+// to send come out, whatever follows the handshake is left in the input.
+pub struct Handshake {
+    initiator: bool,
+    policy: EncryptionPolicy,
+    private_key: BigUint,
+    secret: Vec<u8>,
+    info_hash: Option<[u8; 20]>,
+    stage: Stage,
+    encrypt: Option<Rc4>,
+    decrypt: Option<Rc4>,
+    crypto: u32,
+}
+
+impl Handshake {
+    pub fn outgoing(info_hash: [u8; 20], policy: EncryptionPolicy) -> Self {
+        let mut h = Self::new(true, policy);
+        h.info_hash = Some(info_hash);
+        h.stage = Stage::Start;
+        h
+    }
+
+    pub fn incoming(policy: EncryptionPolicy) -> Self {
+        Self::new(false, policy)
+    }
+
+    fn new(initiator: bool, policy: EncryptionPolicy) -> Self {
+        let mut private_key = [0_u8; PRIVATE_KEY_LEN];
+        getrandom::getrandom(&mut private_key).expect("no randomness for the mse key");
+        Self {
+            initiator: initiator,
+            policy: policy,
+            private_key: BigUint::from_bytes_be(&private_key),
+            secret: Vec::new(),
+            info_hash: None,
+            stage: Stage::PublicKey,
+            encrypt: None,
+            decrypt: None,
+            crypto: 0,
+        }
+    }
+
+    // Consumes handshake bytes from input and queues our side to output. Returns
+    // the keys once done, info_hashes are the SKEYs an incoming connection may ask for.
+    pub fn step(
+        self: &mut Self,
+        input: &mut Vec<u8>,
+        output: &mut Vec<u8>,
+        info_hashes: &[[u8; 20]],
+    ) -> Result<Option<Established>, io::Error> {
+        loop {
+            let done = match self.stage {
+                Stage::Start => {
+                    output.extend(self.public_key());
+                    output.extend(random_pad());
+                    self.stage = Stage::PublicKey;
+                    false
+                }
+                Stage::PublicKey => {
+                    if input.len() < KEY_LEN {
+                        return Ok(None);
+                    }
+                    self.secret = self.shared_secret(&input[..KEY_LEN]);
+                    input.drain(..KEY_LEN);
+                    if self.initiator {
+                        self.send_crypto_provide(output);
+                    } else {
+                        output.extend(self.public_key());
+                        output.extend(random_pad());
+                    }
+                    self.stage = Stage::Sync;
+                    false
+                }
+                Stage::Sync => {
+                    let marker = if self.initiator {
+                        // The receiver's VC, encrypted
+                        let mut vc = VC;
+                        Rc4::new(&self.key(b"keyB")).apply(&mut vc);
+                        vc.to_vec()
+                    } else {
+                        hash(&[b"req1", &self.secret]).to_vec()
+                    };
+                    let i = match input.windows(marker.len()).position(|w| w == marker) {
+                        Some(i) => i,
+                        None if input.len() >= MAX_PAD_LEN + marker.len() => {
+                            return Err(easy_err("mse peer isn't in sync"));
+                        }
+                        None => return Ok(None),
+                    };
+                    input.drain(..i + marker.len());
+                    if self.initiator {
+                        self.decrypt.as_mut().unwrap().apply(&mut VC.clone());
+                        self.stage = Stage::CryptoHeader;
+                    } else {
+                        self.stage = Stage::Skey;
+                    }
+                    false
+                }
+                Stage::Skey => {
+                    if input.len() < 20 {
+                        return Ok(None);
+                    }
+                    let req3 = hash(&[b"req3", &self.secret]);
+                    let req2: Vec<u8> = input[..20].iter().zip(req3).map(|(a, b)| a ^ b).collect();
+                    let info_hash = info_hashes
+                        .iter()
+                        .find(|h| hash(&[b"req2", &h[..]])[..] == req2[..])
+                        .ok_or(easy_err("mse peer asked for an unknown torrent"))?;
+                    self.info_hash = Some(*info_hash);
+                    self.decrypt = Some(Rc4::new(&self.key(b"keyA")));
+                    self.encrypt = Some(Rc4::new(&self.key(b"keyB")));
+                    input.drain(..20);
+                    self.stage = Stage::CryptoHeader;
+                    false
+                }
+                Stage::CryptoHeader => {
+                    // The receiver gets the VC as well
+                    let len = if self.initiator { 6 } else { 14 };
+                    if input.len() < len {
+                        return Ok(None);
+                    }
+                    let mut header: Vec<u8> = input.drain(..len).collect();
+                    self.decrypt.as_mut().unwrap().apply(&mut header);
+                    if !self.initiator && header[..8] != VC {
+                        return Err(easy_err("mse verification constant mismatch"));
+                    }
+                    let header = &header[len - 6..];
+                    let crypto = u32::from_be_bytes(header[..4].try_into().unwrap());
+                    let pad_len = u16::from_be_bytes(header[4..6].try_into().unwrap()) as usize;
+                    if pad_len > MAX_PAD_LEN {
+                        return Err(easy_err("mse padding too long"));
+                    }
+                    self.crypto = if self.initiator {
+                        self.check_crypto_select(crypto)?
+                    } else {
+                        self.select_crypto(crypto)?
+                    };
+                    self.stage = Stage::Pad(pad_len);
+                    false
+                }
+                Stage::Pad(len) => {
+                    // The receiver also reads the length of the initial payload
+                    let header_len = if self.initiator { len } else { len + 2 };
+                    if input.len() < header_len {
+                        return Ok(None);
+                    }
+                    let mut pad: Vec<u8> = input.drain(..header_len).collect();
+                    self.decrypt.as_mut().unwrap().apply(&mut pad);
+                    if self.initiator {
+                        true
+                    } else {
+                        let ia_len = u16::from_be_bytes(pad[len..].try_into().unwrap());
+                        self.stage = Stage::InitialPayload(ia_len as usize);
+                        false
+                    }
+                }
+                Stage::InitialPayload(len) => {
+                    if input.len() < len {
+                        return Ok(None);
+                    }
+                    // The initial payload is always encrypted
+                    self.decrypt.as_mut().unwrap().apply(&mut input[..len]);
+                    self.send_crypto_select(output);
+                    true
+                }
+            };
+            if done {
+                return Ok(Some(self.finish(input)));
+            }
+        }
+    }
+
+    fn finish(self: &mut Self, input: &mut [u8]) -> Established {
+        let start = match self.stage {
+            Stage::InitialPayload(len) => len,
+            _ => 0,
+        };
+        let cipher = match (self.crypto, self.encrypt.take(), self.decrypt.take()) {
+            (CRYPTO_RC4, Some(encrypt), Some(mut decrypt)) => {
+                decrypt.apply(&mut input[start..]);
+                Some(Cipher {
+                    encrypt: encrypt,
+                    decrypt: decrypt,
+                })
+            }
+            _ => None,
+        };
+        Established {
+            info_hash: self.info_hash.unwrap(),
+            cipher: cipher,
+        }
+    }
+
+    fn public_key(&self) -> Vec<u8> {
+        let p = BigUint::parse_bytes(PRIME, 16).unwrap();
+        let y = BigUint::from(GENERATOR).modpow(&self.private_key, &p);
+        to_key_bytes(&y)
+    }
+
+    fn shared_secret(&self, public_key: &[u8]) -> Vec<u8> {
+        let p = BigUint::parse_bytes(PRIME, 16).unwrap();
+        let y = BigUint::from_bytes_be(public_key);
+        to_key_bytes(&y.modpow(&self.private_key, &p))
+    }
+
+    fn key(&self, name: &[u8]) -> [u8; 20] {
+        hash(&[name, &self.secret, &self.info_hash.unwrap()])
+    }
+
+    // hash('req1', S), hash('req2', SKEY) xor hash('req3', S), then the
+    // encrypted VC, crypto provide, no padding and no initial payload
+    fn send_crypto_provide(self: &mut Self, output: &mut Vec<u8>) {
+        output.extend(hash(&[b"req1", &self.secret]));
+        let req2 = hash(&[b"req2", &self.info_hash.unwrap()]);
+        let req3 = hash(&[b"req3", &self.secret]);
+        output.extend(req2.iter().zip(req3).map(|(a, b)| a ^ b));
+
+        let provide = match self.policy {
+            EncryptionPolicy::Forced => CRYPTO_RC4,
+            _ => CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+        };
+        let mut header = VC.to_vec();
+        header.extend(provide.to_be_bytes());
+        header.extend(0_u16.to_be_bytes());
+        header.extend(0_u16.to_be_bytes());
+
+        let mut encrypt = Rc4::new(&self.key(b"keyA"));
+        encrypt.apply(&mut header);
+        output.extend(header);
+        self.encrypt = Some(encrypt);
+        self.decrypt = Some(Rc4::new(&self.key(b"keyB")));
+    }
+
+    fn send_crypto_select(self: &mut Self, output: &mut Vec<u8>) {
+        let mut header = VC.to_vec();
+        header.extend(self.crypto.to_be_bytes());
+        header.extend(0_u16.to_be_bytes());
+        self.encrypt.as_mut().unwrap().apply(&mut header);
+        output.extend(header);
+    }
+
+    // RC4 is preferred, plaintext only if the policy allows it
+    fn select_crypto(&self, provided: u32) -> Result<u32, io::Error> {
+        if provided & CRYPTO_RC4 != 0 {
+            Ok(CRYPTO_RC4)
+        } else if provided & CRYPTO_PLAINTEXT != 0 && self.policy != EncryptionPolicy::Forced {
+            Ok(CRYPTO_PLAINTEXT)
+        } else {
+            Err(easy_err("no common mse crypto method"))
+        }
+    }
+
+    fn check_crypto_select(&self, selected: u32) -> Result<u32, io::Error> {
+        match selected {
+            CRYPTO_RC4 => Ok(CRYPTO_RC4),
+            CRYPTO_PLAINTEXT if self.policy != EncryptionPolicy::Forced => Ok(CRYPTO_PLAINTEXT),
+            _ => Err(easy_err(
+                "mse peer selected a crypto method we didn't provide",
+            )),
+        }
+    }
+}
+
+// Whether an incoming connection starts with the plain handshake,
+// None until enough was read to tell
+pub fn is_plaintext(buf: &[u8]) -> Option<bool> {
+    const PROTOCOL: &[u8] = b"\x13BitTorrent protocol";
+    let len = buf.len().min(PROTOCOL.len());
+    if buf[..len] != PROTOCOL[..len] {
+        return Some(false);
+    }
+    if len < PROTOCOL.len() {
+        return None;
+    }
+    Some(true)
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut h = sha1_smol::Sha1::new();
+    for p in parts {
+        h.update(p);
+    }
+    h.digest().bytes()
+}
+
+// Big endian, padded to the full key length
+fn to_key_bytes(n: &BigUint) -> Vec<u8> {
+    let b = n.to_bytes_be();
+    let mut key = vec![0; KEY_LEN - b.len()];
+    key.extend(b);
+    key
+}
+
+fn random_pad() -> Vec<u8> {
+    let mut len = [0_u8; 2];
+    getrandom::getrandom(&mut len).expect("no randomness for the mse padding");
+    let mut pad = vec![0; u16::from_be_bytes(len) as usize % (MAX_PAD_LEN + 1)];
+    getrandom::getrandom(&mut pad).expect("no randomness for the mse padding");
+    pad
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Passes the bytes back and forth until both sides are done
+    fn exchange(
+        a: &mut Handshake,
+        b: &mut Handshake,
+        info_hashes: &[[u8; 20]],
+    ) -> Result<(Established, Established, Vec<u8>, Vec<u8>), io::Error> {
+        let (mut a_in, mut b_in) = (Vec::new(), Vec::new());
+        let (mut a_done, mut b_done) = (None, None);
+        for _ in 0..10 {
+            if a_done.is_none() {
+                a_done = a.step(&mut a_in, &mut b_in, &[])?;
+            }
+            if b_done.is_none() {
+                b_done = b.step(&mut b_in, &mut a_in, info_hashes)?;
+                // The stream goes on right behind the handshake
+                if let Some(Established {
+                    cipher: Some(c), ..
+                }) = &mut b_done
+                {
+                    let mut msg = b"hello".to_vec();
+                    c.encrypt.apply(&mut msg);
+                    a_in.extend(msg);
+                }
+            }
+            if let (Some(_), Some(_)) = (&a_done, &b_done) {
+                return Ok((a_done.take().unwrap(), b_done.take().unwrap(), a_in, b_in));
+            }
+        }
+        Err(easy_err("handshake didn't finish"))
+    }
+
+    #[test]
+    fn test_handshake() {
+        let info_hash = [3; 20];
+        let mut a = Handshake::outgoing(info_hash, EncryptionPolicy::Enabled);
+        let mut b = Handshake::incoming(EncryptionPolicy::Forced);
+        let (mut a, mut b, a_in, b_in) = exchange(&mut a, &mut b, &[[1; 20], info_hash]).unwrap();
+        assert_eq!(b.info_hash, info_hash);
+        assert_eq!(a_in, b"hello");
+        assert!(b_in.is_empty());
+
+        let (ac, bc) = (a.cipher.as_mut().unwrap(), b.cipher.as_mut().unwrap());
+        let mut msg = b"BitTorrent".to_vec();
+        ac.encrypt.apply(&mut msg);
+        assert_ne!(msg, b"BitTorrent");
+        bc.decrypt.apply(&mut msg);
+        assert_eq!(msg, b"BitTorrent");
+
+        // The receiver has to serve the torrent
+        let mut a = Handshake::outgoing(info_hash, EncryptionPolicy::Forced);
+        let mut b = Handshake::incoming(EncryptionPolicy::Enabled);
+        assert!(exchange(&mut a, &mut b, &[[1; 20]]).is_err());
+
+        // Plaintext is selected if the initiator only provides that
+        let b = Handshake::incoming(EncryptionPolicy::Enabled);
+        assert_eq!(b.select_crypto(CRYPTO_PLAINTEXT).unwrap(), CRYPTO_PLAINTEXT);
+        assert_eq!(
+            b.select_crypto(CRYPTO_PLAINTEXT | CRYPTO_RC4).unwrap(),
+            CRYPTO_RC4
+        );
+        let b = Handshake::incoming(EncryptionPolicy::Forced);
+        assert!(b.select_crypto(CRYPTO_PLAINTEXT).is_err());
+
+        assert_eq!(is_plaintext(b"\x13BitTorrent"), None);
+        assert_eq!(is_plaintext(b"\x13BitTorrent protocol"), Some(true));
+        assert_eq!(is_plaintext(b"\x13Bx"), Some(false));
+
+        // Known RC4 test vector, without the discarded keystream
+        let mut msg = *b"Plaintext";
+        Rc4::schedule(b"Key").apply(&mut msg);
+        assert_eq!(msg, [0xbb, 0xf3, 0x16, 0xe8, 0xd9, 0x40, 0xaf, 0x0a, 0xd3]);
+    }
+}
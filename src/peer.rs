@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::collections::HashSet;
 use std::fmt;
 use std::io;
@@ -5,60 +6,66 @@ use std::io::Read;
 use std::io::Write;
 use std::net;
 use std::net::SocketAddr;
-use std::net::TcpStream;
 use std::time;
 
-use crate::PEER_ID;
+use mio::Interest;
+use mio::Registry;
+use mio::Token;
+use mio::net::TcpStream;
+
+use crate::bencoding;
+use crate::bitfield::Bitfield;
+use crate::message::PeerMessage;
+use crate::mse;
+use crate::mse::EncryptionPolicy;
+use crate::pipeline::DEFAULT_MAX_REQUESTS_IN_FLIGHT;
+use crate::pipeline::DEFAULT_MIN_REQUESTS_IN_FLIGHT;
+use crate::pipeline::RequestPipeline;
+use crate::rate_limit::RateLimit;
+use crate::rate_limit::Throttle;
 use crate::torrent::Block;
-use crate::torrent::DownloadBlock;
 use crate::util::easy_err;
+use crate::utp::{SharedUtpSocket, UtpStream};
 
 const BITTORRENT_PROTOCOL: &str = "BitTorrent protocol";
+pub const HANDSHAKE_LEN: usize = 68;
+// https://www.bittorrent.org/beps/bep_0010.html
+const EXTENSION_PROTOCOL_BIT: u8 = 0x10;
+const EXTENDED_HANDSHAKE_ID: u8 = 0;
+// https://www.bittorrent.org/beps/bep_0052.html, last byte of the reserved field
+const V2_PROTOCOL_BIT: u8 = 0x10;
+// https://www.bittorrent.org/beps/bep_0006.html, last byte of the reserved field
+const FAST_EXTENSION_BIT: u8 = 0x04;
+// Suggestions beyond this many are dropped, oldest first
+const MAX_SUGGESTED_PIECES: usize = 16;
 pub static KEEP_ALIVE_MAX_DURATION: time::Duration = time::Duration::from_secs(120);
-
-#[derive(Debug)]
-pub enum MessageType {
-    KeepAlive = -1,
-    Choke,
-    Unchoke,
-    Interested,
-    NotInterested,
-    Have,
-    Bitfield,
-    Request,
-    Piece,
-    Cancel,
-    Port,
-}
-
-impl MessageType {
-    pub fn from_u8(u: u8) -> Option<MessageType> {
-        match u {
-            0 => Some(MessageType::Choke),
-            1 => Some(MessageType::Unchoke),
-            2 => Some(MessageType::Interested),
-            3 => Some(MessageType::NotInterested),
-            4 => Some(MessageType::Have),
-            5 => Some(MessageType::Bitfield),
-            6 => Some(MessageType::Request),
-            7 => Some(MessageType::Piece),
-            8 => Some(MessageType::Cancel),
-            9 => Some(MessageType::Port),
-            10.. => None,
-        }
-    }
-}
-
-pub struct Message {
-    pub message_type: MessageType,
-    pub payload: Vec<u8>,
-}
+// Half the time peers wait for a message before dropping us
+const KEEP_ALIVE_INTERVAL: time::Duration = time::Duration::from_secs(60);
+pub static HANDSHAKE_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+// A peer that didn't send any of the blocks we requested for this long is snubbing us
+pub static SNUB_TIME: time::Duration = time::Duration::from_secs(60);
 
 pub struct Peer {
     pub ip_address: u32,
     pub port: u16,
-    conn: Option<TcpStream>,
+    conn: Option<Connection>,
     addr: SocketAddr,
+    pub state: ConnectionState,
+    // Bytes read from the connection that don't form a whole message yet
+    read_buf: Vec<u8>,
+    // Messages that couldn't be written to the connection without blocking
+    write_buf: Vec<u8>,
+    handshake_sent: bool,
+    // MSE handshake in progress, then the keys of the encrypted stream
+    encryption: Option<mse::Handshake>,
+    cipher: Option<mse::Cipher>,
+    // The SKEY of a finished MSE handshake, the plain handshake has to match it
+    encrypted_for: Option<[u8; 20]>,
+    // Set once an encrypted connection attempt failed, the next ones are plaintext
+    pub plaintext_only: bool,
+    // Set once a uTP connection attempt failed, the next ones use TCP
+    pub tcp_only: bool,
+
     pub am_choked: bool,
     pub am_interested: bool,
     pub peer_choked: bool,
@@ -66,14 +73,64 @@ pub struct Peer {
     pub peer_id: Option<[u8; 20]>,
 
     pub request_queue: Vec<Block>,
-    pub downloaded_blocks: Vec<DownloadBlock>,
+    // Our requests to the peer that are still in flight
+    pub pipeline: RequestPipeline,
+
+    pub supports_extensions: bool,
+    // Whether the peer can answer hash requests of v2 torrents
+    pub supports_v2: bool,
+    pub supports_fast: bool,
+    // Number of outstanding requests the peer allows, from its extended handshake
+    pub reqq: Option<u32>,
+
+    // Sized to the torrent once the handshake told us which one it is
+    pub peer_has: Bitfield,
+    // Pieces the peer lets us request while we are choked
+    pub allowed_fast: HashSet<u32>,
+    // Pieces we let the peer request while it is choked
+    pub granted_fast: HashSet<u32>,
+    // Pieces the peer suggested we download, oldest first
+    pub suggested: Vec<u32>,
+    pub data_movements: Vec<DataMovement>,
+    pub throttle: Throttle,
+
+    pub connection_started_at: Option<time::Instant>,
+    // When the connection entered its current state
+    state_changed_at: time::Instant,
+    pub last_message_at: Option<time::Instant>,
+    last_sent_at: Option<time::Instant>,
+    // When we last got a block, or started waiting for one
+    pub last_piece_at: Option<time::Instant>,
+    pub last_unchoked_at: Option<time::Instant>,
+}
 
-    // List of piece indexes
-    pub peer_has: HashSet<u32>,
-    pub data_movements: Vec<DataMovement>, // TODO: clear entries older than 2 x choke interval
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConnectionState {
+    Disconnected,
+    // Waiting for the non-blocking connect to finish
+    Connecting,
+    // Connected, exchanging MSE keys
+    Encrypting,
+    // Connected, waiting for the remote handshake
+    Handshaking,
+    Active,
+    // We are done with the peer, the queued messages are still written
+    Closing,
+}
 
-    pub failed_connection_attempts: u32,
-    pub last_message_at: Option<time::Instant>,
+impl ConnectionState {
+    fn can_become(self, next: ConnectionState) -> bool {
+        use ConnectionState::*;
+        matches!(
+            (self, next),
+            (_, Disconnected)
+                | (Disconnected, Connecting | Handshaking)
+                | (Connecting, Handshaking)
+                | (Handshaking, Encrypting | Active | Closing)
+                | (Encrypting, Handshaking)
+                | (Active, Closing)
+        )
+    }
 }
 
 pub struct DataMovement {
@@ -82,6 +139,7 @@ pub struct DataMovement {
     pub direction: DataDirection,
 }
 
+#[derive(PartialEq)]
 pub enum DataDirection {
     UploadedToPeer,
     DownloadedFromPeer,
@@ -94,245 +152,538 @@ impl Peer {
             port: port,
             conn: None,
             addr: ip_to_socket_addr(ip_address, port),
+            state: ConnectionState::Disconnected,
+            read_buf: Vec::new(),
+            write_buf: Vec::new(),
+            handshake_sent: false,
+            encryption: None,
+            cipher: None,
+            encrypted_for: None,
+            plaintext_only: false,
+            tcp_only: false,
             // https://wiki.theory.org/BitTorrentSpecification#Overview
-            am_choked: false,
+            am_choked: true,
             am_interested: false,
-            peer_choked: false,
+            peer_choked: true,
             peer_interested: false,
             peer_id: None,
-            peer_has: HashSet::new(),
+            peer_has: Bitfield::default(),
+            allowed_fast: HashSet::new(),
+            granted_fast: HashSet::new(),
+            suggested: Vec::new(),
+            connection_started_at: None,
+            state_changed_at: time::Instant::now(),
             last_message_at: None,
+            last_sent_at: None,
+            last_piece_at: None,
+            last_unchoked_at: None,
             data_movements: Vec::new(),
+            throttle: Throttle::new(RateLimit::default()),
             request_queue: Vec::new(),
-            downloaded_blocks: Vec::new(),
-            failed_connection_attempts: 0,
+            pipeline: RequestPipeline::new(
+                DEFAULT_MIN_REQUESTS_IN_FLIGHT,
+                DEFAULT_MAX_REQUESTS_IN_FLIGHT,
+            ),
+            supports_extensions: false,
+            supports_v2: false,
+            supports_fast: false,
+            reqq: None,
         }
     }
 
-    pub fn connect(self: &mut Self) -> Result<(), io::Error> {
-        let c = TcpStream::connect_timeout(&self.addr, time::Duration::from_secs(3))?;
-        self.conn = Some(c);
-        self.configure_connection()?;
-        Ok(())
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    fn set_state(self: &mut Self, state: ConnectionState) {
+        debug_assert!(
+            self.state.can_become(state),
+            "peer can't go from {:?} to {:?}",
+            self.state,
+            state
+        );
+        self.state = state;
+        self.state_changed_at = time::Instant::now();
+    }
+
+    // How long the connection is in its current state
+    pub fn state_age(&self) -> time::Duration {
+        self.state_changed_at.elapsed()
+    }
+
+    // Stops talking to the peer, it is disconnected once the queued messages are written
+    pub fn close(self: &mut Self) {
+        self.set_state(ConnectionState::Closing);
+        self.request_queue.clear();
+        self.am_interested = false;
+        self.peer_interested = false;
     }
 
-    pub fn accept(self: &mut Self, conn: TcpStream) -> Result<(), io::Error> {
-        self.conn = Some(conn);
-        self.configure_connection()?;
-        
+    // Starts a non-blocking connect, the registry reports the connection writable once it is done
+    pub fn connect(self: &mut Self, registry: &Registry, token: Token) -> Result<(), io::Error> {
+        self.reset();
+        let mut c = TcpStream::connect(self.addr)?;
+        registry.register(&mut c, token, Interest::READABLE | Interest::WRITABLE)?;
+        self.conn = Some(Connection::Tcp(c));
+        self.set_state(ConnectionState::Connecting);
+        self.connection_started_at = Some(time::Instant::now());
         Ok(())
     }
 
-    pub fn disconnect(self: &mut Self) -> Result<(), io::Error> {
-        if let None = self.conn {
-            return Ok(());
-        }
-        self.conn.as_mut().unwrap().shutdown(net::Shutdown::Both)
+    // Same as connect over uTP, the socket reports the token once the peer answered
+    pub fn connect_utp(
+        self: &mut Self,
+        socket: &SharedUtpSocket,
+        token: Token,
+    ) -> Result<(), io::Error> {
+        self.reset();
+        let c = UtpStream::connect(socket, self.addr, token)?;
+        self.conn = Some(Connection::Utp(c));
+        self.set_state(ConnectionState::Connecting);
+        self.connection_started_at = Some(time::Instant::now());
+        Ok(())
     }
 
-    fn configure_connection(self: &mut Self) -> Result<(), io::Error> {
-        if let None = self.conn {
-            return Ok(());
+    // Returns true once the pending connect succeeded
+    pub fn finish_connect(self: &mut Self) -> Result<bool, io::Error> {
+        let connected = match &self.conn {
+            Some(Connection::Tcp(c)) => {
+                if let Some(e) = c.take_error()? {
+                    return Err(e);
+                }
+                match c.peer_addr() {
+                    Ok(_) => true,
+                    Err(e) if e.kind() == io::ErrorKind::NotConnected => false,
+                    Err(e) => return Err(e),
+                }
+            }
+            Some(Connection::Utp(c)) => {
+                if let Some(e) = c.take_error() {
+                    return Err(e);
+                }
+                c.is_connected()
+            }
+            None => return Err(easy_err("not connected")),
+        };
+        if connected {
+            self.set_state(ConnectionState::Handshaking);
         }
+        Ok(connected)
+    }
 
-        self.conn
-            .as_mut()
-            .unwrap()
-            .set_read_timeout(Some(KEEP_ALIVE_MAX_DURATION))?;
-        self.conn
-            .as_mut()
-            .unwrap()
-            .set_write_timeout(Some(KEEP_ALIVE_MAX_DURATION))?;
-        self.conn.as_mut().unwrap().set_nonblocking(false)?;
+    pub fn is_utp(&self) -> bool {
+        matches!(self.conn, Some(Connection::Utp(_)))
+    }
 
+    pub fn accept(
+        self: &mut Self,
+        mut conn: TcpStream,
+        registry: &Registry,
+        token: Token,
+    ) -> Result<(), io::Error> {
+        self.reset();
+        registry.register(&mut conn, token, Interest::READABLE | Interest::WRITABLE)?;
+        self.conn = Some(Connection::Tcp(conn));
+        self.set_state(ConnectionState::Handshaking);
+        self.connection_started_at = Some(time::Instant::now());
         Ok(())
     }
 
-    pub fn handshake(self: &mut Self, info_hash: [u8; 20]) -> Result<(), io::Error> {
-        if let None = self.conn {
-            return Ok(());
+    pub fn accept_utp(self: &mut Self, conn: UtpStream, token: Token) {
+        self.reset();
+        conn.set_token(token);
+        self.conn = Some(Connection::Utp(conn));
+        self.set_state(ConnectionState::Handshaking);
+        self.connection_started_at = Some(time::Instant::now());
+    }
+
+    // Moves the connection to another token, i.e. when an incoming connection is handed to its torrent
+    pub fn reregister(self: &mut Self, registry: &Registry, token: Token) -> Result<(), io::Error> {
+        match &mut self.conn {
+            Some(Connection::Tcp(c)) => {
+                registry.reregister(c, token, Interest::READABLE | Interest::WRITABLE)
+            }
+            Some(Connection::Utp(c)) => {
+                c.set_token(token);
+                Ok(())
+            }
+            None => Err(easy_err("not connected")),
+        }
+    }
+
+    pub fn disconnect(self: &mut Self) -> Result<(), io::Error> {
+        self.set_state(ConnectionState::Disconnected);
+        let conn = match self.conn.take() {
+            Some(Connection::Tcp(c)) => c,
+            // Closed once the queued data is acked
+            Some(Connection::Utp(c)) => {
+                c.shutdown();
+                return Ok(());
+            }
+            None => return Ok(()),
+        };
+        match conn.shutdown(net::Shutdown::Both) {
+            Err(e) if e.kind() != io::ErrorKind::NotConnected => Err(e),
+            _ => Ok(()),
         }
+    }
 
-        let packet = HandshakePacket::new(info_hash).build();
+    // Clears everything we learned during a previous connection
+    fn reset(self: &mut Self) {
+        self.read_buf.clear();
+        self.write_buf.clear();
+        self.handshake_sent = false;
+        self.encryption = None;
+        self.cipher = None;
+        self.encrypted_for = None;
+        self.peer_id = None;
+        self.am_choked = true;
+        self.am_interested = false;
+        self.peer_choked = true;
+        self.peer_interested = false;
+        self.peer_has = Bitfield::default();
+        self.request_queue.clear();
+        self.pipeline.clear();
+        self.supports_extensions = false;
+        self.supports_v2 = false;
+        self.supports_fast = false;
+        self.allowed_fast.clear();
+        self.granted_fast.clear();
+        self.suggested.clear();
+        self.reqq = None;
+        self.last_message_at = None;
+        self.last_sent_at = None;
+        self.last_piece_at = None;
+        self.last_unchoked_at = None;
+        self.data_movements.clear();
+    }
 
-        self.conn.as_ref().unwrap().write(&packet)?;
+    pub fn send_handshake(self: &mut Self, info_hash: [u8; 20], peer_id: [u8; 20]) {
+        let packet = HandshakePacket::new(info_hash, peer_id).build();
+        self.queue(packet);
+        self.handshake_sent = true;
+    }
 
-        let mut buf = [0; 68];
-        self.conn.as_ref().unwrap().read_exact(&mut buf)?;
+    // Starts the MSE handshake of an outgoing connection, the plain handshake follows once it is done
+    pub fn start_encryption(self: &mut Self, info_hash: [u8; 20], policy: EncryptionPolicy) {
+        self.encryption = Some(mse::Handshake::outgoing(info_hash, policy));
+        self.set_state(ConnectionState::Encrypting);
+    }
 
-        match HandshakePacket::parse(&buf) {
-            Some(p) => {
-                println!("got peer id {}", str::from_utf8(&p.peer_id).unwrap());
-                self.peer_id = Some(p.peer_id);
+    // Incoming connections start with either the plain handshake or an MSE public key.
+    // Returns false until enough was read to tell.
+    pub fn detect_encryption(self: &mut Self, policy: EncryptionPolicy) -> Result<bool, io::Error> {
+        if self.state != ConnectionState::Handshaking || self.encrypted_for.is_some() {
+            return Ok(true);
+        }
+        match mse::is_plaintext(&self.read_buf) {
+            None => Ok(false),
+            Some(true) if policy == EncryptionPolicy::Forced => {
+                Err(invalid_handshake("plaintext connections are not allowed"))
+            }
+            Some(false) if policy == EncryptionPolicy::Disabled => {
+                Err(invalid_handshake("encrypted connections are not allowed"))
+            }
+            Some(true) => Ok(true),
+            Some(false) => {
+                self.encryption = Some(mse::Handshake::incoming(policy));
+                self.set_state(ConnectionState::Encrypting);
+                Ok(true)
             }
-            None => {}
         }
+    }
 
+    // Returns true once the MSE handshake is done, or if there is none.
+    // Incoming connections may ask for any of the info hashes.
+    pub fn continue_encryption(
+        self: &mut Self,
+        info_hashes: &[[u8; 20]],
+    ) -> Result<bool, io::Error> {
+        let encryption = match &mut self.encryption {
+            Some(e) => e,
+            None => return Ok(true),
+        };
+        let established =
+            match encryption.step(&mut self.read_buf, &mut self.write_buf, info_hashes)? {
+                Some(e) => e,
+                None => return Ok(false),
+            };
+
+        let method = match established.cipher {
+            Some(_) => "rc4",
+            None => "plaintext",
+        };
+        println!("mse handshake with {:?} done, using {}", self, method);
+        self.encryption = None;
+        self.cipher = established.cipher;
+        self.encrypted_for = Some(established.info_hash);
+        self.set_state(ConnectionState::Handshaking);
+        Ok(true)
+    }
+
+    // Info hash of the remote handshake, once all of it has been read
+    pub fn handshake_info_hash(&self) -> Option<[u8; 20]> {
+        if self.state != ConnectionState::Handshaking || self.read_buf.len() < HANDSHAKE_LEN {
+            return None;
+        }
+        Some(self.read_buf[28..48].try_into().unwrap())
+    }
+
+    // Returns true once the remote handshake was read and the peer is active.
+    // Handshakes for another torrent and connections to ourselves are rejected with InvalidData.
+    pub fn receive_handshake(
+        self: &mut Self,
+        info_hash: [u8; 20],
+        own_peer_id: [u8; 20],
+    ) -> Result<bool, io::Error> {
+        if self.read_buf.len() < HANDSHAKE_LEN {
+            return Ok(false);
+        }
+
+        let p = match HandshakePacket::parse(&self.read_buf[..HANDSHAKE_LEN]) {
+            Some(p) => p,
+            None => return Err(invalid_handshake("unknown protocol")),
+        };
+        if p.info_hash != info_hash || self.encrypted_for.is_some_and(|h| h != info_hash) {
+            return Err(invalid_handshake("info hash mismatch"));
+        }
+        if p.peer_id == own_peer_id {
+            return Err(invalid_handshake("connected to ourselves"));
+        }
+        self.read_buf.drain(..HANDSHAKE_LEN);
+
+        println!("got peer id {}", String::from_utf8_lossy(&p.peer_id));
+        self.peer_id = Some(p.peer_id);
+        self.supports_extensions = p.supports_extensions();
+        self.supports_v2 = p.reserved[7] & V2_PROTOCOL_BIT != 0;
+        self.supports_fast = p.reserved[7] & FAST_EXTENSION_BIT != 0;
+        self.set_state(ConnectionState::Active);
         self.last_message_at = Some(time::Instant::now());
 
-        Ok(())
+        Ok(true)
     }
 
-    pub fn send_message(
-        self: &Self,
-        msg_type: MessageType,
-        payload: Option<&Vec<u8>>,
+    pub fn handshake_sent(&self) -> bool {
+        self.handshake_sent
+    }
+
+    pub fn send_extended_handshake(self: &mut Self) {
+        let mut dict = HashMap::new();
+        dict.insert(
+            "m".as_bytes(),
+            bencoding::Statement::Dictionary(HashMap::new()),
+        );
+        dict.insert(
+            "reqq".as_bytes(),
+            bencoding::Statement::Integer(DEFAULT_MAX_REQUESTS_IN_FLIGHT as i64),
+        );
+
+        self.send_message(PeerMessage::Extended {
+            id: EXTENDED_HANDSHAKE_ID,
+            payload: bencoding::marshal(&bencoding::Statement::Dictionary(dict)),
+        })
+    }
+
+    pub fn use_extended_message(
+        self: &mut Self,
+        id: u8,
+        payload: &Vec<u8>,
     ) -> Result<(), io::Error> {
-        if let None = self.conn {
+        if id != EXTENDED_HANDSHAKE_ID {
+            // We don't advertise any extension messages yet
             return Ok(());
         }
 
-        let msg_buf = match msg_type {
-            MessageType::KeepAlive => create_peer_message(0, None, None),
-            MessageType::Choke
-            | MessageType::Unchoke
-            | MessageType::Interested
-            | MessageType::NotInterested => create_peer_message(1, Some(msg_type as u8), None),
-            MessageType::Have
-            | MessageType::Bitfield
-            | MessageType::Request
-            | MessageType::Piece
-            | MessageType::Cancel
-            | MessageType::Port => {
-                let mut payload_len: u32 = 0;
-                if let Some(p) = payload {
-                    payload_len = p.len() as u32;
-                }
-                create_peer_message(1 + payload_len, Some(msg_type as u8), payload)
-            }
-        };
-
-        self.conn.as_ref().unwrap().write_all(&msg_buf)?;
+        let statements = bencoding::parse(payload).map_err(|e| easy_err(&e))?;
+        if let Some(bencoding::Statement::Dictionary(dict)) = statements.first()
+            && let Some(bencoding::Statement::Integer(reqq)) = dict.get("reqq".as_bytes())
+            && *reqq > 0
+        {
+            self.reqq = Some(*reqq as u32);
+        }
 
         Ok(())
     }
 
-    pub fn has_data(self: &Self) -> Result<bool, io::Error> {
+    // Queues the message, it is written to the connection on the next flush
+    pub fn send_message(self: &mut Self, msg: PeerMessage) {
         if let None = self.conn {
-            return Err(easy_err("not connected"));
+            return;
         }
 
-        let mut buf = [0 as u8; 10];
-
-        self.conn.as_ref().unwrap().set_nonblocking(true)?;
-        let peek_res = self.conn.as_ref().unwrap().peek(&mut buf);
-        self.conn.as_ref().unwrap().set_nonblocking(false)?;
+        let mut buf = Vec::new();
+        msg.encode(&mut buf);
+        self.queue(buf);
+    }
 
-        match peek_res {
-            Ok(nread) => return Ok(nread > 0),
-            Err(e) => {
-                if e.kind() == io::ErrorKind::WouldBlock {
-                    return Ok(false);
-                }
-                return Err(e);
-            }
+    // Sends a keep alive if we had nothing else to say for a while
+    pub fn keep_alive(self: &mut Self) {
+        if self.state == ConnectionState::Active
+            && self
+                .last_sent_at
+                .is_none_or(|t| t.elapsed() >= KEEP_ALIVE_INTERVAL)
+        {
+            self.send_message(PeerMessage::KeepAlive);
         }
     }
 
-    pub fn receive_message(self: &Self) -> Result<Message, io::Error> {
-        if let None = self.conn {
-            return Err(easy_err("not connected"));
+    fn queue(self: &mut Self, mut buf: Vec<u8>) {
+        self.last_sent_at = Some(time::Instant::now());
+        if let Some(c) = &mut self.cipher {
+            c.encrypt.apply(&mut buf);
         }
+        self.write_buf.extend(buf);
+    }
 
-        let mut buf = [0; 4];
-        loop_read(&mut self.conn.as_ref().unwrap(), &mut buf, 4)?;
-
-        let data_len = u32::from_be_bytes(buf.try_into().unwrap()) as usize;
-        if data_len == 0 {
-            return Ok(Message {
-                message_type: MessageType::KeepAlive,
-                payload: Vec::new(),
-            });
+    // Writes queued messages until the connection would block
+    pub fn flush(self: &mut Self) -> Result<(), io::Error> {
+        let conn = match &mut self.conn {
+            Some(c) => c,
+            None => return Ok(()),
+        };
+        if self.state == ConnectionState::Connecting {
+            return Ok(());
         }
 
-        let mut data = vec![0; data_len];
-        loop_read(&mut self.conn.as_ref().unwrap(), &mut data, data_len)?;
-
-        let mt = MessageType::from_u8(data.remove(0));
-        if let None = mt {
-            return Err(easy_err("unknown message type"));
+        let mut written = 0;
+        while written < self.write_buf.len() {
+            match conn.write(&self.write_buf[written..]) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+                Ok(n) => written += n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
         }
+        self.write_buf.drain(..written);
 
-        Ok(Message {
-            message_type: mt.unwrap(),
-            payload: data,
-        })
+        Ok(())
     }
 
-    pub fn has_piece(self: &Self, piece_idx: u32) -> bool {
-        self.peer_has.contains(&piece_idx)
+    pub fn pending_write_len(&self) -> usize {
+        self.write_buf.len()
     }
 
-    // this will overwrite peer's has list
-    pub fn use_bitfield(self: &mut Self, bitfield: &Vec<u8>) {
-        let v = parse_bitfield(bitfield);
-        v.iter().for_each(|t| {
-            self.peer_has.insert(*t as u32);
-        });
+    // Reads everything available on the connection
+    pub fn fill_read_buf(self: &mut Self) -> Result<(), io::Error> {
+        let conn = match &mut self.conn {
+            Some(c) => c,
+            None => return Err(easy_err("not connected")),
+        };
+
+        let mut buf = [0; 16 * 1024];
+        loop {
+            match conn.read(&mut buf) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+                Ok(n) => {
+                    if let Some(c) = &mut self.cipher {
+                        c.decrypt.apply(&mut buf[..n]);
+                    }
+                    self.read_buf.extend_from_slice(&buf[..n]);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Returns the complete messages in the read buffer
+    pub fn receive_messages(self: &mut Self) -> Result<Vec<PeerMessage>, io::Error> {
+        let mut messages = Vec::new();
+
+        let mut start = 0;
+        while let Some((msg, len)) = PeerMessage::decode(&self.read_buf[start..])? {
+            messages.push(msg);
+            start += len;
+        }
+        self.read_buf.drain(..start);
+
+        if !messages.is_empty() {
+            self.last_message_at = Some(time::Instant::now());
+        }
+
+        Ok(messages)
     }
 
-    pub fn set_interested(self: &mut Self, interested: bool) -> Result<(), io::Error> {
+    pub fn set_interested(self: &mut Self, interested: bool) {
         if self.am_interested != interested {
             if interested {
-                self.send_message(MessageType::Interested, None)?;
+                self.send_message(PeerMessage::Interested);
             } else {
-                self.send_message(MessageType::NotInterested, None)?;
+                self.send_message(PeerMessage::NotInterested);
             }
         }
         self.am_interested = interested;
-        Ok(())
     }
 
-    pub fn set_choked(self: &mut Self, choked: bool) -> Result<(), io::Error> {
+    pub fn set_choked(self: &mut Self, choked: bool) {
         if self.peer_choked != choked {
             if choked {
-                self.send_message(MessageType::Choke, None)?;
+                self.send_message(PeerMessage::Choke);
+                // Choked peers have to request again once unchoked, peers supporting
+                // the fast extension are told which requests were dropped
+                for b in std::mem::take(&mut self.request_queue) {
+                    if self.granted_fast.contains(&b.piece_index) {
+                        self.request_queue.push(b);
+                    } else {
+                        self.reject_request(&b);
+                    }
+                }
             } else {
-                self.send_message(MessageType::Unchoke, None)?;
+                self.send_message(PeerMessage::Unchoke);
+                self.last_unchoked_at = Some(time::Instant::now());
             }
         }
         self.peer_choked = choked;
-        Ok(())
     }
 
-    pub fn can_download(&self) -> bool {
-        !self.am_choked
+    // Without the fast extension requests are silently dropped
+    pub fn reject_request(self: &mut Self, b: &Block) {
+        if self.supports_fast {
+            self.send_message(PeerMessage::RejectRequest(*b));
+        }
     }
- 
-    // pub fn can_upload(&self) -> bool {
-    //     !self.peer_choked && self.peer_interested
-    // }
 
-    pub fn get_peer_id(self: &Self) -> Result<Option<String>, std::string::FromUtf8Error> {
-        if let None = self.peer_id {
-            return Ok(None);
+    pub fn suggest(self: &mut Self, piece: u32) {
+        self.suggested.retain(|p| *p != piece);
+        self.suggested.push(piece);
+        if self.suggested.len() > MAX_SUGGESTED_PIECES {
+            self.suggested.remove(0);
         }
+    }
+
+    pub fn can_download(&self) -> bool {
+        !self.am_choked || !self.allowed_fast.is_empty()
+    }
 
-        Ok(Some(String::from_utf8(self.peer_id.unwrap().to_vec())?))
+    pub fn is_snubbed(&self) -> bool {
+        !self.pipeline.is_empty() && self.last_piece_at.is_some_and(|t| t.elapsed() >= SNUB_TIME)
     }
 
-    // Returns bytes per second
-    pub fn calculate_upload_rate(&self, interval: time::Duration) -> usize {
+    // Returns bytes per second the peer sent us
+    pub fn download_rate(&self, interval: time::Duration) -> usize {
+        self.calculate_rate(DataDirection::DownloadedFromPeer, interval)
+    }
+
+    // Returns bytes per second we sent the peer
+    pub fn upload_rate(&self, interval: time::Duration) -> usize {
+        self.calculate_rate(DataDirection::UploadedToPeer, interval)
+    }
+
+    fn calculate_rate(&self, direction: DataDirection, interval: time::Duration) -> usize {
         let mut total_bytes: usize = 0;
         for bm in &self.data_movements {
-            if matches!(bm.direction, DataDirection::DownloadedFromPeer)
-                && bm.when.elapsed() < interval
-            {
+            if bm.direction == direction && bm.when.elapsed() < interval {
                 total_bytes += bm.data_len;
             }
         }
         total_bytes / interval.as_secs() as usize
     }
-}
 
-fn loop_read<T: Read>(r: &mut T, buf: &mut [u8], goal_read: usize) -> Result<(), io::Error> {
-    let mut total_read = 0;
-    while total_read < goal_read {
-        let nread = r.read(&mut buf[total_read..])?;
-        total_read += nread;
+    pub fn prune_data_movements(self: &mut Self, max_age: time::Duration) {
+        self.data_movements.retain(|bm| bm.when.elapsed() < max_age);
     }
-
-    Ok(())
 }
 
 impl Drop for Peer {
@@ -343,33 +694,25 @@ impl Drop for Peer {
     }
 }
 
-fn parse_bitfield(bitfield: &Vec<u8>) -> Vec<usize> {
-    let mut pieces = Vec::new();
+// Peers are reached over TCP or uTP, the wire protocol is the same on both
+enum Connection {
+    Tcp(TcpStream),
+    Utp(UtpStream),
+}
 
-    for (byte_idx, byte) in bitfield.iter().enumerate() {
-        for idx in 0..8 {
-            if byte & (0b10000000 >> (idx % 8)) != 0 {
-                pieces.push(idx + (byte_idx * 8));
-            }
+impl Connection {
+    fn read(self: &mut Self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        match self {
+            Connection::Tcp(c) => c.read(buf),
+            Connection::Utp(c) => c.read(buf),
         }
     }
 
-    pieces
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn test_parse_bitfield() {
-        let mut v = Vec::new();
-        v.push(0b11110000);
-        v.push(0b11111111);
-        v.push(0b00000000);
-        v.push(0b00000001);
-        let b = parse_bitfield(&v);
-        println!("{:?} {}", b, b.len());
+    fn write(self: &mut Self, buf: &[u8]) -> Result<usize, io::Error> {
+        match self {
+            Connection::Tcp(c) => c.write(buf),
+            Connection::Utp(c) => c.write(buf),
+        }
     }
 }
 
@@ -379,21 +722,7 @@ impl fmt::Debug for Peer {
     }
 }
 
-fn create_peer_message(len: u32, id: Option<u8>, payload: Option<&Vec<u8>>) -> Vec<u8> {
-    let mut buf = Vec::new();
-
-    buf.extend_from_slice(&len.to_be_bytes());
-    if let Some(i) = id {
-        buf.push(i);
-    }
-    if let Some(p) = payload {
-        buf.extend_from_slice(&p);
-    }
-
-    buf
-}
-
-trait Packet {
+pub trait Packet {
     fn build(self: &Self) -> Vec<u8>;
     fn parse(buf: &[u8]) -> Option<Box<Self>>;
 }
@@ -401,18 +730,37 @@ trait Packet {
 // https://wiki.theory.org/BitTorrentSpecification#Handshake
 pub struct HandshakePacket {
     prot: [u8; 19],
+    reserved: [u8; 8],
     info_hash: [u8; 20],
     peer_id: [u8; 20],
 }
 
 impl HandshakePacket {
-    fn new(info_hash: [u8; 20]) -> Self {
+    pub fn new(info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
         Self {
             prot: BITTORRENT_PROTOCOL.as_bytes().try_into().unwrap(),
+            reserved: [
+                0,
+                0,
+                0,
+                0,
+                0,
+                EXTENSION_PROTOCOL_BIT,
+                0,
+                V2_PROTOCOL_BIT | FAST_EXTENSION_BIT,
+            ],
             info_hash: info_hash,
-            peer_id: *PEER_ID.get().unwrap(),
+            peer_id: peer_id,
         }
     }
+
+    pub fn info_hash(&self) -> [u8; 20] {
+        self.info_hash
+    }
+
+    pub fn supports_extensions(&self) -> bool {
+        self.reserved[5] & EXTENSION_PROTOCOL_BIT != 0
+    }
 }
 
 impl Packet for HandshakePacket {
@@ -421,8 +769,7 @@ impl Packet for HandshakePacket {
 
         buf.push(19);
         buf.extend(&self.prot);
-        // 8 reserved bytes
-        buf.extend(&[0; 8]);
+        buf.extend(&self.reserved);
         buf.extend(&self.info_hash);
         buf.extend(&self.peer_id);
 
@@ -440,12 +787,17 @@ impl Packet for HandshakePacket {
 
         Some(Box::new(Self {
             prot: BITTORRENT_PROTOCOL.as_bytes().try_into().unwrap(),
+            reserved: buf.get(20..28).unwrap().try_into().unwrap(),
             info_hash: buf.get(28..48).unwrap().try_into().unwrap(),
             peer_id: buf.get(48..68).unwrap().try_into().unwrap(),
         }))
     }
 }
 
+fn invalid_handshake(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
 fn ip_to_socket_addr(ip: u32, port: u16) -> SocketAddr {
     SocketAddr::new(
         net::IpAddr::V4(net::Ipv4Addr::new(
@@ -467,3 +819,51 @@ pub fn ip_to_str(ip: u32) -> String {
         (ip) as u8,
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_receive_handshake() {
+        let info_hash = [1; 20];
+        let own_id = [b'a'; 20];
+        let handshake = |info_hash, peer_id| HandshakePacket::new(info_hash, peer_id).build();
+
+        let mut p = Peer::new(0x7f000001, 6881);
+        // As after accepting the connection
+        p.state = ConnectionState::Handshaking;
+        p.read_buf = handshake([2; 20], [b'b'; 20]);
+        assert!(p.receive_handshake(info_hash, own_id).is_err());
+
+        p.read_buf = handshake(info_hash, own_id);
+        assert!(p.receive_handshake(info_hash, own_id).is_err());
+
+        p.read_buf = handshake(info_hash, [b'b'; 20]);
+        p.read_buf[1] = b'b';
+        assert!(p.receive_handshake(info_hash, own_id).is_err());
+
+        p.read_buf = handshake(info_hash, [b'b'; 20]);
+        p.read_buf.truncate(HANDSHAKE_LEN - 1);
+        assert!(!p.receive_handshake(info_hash, own_id).unwrap());
+        p.read_buf.push(b'b');
+        assert!(p.receive_handshake(info_hash, own_id).unwrap());
+        assert_eq!(p.state, ConnectionState::Active);
+        assert_eq!(p.peer_id, Some([b'b'; 20]));
+        assert!(p.supports_extensions);
+        assert!(p.supports_v2);
+        assert!(p.supports_fast);
+    }
+
+    #[test]
+    fn test_state_transitions() {
+        use ConnectionState::*;
+        assert!(Disconnected.can_become(Connecting));
+        assert!(Handshaking.can_become(Encrypting));
+        assert!(Active.can_become(Closing));
+        assert!(Closing.can_become(Disconnected));
+        assert!(!Connecting.can_become(Active));
+        assert!(!Closing.can_become(Active));
+        assert!(!Disconnected.can_become(Active));
+    }
+}
@@ -1,836 +1,1569 @@
 use crate::{
-    peer::{DataDirection, DataMovement, KEEP_ALIVE_MAX_DURATION, MessageType, Peer},
-    server::Server,
-    torrent::{Block, DEFAULT_BLOCK_LENGTH, DownloadBlock, Torrent},
+    bitfield::Bitfield,
+    candidates::{CandidateList, PeerSource},
+    choker::{ChokeCandidate, Choker, DEFAULT_UPLOAD_SLOTS, SeedChokeMode},
+    connection_limits::{
+        self, ConnectionLimits, ConnectionSlots, DEFAULT_TORRENT_LIMITS, ReplaceCandidate,
+    },
+    disk::{DiskFailure, DiskIo},
+    hasher::{HashOutcome, HashResult, Hasher},
+    merkle::{self, Hash, HashRequest, MAX_HASH_REQUEST_LENGTH},
+    message::PeerMessage,
+    mse::EncryptionPolicy,
+    peer::{
+        ConnectionState, DataDirection, DataMovement, HANDSHAKE_TIMEOUT, KEEP_ALIVE_MAX_DURATION,
+        Peer,
+    },
+    picker::PiecePicker,
+    pipeline::DEFAULT_MAX_REQUESTS_IN_FLIGHT,
+    rate_limit::{self, PIECE_MESSAGE_OVERHEAD, RateLimit, Throttle},
+    read_cache::{DEFAULT_READ_CACHE_SIZE, ReadCache},
+    session::TORRENT_TOKEN_SHIFT,
+    torrent::{Block, DEFAULT_BLOCK_LENGTH, DownloadBlock, PieceHash, Torrent},
+    trust::PeerTrust,
     util::easy_err,
+    utp::SharedUtpSocket,
+    web_seed::{WebSeed, WebSeedKind},
 };
+use mio::{Registry, Token, event::Event};
 use std::{
-    cmp::min,
-    collections::HashSet,
-    fs,
-    io::{self, Write},
-    net,
-    os::unix::fs::FileExt,
-    sync::Arc,
-    thread::{self, JoinHandle},
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
     time,
 };
 
+// Owns every connection of a single torrent. The session polls the sockets
+// and hands the events of the pool's tokens to it.
+// Sockets are non-blocking, reads and writes go through the peers' buffers.
 pub struct PeerPool {
+    // Tokens of this pool's connections are prefixed with the id
+    id: usize,
+    peer_id: [u8; 20],
     torrent: Torrent,
-    download_file_name: String,
-    have_pieces: HashSet<u32>,
-    pieces_in_progress: HashSet<u32>,
-
-    server: Option<Server>,
-    accept_thread: Option<JoinHandle<Option<Peer>>>,
-
-    active_peers: Vec<Peer>,
-    downloading_threads: Vec<DownloadThread>,
-    uploading_threads: Vec<UploadThread>,
-    backlog_peers: Vec<Peer>, // these are unconnected peers
-
+    disk: DiskIo,
+    hasher: Hasher,
+    // Set until the disk has written everything a requested recheck has to see
+    recheck_requested: bool,
+    // Last reported recheck progress in percent
+    recheck_percent: u32,
+    // Validated v2 block hashes of pieces that failed the hash check before
+    block_hashes: HashMap<u32, Vec<Hash>>,
+    picker: PiecePicker,
+    // Hash check results per peer ip, decides who gets banned
+    trust: PeerTrust,
+    paused: bool,
+
+    throttle: Throttle,
+    // Limit of each single peer of the torrent
+    peer_limit: RateLimit,
+    // Whether message framing is counted against the limits, not just piece data
+    count_overhead: bool,
+    encryption: EncryptionPolicy,
+    // Outgoing connections try uTP first when set
+    utp: Option<SharedUtpSocket>,
+
+    peers: HashMap<Token, Peer>, // connecting, handshaking, active and closing peers
+    // Closing peers don't count, they are gone after the close timeout at the latest
+    limits: ConnectionLimits,
+    connect_timeout: time::Duration,
+    next_token: usize,
+    // Every peer we know of, the unconnected ones wait for their next attempt
+    candidates: CandidateList,
+    web_seeds: Vec<WebSeed>,
+
+    // Pieces we got since the last HAVE broadcast
+    new_pieces: Vec<u32>,
+
+    choker: Choker,
+    choke_interval: time::Duration,
+    // Pieces recently read for uploading
+    read_cache: ReadCache,
     last_choke_update: time::Instant,
-    last_optimic_unchoke: time::Instant,
-}
-
-struct DownloadThread {
-    piece: u32,
-    thread: JoinHandle<(Option<Vec<u8>>, Peer, bool)>,
-}
-
-struct UploadThread {
-    thread: JoinHandle<(Peer, bool)>,
 }
 
-const MAX_CONNECTIONS: usize = 64;
-const MAX_FAILED_CONNECTION_ATTEMPTS: u32 = 5;
-const DECIDE_CHOKE_INTERVAL: time::Duration = time::Duration::from_secs(10);
+pub const DEFAULT_CONNECT_TIMEOUT: time::Duration = time::Duration::from_secs(3);
+// Requests are only served while less than this is waiting to be written to the peer
+const MAX_PENDING_UPLOAD_BYTES: usize = 4 * DEFAULT_BLOCK_LENGTH as usize;
+// Most clients request 16 KiB blocks, larger ones are allowed up to this size
+const MAX_REQUEST_LENGTH: u32 = 128 * 1024;
+// Same as the reqq we advertise in the extended handshake
+const MAX_QUEUED_REQUESTS: usize = DEFAULT_MAX_REQUESTS_IN_FLIGHT;
+
+// Size of the allowed fast set we give every peer
+const ALLOWED_FAST_COUNT: u32 = 10;
+
+// Unanswered requests are given to other peers after this
+const REQUEST_TIMEOUT: time::Duration = time::Duration::from_secs(90);
+// Closing peers are dropped after this even if their queue isn't written yet
+const CLOSE_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+pub const DEFAULT_CHOKE_INTERVAL: time::Duration = time::Duration::from_secs(10);
+// Transfer rates used for choking are averaged over this long
+const CHOKE_RATE_INTERVAL: time::Duration = time::Duration::from_secs(20);
 
 impl PeerPool {
-    pub fn new(torrent: Torrent, download_file_name: String) -> Result<PeerPool, io::Error> {
-        Ok(PeerPool {
+    pub fn new(
+        id: usize,
+        peer_id: [u8; 20],
+        torrent: Torrent,
+        disk: DiskIo,
+        hasher: Hasher,
+    ) -> PeerPool {
+        let mut web_seeds = Vec::new();
+        let urls = torrent
+            .url_list
+            .iter()
+            .map(|u| (u, WebSeedKind::UrlList))
+            .chain(
+                torrent
+                    .http_seeds
+                    .iter()
+                    .map(|u| (u, WebSeedKind::HttpSeed)),
+            );
+        for (url, kind) in urls {
+            if !url.starts_with("http://") {
+                println!("skipping web seed {}, only http is supported", url);
+                continue;
+            }
+            web_seeds.push(WebSeed::new(url.clone(), kind));
+        }
+        // Uploads wait for pieces to arrive in the read cache, so it has to fit a few
+        let read_cache_size = DEFAULT_READ_CACHE_SIZE.max(4 * torrent.piece_len as usize);
+
+        PeerPool {
+            id: id,
+            peer_id: peer_id,
+            picker: PiecePicker::new(
+                (0..torrent.get_total_piece_count())
+                    .map(|p| torrent.get_piece_len(p))
+                    .collect(),
+            ),
             torrent: torrent,
-            have_pieces: HashSet::new(),
-            pieces_in_progress: HashSet::new(),
-            server: Some(Server::start()?),
-            accept_thread: None,
-            backlog_peers: Vec::new(),
-            active_peers: Vec::new(),
-            download_file_name: download_file_name,
-            downloading_threads: Vec::new(),
-            uploading_threads: Vec::new(),
+            disk: disk,
+            hasher: hasher,
+            recheck_requested: false,
+            recheck_percent: 0,
+            block_hashes: HashMap::new(),
+            trust: PeerTrust::new(),
+            paused: false,
+            throttle: Throttle::new(RateLimit::default()),
+            peer_limit: RateLimit::default(),
+            count_overhead: false,
+            encryption: EncryptionPolicy::default(),
+            utp: None,
+            peers: HashMap::new(),
+            limits: DEFAULT_TORRENT_LIMITS,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            next_token: 0,
+            candidates: CandidateList::new(),
+            web_seeds: web_seeds,
+            new_pieces: Vec::new(),
+            choker: Choker::new(DEFAULT_UPLOAD_SLOTS),
+            choke_interval: DEFAULT_CHOKE_INTERVAL,
+            read_cache: ReadCache::new(read_cache_size),
             last_choke_update: time::Instant::now(),
-            last_optimic_unchoke: time::Instant::now(),
-        })
-    }
-
-    pub fn connect_peers(self: &mut Self, mut peers: Vec<Peer>) {
-        let mut ts: Vec<JoinHandle<(Peer, bool)>> = Vec::new();
-
-        for _ in 0..peers.len() {
-            let mut peer = peers.remove(0);
-            let info_hash = self.torrent.info_hash.clone();
-            let t = thread::spawn(move || -> (Peer, bool) {
-                match peer.connect() {
-                    Ok(_) => {}
-                    Err(e) => {
-                        println!("failed to connect {:?}", e);
-                        peer.failed_connection_attempts += 1;
-                        return (peer, false);
-                    }
-                }
-                match peer.handshake(info_hash) {
-                    Ok(_) => {}
-                    Err(e) => {
-                        println!("failed to handshake {:?}", e);
-                        if let Err(e) = peer.disconnect() {
-                            println!("failed to disconnect client bc of failed handshake {:?}", e);
-                        }
-                        peer.failed_connection_attempts += 1;
-                        return (peer, false);
-                    }
-                }
-                peer.failed_connection_attempts = 0;
-                return (peer, true);
-            });
-            ts.push(t);
         }
+    }
 
-        for _ in 0..ts.len() {
-            let t = ts.remove(0);
-            match t.join() {
-                Ok(p) => {
-                    if p.1 {
-                        self.active_peers.push(p.0);
-                    } else {
-                        if p.0.failed_connection_attempts < MAX_FAILED_CONNECTION_ATTEMPTS {
-                            self.backlog_peers.push(p.0);
-                        } else {
-                            let _ = p.0.get_peer_id().map(|r| {
-                                if let Some(pid) = r {
-                                    println!(
-                                        "removing peer {} from backlog, failed too many times",
-                                        pid
-                                    );
-                                }
-                            });
-                        }
-                    }
+    pub fn info_hash(&self) -> [u8; 20] {
+        self.torrent.info_hash
+    }
+
+    // Every piece is verified, either downloaded or found on disk, and written
+    pub fn is_complete(&self) -> bool {
+        self.picker.is_complete() && self.recheck_progress().is_none() && self.disk.is_idle()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn set_rate_limit(self: &mut Self, limit: RateLimit) {
+        self.throttle.set_limit(limit);
+    }
+
+    pub fn set_peer_rate_limit(self: &mut Self, limit: RateLimit) {
+        self.peer_limit = limit;
+        for p in self.peers.values_mut() {
+            p.throttle.set_limit(limit);
+        }
+    }
+
+    pub fn set_upload_slots(self: &mut Self, slots: usize) {
+        self.choker.slots = slots;
+    }
+
+    pub fn set_seed_choke_mode(self: &mut Self, mode: SeedChokeMode) {
+        self.choker.seed_mode = mode;
+    }
+
+    pub fn set_count_overhead(self: &mut Self, count_overhead: bool) {
+        self.count_overhead = count_overhead;
+    }
+
+    pub fn set_encryption_policy(self: &mut Self, policy: EncryptionPolicy) {
+        self.encryption = policy;
+    }
+
+    // Connections above the new limit are closed on the next tick, the worst first
+    pub fn set_connection_limits(self: &mut Self, limits: ConnectionLimits) {
+        self.limits = limits;
+    }
+
+    pub fn set_connect_timeout(self: &mut Self, timeout: time::Duration) {
+        self.connect_timeout = timeout;
+    }
+
+    pub fn set_choke_interval(self: &mut Self, interval: time::Duration) {
+        self.choke_interval = interval;
+    }
+
+    // Open and half open connections, closing ones aren't counted
+    pub fn connection_counts(&self) -> (usize, usize) {
+        let open = self
+            .peers
+            .values()
+            .filter(|p| p.state != ConnectionState::Closing)
+            .count();
+        let half_open = self
+            .peers
+            .values()
+            .filter(|p| is_half_open(p.state))
+            .count();
+        (open, half_open)
+    }
+
+    pub fn set_utp(self: &mut Self, utp: Option<SharedUtpSocket>) {
+        self.utp = utp;
+    }
+
+    // Adds the peers to the candidates, the best ones are connected right away if there is room
+    pub fn connect_peers(
+        self: &mut Self,
+        registry: &Registry,
+        peers: Vec<Peer>,
+        source: PeerSource,
+        slots: &mut ConnectionSlots,
+    ) {
+        for peer in peers {
+            if !self.trust.is_banned(&peer.addr().ip()) {
+                self.candidates.add(peer, source);
+            }
+        }
+        if !self.paused {
+            self.connect_candidates(registry, slots);
+        }
+    }
+
+    // Connects candidates while both the torrent's and the session's limits leave room
+    fn connect_candidates(self: &mut Self, registry: &Registry, slots: &mut ConnectionSlots) {
+        let now = time::Instant::now();
+        let (open, half_open) = self.connection_counts();
+        let mut own = self.limits.free(open, half_open);
+        while own.can_connect() && slots.can_connect() {
+            let mut peer = match self.candidates.next(now) {
+                Some(p) => p,
+                None => return,
+            };
+
+            let token = self.next_token();
+            peer.throttle.set_limit(self.peer_limit);
+            let connected = match &self.utp {
+                Some(utp) if !peer.tcp_only => peer.connect_utp(utp, token),
+                _ => peer.connect(registry, token),
+            };
+            match connected {
+                Ok(_) => {
+                    self.peers.insert(token, peer);
+                    own.take_connect();
+                    slots.take_connect();
                 }
                 Err(e) => {
-                    println!("failed to join thread {:?}", e);
+                    println!("failed to connect {:?}", e);
+                    self.connection_failed(peer);
                 }
             }
         }
     }
 
-    pub fn handle(self: &mut Self) {
-        loop {
-            if self.count_active_connections() < MAX_CONNECTIONS {
-                self.accept_connections();
-            }
+    // Takes over an incoming connection whose handshake asked for this torrent.
+    // When there is no room left the worst peer is replaced, if any was connected long enough.
+    pub fn add_incoming(
+        self: &mut Self,
+        registry: &Registry,
+        global: &mut Throttle,
+        slots: &mut ConnectionSlots,
+        mut peer: Peer,
+    ) {
+        if self.trust.is_banned(&peer.addr().ip()) {
+            println!("dropping incoming connection from banned peer {:?}", peer);
+            return;
+        }
+        let (open, half_open) = self.connection_counts();
+        let free = self.limits.free(open, half_open).min(*slots);
+        if free.connections == 0 && !self.replace_worst_peer() {
+            println!(
+                "dropping incoming connection from {:?}, too many connections",
+                peer
+            );
+            return;
+        }
+        slots.take_incoming();
+
+        let token = self.next_token();
+        if let Err(e) = peer.reregister(registry, token) {
+            println!("failed to accept {:?}", e);
+            return;
+        }
+        peer.throttle.set_limit(self.peer_limit);
+
+        println!("peer connected via server");
+        self.candidates
+            .add_connected(peer.addr(), PeerSource::Incoming);
+        self.peers.insert(token, peer);
+        // The remote handshake is already buffered, ours is only sent once it checks out
+        self.handle_peer(token, false, global);
+    }
+
+    pub fn handle_event(self: &mut Self, event: &Event, global: &mut Throttle) {
+        self.handle_peer(
+            event.token(),
+            event.is_readable() || event.is_read_closed(),
+            global,
+        );
+    }
+
+    // The uTP socket has no readiness per connection, it reports the changed ones
+    pub fn handle_utp_ready(self: &mut Self, token: Token, global: &mut Throttle) {
+        self.handle_peer(token, true, global);
+    }
+
+    // Disconnects every peer, they are connected again on resume
+    pub fn pause(self: &mut Self) {
+        if self.paused {
+            return;
+        }
+        self.paused = true;
 
-            // Try to connect to backlog peers.
-            // Only do this while downloading, no need to actively seek
-            // peers after download is finished.
-            if self.count_pieces_left() > 0 {
-                self.attempt_backlog_connections();
+        let tokens: Vec<Token> = self.peers.keys().copied().collect();
+        for token in tokens {
+            if self.peers[&token].state == ConnectionState::Active {
+                self.close_gracefully(token);
             } else {
-                self.backlog_peers.clear();
+                // Connections that never completed are tried again as well
+                let peer = self.peers.remove(&token).unwrap();
+                self.close_peer(peer, true);
             }
+        }
+        println!("paused {}", self.torrent.get_info_hash_str());
+    }
 
-            self.consume_messages();
+    pub fn resume(self: &mut Self) {
+        self.paused = false;
+        self.candidates.retry_now();
+        println!("resumed {}", self.torrent.get_info_hash_str());
+    }
 
-            if self.last_choke_update.elapsed() >= DECIDE_CHOKE_INTERVAL {
-                self.run_choke_algo();
-            }
+    // Hashes every piece on disk again, pieces that fail are downloaded again.
+    // Starts once the cached blocks are written.
+    pub fn recheck(self: &mut Self) {
+        self.disk.flush_all();
+        self.recheck_requested = true;
+    }
+
+    pub fn cancel_recheck(self: &mut Self) {
+        self.recheck_requested = false;
+        if self.hasher.recheck_progress().is_some() {
+            self.hasher.cancel_recheck();
+            println!("cancelled recheck of {}", self.torrent.get_info_hash_str());
+        }
+    }
 
-            if self.count_pieces_left() > 0 {
-                self.download();
+    // Checked and total piece count while a recheck runs
+    pub fn recheck_progress(&self) -> Option<(u32, u32)> {
+        if self.recheck_requested {
+            return Some((0, self.torrent.get_total_piece_count()));
+        }
+        self.hasher.recheck_progress()
+    }
+
+    // Work that doesn't depend on socket readiness
+    pub fn tick(
+        self: &mut Self,
+        registry: &Registry,
+        global: &mut Throttle,
+        slots: &mut ConnectionSlots,
+    ) {
+        for f in self.disk.tick() {
+            self.on_disk_failure(f);
+        }
+        for (piece, r) in self.disk.finished_reads() {
+            match r {
+                Ok(data) => self.read_cache.insert(piece, data),
+                Err(e) => self.on_read_failure(piece, e),
             }
-            self.upload();
-            self.check_keep_alive();
         }
+        if self.recheck_requested && self.disk.is_idle() {
+            self.recheck_requested = false;
+            self.recheck_percent = 0;
+            self.hasher.start_recheck(self.disk.file(), &self.torrent);
+        }
+        let rechecking = self.hasher.recheck_progress().is_some();
+        for r in self.hasher.results() {
+            self.on_hash_result(r);
+        }
+        // The last results of a recheck come in together, the summary is printed once
+        if rechecking && self.hasher.recheck_progress().is_none() {
+            println!(
+                "recheck done, have {} of {} pieces",
+                self.picker.have_pieces().count(),
+                self.torrent.get_total_piece_count()
+            );
+        }
+        self.collect_web_seed_pieces(global);
+        if self.paused {
+            // Only closing peers are left
+            self.check_timeouts();
+            self.flush_peers();
+            return;
+        }
+
+        // Only connect to candidates while downloading, no need to actively seek
+        // peers after download is finished.
+        self.enforce_connection_limit();
+        if self.count_pieces_left() > 0 {
+            self.connect_candidates(registry, slots);
+        } else {
+            self.candidates.drop_unconnected();
+        }
+
+        if self.last_choke_update.elapsed() >= self.choke_interval {
+            self.run_choke_algo();
+        }
+
+        // Pieces might turn out to be on disk already while rechecking
+        if !self.picker.is_complete() && self.recheck_progress().is_none() {
+            self.download(global);
+            self.download_from_web_seeds(global);
+        }
+        self.announce_new_pieces();
+        self.upload(global);
+        self.expire_requests();
+        self.check_timeouts();
+        for p in self.peers.values_mut() {
+            p.keep_alive();
+        }
+        self.flush_peers();
     }
 
-    fn accept_connections(&mut self) {
-        if self.accept_thread.is_some() {
-            if !self.accept_thread.as_ref().unwrap().is_finished() {
-                return;
+    fn next_token(self: &mut Self) -> Token {
+        let t = Token(self.id << TORRENT_TOKEN_SHIFT | self.next_token);
+        self.next_token += 1;
+        t
+    }
+
+    fn handle_peer(self: &mut Self, token: Token, readable: bool, global: &mut Throttle) {
+        // The peer is taken out of the map so that message handling can use the rest of the pool
+        let mut peer = match self.peers.remove(&token) {
+            Some(p) => p,
+            None => return,
+        };
+
+        match self.process_peer(&mut peer, readable, global) {
+            Ok(_) => {
+                if self.trust.is_banned(&peer.addr().ip()) {
+                    self.close_peer(peer, false);
+                } else {
+                    self.peers.insert(token, peer);
+                }
             }
-            match self.accept_thread.take().unwrap().join() {
-                Ok(p) => {
-                    if p.is_some() {
-                        println!("peer connected via server");
-                        self.active_peers.push(p.unwrap());
+            Err(e) => {
+                println!("failed to handle peer {:?} {:?}", peer, e);
+                match peer.state {
+                    // Rejected handshake, there is no point in connecting again
+                    _ if e.kind() == io::ErrorKind::InvalidData => self.close_peer(peer, false),
+                    ConnectionState::Active | ConnectionState::Closing => {
+                        self.close_peer(peer, true)
                     }
-                }
-                Err(e) => {
-                    println!("failed to join accept thread {:?}", e);
+                    _ => self.connection_failed(peer),
                 }
             }
         }
+    }
 
-        if self.server.is_none() {
-            return;
+    fn process_peer(
+        self: &mut Self,
+        peer: &mut Peer,
+        readable: bool,
+        global: &mut Throttle,
+    ) -> Result<(), io::Error> {
+        if peer.state == ConnectionState::Closing {
+            // Whatever it sends now is ignored
+            return peer.flush();
+        }
+        if peer.state == ConnectionState::Connecting {
+            if !peer.finish_connect()? {
+                return Ok(());
+            }
+            if self.encryption == EncryptionPolicy::Disabled || peer.plaintext_only {
+                peer.send_handshake(self.torrent.info_hash, self.peer_id);
+            } else {
+                peer.start_encryption(self.torrent.info_hash, self.encryption);
+            }
         }
 
-        let server = self.server.take().unwrap();
-        let info_hash = self.torrent.info_hash.clone();
-        let have_pieces = self.have_pieces.clone();
-        let piece_count = self.torrent.get_total_piece_count();
+        if readable {
+            peer.fill_read_buf()?;
+        }
 
-        self.accept_thread = Some(thread::spawn(move || -> Option<Peer> {
-            let (conn, addr) = server.s.accept().unwrap();
-            if addr.ip().is_ipv6() {
-                return None;
+        if peer.state == ConnectionState::Encrypting {
+            if !peer.continue_encryption(&[self.torrent.info_hash])? {
+                return peer.flush();
             }
+            peer.send_handshake(self.torrent.info_hash, self.peer_id);
+        }
 
-            let mut peer = match addr.ip() {
-                net::IpAddr::V4(v4) => Peer::new(u32::from_be_bytes(v4.octets()), addr.port()),
-                net::IpAddr::V6(_) => {
-                    return None;
-                }
-            };
+        if peer.state == ConnectionState::Handshaking {
+            if !peer.receive_handshake(self.torrent.info_hash, self.peer_id)? {
+                return peer.flush();
+            }
+            if self
+                .peers
+                .values()
+                .any(|p| p.state == ConnectionState::Active && p.peer_id == peer.peer_id)
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "already connected to peer id",
+                ));
+            }
+            self.candidates.connected(peer.addr());
+            peer.peer_has = Bitfield::new(self.torrent.get_total_piece_count());
 
-            match peer.accept(conn) {
-                Ok(_) => {}
-                Err(e) => {
-                    println!("failed to accept {:?}", e);
-                    return None;
-                }
+            if !peer.handshake_sent() {
+                peer.send_handshake(self.torrent.info_hash, self.peer_id);
             }
-            match peer.handshake(info_hash) {
-                Ok(_) => {}
-                Err(e) => {
-                    println!("failed to handshake with incoming peer {:?}", e);
-                    return None;
-                }
+            if peer.supports_extensions {
+                peer.send_extended_handshake();
             }
-            peer.send_message(
-                MessageType::Bitfield,
-                Some(&create_bitfield(piece_count, &have_pieces)),
-            )
-            .unwrap();
+            self.send_have_pieces(peer);
+        }
+
+        for msg in peer.receive_messages()? {
+            self.handle_message(peer, msg)?;
+        }
+        self.request_blocks(peer, global);
 
-            return Some(peer);
-        }));
+        peer.flush()
     }
 
-    fn run_choke_algo(&mut self) {
-        let unchoke = self.decide_unchoke();
+    // Follows the handshake
+    fn send_have_pieces(self: &mut Self, peer: &mut Peer) {
+        let piece_count = self.torrent.get_total_piece_count();
+        if peer.supports_fast {
+            if self.picker.is_complete() {
+                peer.send_message(PeerMessage::HaveAll);
+            } else if self.picker.have_pieces().is_empty() {
+                peer.send_message(PeerMessage::HaveNone);
+            } else {
+                peer.send_message(PeerMessage::Bitfield(
+                    self.picker.have_pieces().as_bytes().to_vec(),
+                ));
+            }
 
-        let mut ts = spawn_peer_threads(
-            &mut self
-                .active_peers
-                .extract_if(.., |ap| unchoke.contains(ap.peer_id.as_ref().unwrap()))
-                .collect(),
-            |mut p: Peer| -> Option<Peer> {
-                if let Err(e) = p.set_choked(false) {
-                    println!("failed to set uninterested for peer {:?}", e);
-                    return None;
+            let ip = Ipv4Addr::from(peer.ip_address);
+            for piece in
+                allowed_fast_set(ip, &self.torrent.info_hash, piece_count, ALLOWED_FAST_COUNT)
+            {
+                peer.granted_fast.insert(piece);
+                peer.send_message(PeerMessage::AllowedFast(piece));
+            }
+        } else if !self.picker.have_pieces().is_empty() {
+            peer.send_message(PeerMessage::Bitfield(
+                self.picker.have_pieces().as_bytes().to_vec(),
+            ));
+        }
+    }
+
+    fn handle_message(self: &mut Self, peer: &mut Peer, msg: PeerMessage) -> Result<(), io::Error> {
+        let piece_count = self.torrent.get_total_piece_count();
+        match msg {
+            PeerMessage::SuggestPiece(_)
+            | PeerMessage::HaveAll
+            | PeerMessage::HaveNone
+            | PeerMessage::RejectRequest(_)
+            | PeerMessage::AllowedFast(_)
+                if !peer.supports_fast =>
+            {
+                return Err(easy_err("fast extension message from peer without it"));
+            }
+            _ => {}
+        }
+
+        match msg {
+            PeerMessage::KeepAlive => {}
+            PeerMessage::Choke => {
+                peer.am_choked = true;
+                // Peers discard pending requests when they choke us, with the
+                // fast extension they reject them one by one instead
+                if !peer.supports_fast {
+                    for b in peer.pipeline.clear() {
+                        self.picker.cancel_request(&b);
+                    }
                 }
-                Some(p)
-            },
-        );
-        ts.extend(spawn_peer_threads(
-            &mut self.active_peers,
-            |mut p: Peer| -> Option<Peer> {
-                if let Err(e) = p.set_choked(true) {
-                    println!("failed to set uninterested for peer {:?}", e);
-                    return None;
+            }
+            PeerMessage::Unchoke => {
+                peer.am_choked = false;
+            }
+            PeerMessage::Interested => {
+                peer.peer_interested = true;
+            }
+            PeerMessage::NotInterested => {
+                peer.peer_interested = false;
+            }
+            PeerMessage::Have(have_idx) => {
+                check_piece_index(have_idx, &self.torrent)?;
+                peer.peer_has.set(have_idx);
+                if !peer.am_interested
+                    && self
+                        .picker
+                        .is_interesting(&Bitfield::from_pieces(piece_count, [have_idx]))
+                {
+                    peer.set_interested(true);
                 }
-                Some(p)
-            },
-        ));
-        for t in ts {
-            match t.join() {
-                Ok(p) => {
-                    if let Some(peer) = p {
-                        self.active_peers.push(peer);
-                    }
+            }
+            PeerMessage::Bitfield(bitfield) => {
+                peer.peer_has = Bitfield::from_bytes(&bitfield, piece_count)?;
+                peer.set_interested(self.picker.is_interesting(&peer.peer_has));
+            }
+            PeerMessage::Request(b) => {
+                check_request(&self.torrent, &b)?;
+                if peer.peer_choked && !peer.granted_fast.contains(&b.piece_index) {
+                    // Requests sent before our choke arrived
+                    peer.reject_request(&b);
+                } else if !self.picker.has(b.piece_index) {
+                    println!(
+                        "peer {:?} requested piece {} we don't have",
+                        peer, b.piece_index
+                    );
+                    peer.reject_request(&b);
+                } else if peer.request_queue.len() >= MAX_QUEUED_REQUESTS {
+                    println!(
+                        "peer {:?} exceeded the request queue, dropping request",
+                        peer
+                    );
+                    peer.reject_request(&b);
+                } else {
+                    peer.request_queue.push(b);
                 }
-                Err(e) => {
-                    println!("failed to join thread {:?}", e);
+            }
+            PeerMessage::Piece {
+                piece_index,
+                byte_offset,
+                data,
+            } => {
+                let db = DownloadBlock {
+                    piece_index: piece_index,
+                    byte_offset: byte_offset,
+                    data: data,
+                };
+                peer.data_movements.push(DataMovement {
+                    data_len: db.data.len(),
+                    direction: DataDirection::DownloadedFromPeer,
+                    when: time::Instant::now(),
+                });
+                self.on_block(peer, db)?;
+            }
+            PeerMessage::Cancel(b) => {
+                if let Some(idx) = peer.request_queue.iter().position(|p| p.eq(&b)) {
+                    peer.request_queue.swap_remove(idx);
+                    // Fast peers expect every request to be answered
+                    peer.reject_request(&b);
                 }
             }
-        }
-    }
-
-    // Returns peer ids to unchoke, any peer id not in this list is to be choked
-    fn decide_unchoke(&mut self) -> Vec<[u8; 20]> {
-        self.last_choke_update = time::Instant::now();
+            PeerMessage::SuggestPiece(piece) => {
+                check_piece_index(piece, &self.torrent)?;
+                if !self.picker.has(piece) {
+                    peer.suggest(piece);
+                }
+            }
+            PeerMessage::HaveAll => {
+                peer.peer_has = Bitfield::full(piece_count);
+                peer.set_interested(self.picker.is_interesting(&peer.peer_has));
+            }
+            PeerMessage::HaveNone => {
+                peer.peer_has = Bitfield::new(piece_count);
+                peer.set_interested(false);
+            }
+            PeerMessage::RejectRequest(b) => {
+                if peer.pipeline.remove(&b) {
+                    self.picker.cancel_request(&b);
+                }
+            }
+            PeerMessage::AllowedFast(piece) => {
+                check_piece_index(piece, &self.torrent)?;
+                peer.allowed_fast.insert(piece);
+            }
+            PeerMessage::Port(_) => {
+                // TODO: dht
+            }
+            PeerMessage::Extended { id, payload } => {
+                peer.use_extended_message(id, &payload)?;
+            }
+            PeerMessage::HashRequest(r) => {
+                self.serve_hash_request(peer, r);
+            }
+            PeerMessage::Hashes { request, hashes } => {
+                self.on_block_hashes(request, hashes)?;
+            }
+            PeerMessage::HashReject(r) => {
+                println!("peer {:?} rejected hash request {:?}", peer, r);
+            }
+        };
 
-        let choke_rate_interval = time::Duration::from_secs(30);
+        Ok(())
+    }
 
-        struct Uploader {
-            peer_id: [u8; 20],
-            upload_rate: usize,
+    fn on_block(self: &mut Self, peer: &mut Peer, db: DownloadBlock) -> Result<(), io::Error> {
+        let block = Block::new(db.piece_index, db.byte_offset, db.data.len() as u32);
+        if !peer.pipeline.on_block(&block) {
+            return Ok(());
         }
+        peer.last_piece_at = Some(time::Instant::now());
+        self.store_block(&block, db.data, peer.addr());
 
-        let mut upload_rates = Vec::new();
-        for ap in &self.active_peers {
-            upload_rates.push(Uploader {
-                peer_id: ap.peer_id.unwrap(),
-                upload_rate: ap.calculate_upload_rate(choke_rate_interval),
-            });
+        if self.picker.in_endgame() {
+            self.cancel_received_blocks();
         }
-        upload_rates.sort_by(|a, b| b.upload_rate.cmp(&a.upload_rate));
 
-        let mut unchoke = Vec::new();
+        Ok(())
+    }
+
+    // Reading and hashing happen on the hash threads, the result is handled in on_hash_result
+    fn verify_piece(self: &mut Self, piece: u32) {
+        let data = self.disk.prepare_read(
+            piece as u64 * self.torrent.piece_len as u64,
+            self.torrent.get_piece_len(piece) as usize,
+        );
+        self.hasher.verify(
+            piece,
+            data,
+            self.torrent.piece_hash(piece),
+            self.block_hashes.get(&piece).cloned(),
+        );
+    }
 
-        if upload_rates.is_empty() {
-            return unchoke;
+    fn on_hash_result(self: &mut Self, r: HashResult) {
+        if r.recheck.is_some() {
+            self.on_recheck_result(r);
+            return;
         }
 
-        // Select 4 best interested uploaders
-        let mut idx = 0;
-        while unchoke.len() < 4 && idx < upload_rates.len() {
-            let ap = self
-                .active_peers
-                .iter()
-                .find(|a| a.peer_id == Some(upload_rates.get(idx).unwrap().peer_id))
-                .unwrap();
-            if ap.peer_interested {
-                unchoke.push(ap.peer_id.unwrap());
+        let piece = r.piece;
+        match r.outcome {
+            HashOutcome::Passed => {}
+            HashOutcome::Failed(bad) => {
+                let contributors = if !bad.is_empty() {
+                    // The good blocks stay in the cache, new data overwrites the bad ones
+                    self.picker.fail_blocks(piece, &bad)
+                } else {
+                    // The piece goes back to the picker and is downloaded again
+                    self.request_block_hashes(piece);
+                    self.disk.discard_piece(piece);
+                    self.picker.fail_piece(piece)
+                };
+                println!(
+                    "got false hash for piece {}, blocks came from {:?}",
+                    piece, contributors
+                );
+                for ip in self.trust.piece_failed(&contributors) {
+                    self.ban(ip);
+                }
+                return;
+            }
+            HashOutcome::Cancelled | HashOutcome::ReadFailed(_) => {
+                self.picker.fail_piece(piece);
+                self.disk.discard_piece(piece);
+                return;
             }
-            idx += 1;
         }
 
-        if self.last_optimic_unchoke.elapsed() >= choke_rate_interval {
-            for ap in self.active_peers.iter().rev() {
-                if ap.peer_choked && ap.peer_interested {
-                    unchoke.push(ap.peer_id.unwrap());
+        self.trust.piece_passed(&self.picker.contributors(piece));
+        self.piece_passed(piece);
+    }
+
+    fn on_recheck_result(self: &mut Self, r: HashResult) {
+        match r.outcome {
+            HashOutcome::Passed => {
+                if !self.picker.has(r.piece) {
+                    self.piece_passed(r.piece);
                 }
             }
+            HashOutcome::Failed(_) => self.picker.clear_have(r.piece),
+            HashOutcome::ReadFailed(e) => {
+                println!("failed to read piece {} for recheck {:?}", r.piece, e);
+                self.picker.clear_have(r.piece);
+            }
+            HashOutcome::Cancelled => {}
         }
 
-        unchoke
+        if let Some((checked, total)) = self.hasher.recheck_progress() {
+            let percent = checked * 100 / total;
+            if percent >= self.recheck_percent + 10 {
+                self.recheck_percent = percent;
+                println!("rechecked {}% of pieces", percent);
+            }
+        }
     }
 
-    fn consume_messages(self: &mut Self) {
-        let mut ts: Vec<JoinHandle<(Peer, bool)>> = Vec::new();
-        for _ in 0..self.active_peers.len() {
-            let mut peer = self.active_peers.swap_remove(0);
-            ts.push(thread::spawn(|| -> (Peer, bool) {
-                'peerLoop: loop {
-                    match peer.has_data() {
-                        Ok(b) => {
-                            if !b {
-                                break 'peerLoop;
-                            }
-                        }
-                        Err(e) => {
-                            println!("failed to check if peer has data {:?}", e);
-                            return (peer, false);
-                        }
-                    }
+    // Asks a v2 peer for the block hashes of a v2 piece, so that the next time
+    // the piece fails only its bad blocks are downloaded again
+    fn request_block_hashes(self: &mut Self, piece: u32) {
+        let width = match self.torrent.piece_hash(piece) {
+            PieceHash::V2 { width, .. } if (2..=MAX_HASH_REQUEST_LENGTH).contains(&width) => width,
+            _ => return,
+        };
+        if self.block_hashes.contains_key(&piece) {
+            return;
+        }
 
-                    if let Err(e) = handle_peer(&mut peer) {
-                        println!("failed to handle peer {:?}", e);
-                        return (peer, false);
-                    }
-                }
+        let (f, idx) = self.torrent.v2_piece_file(piece).unwrap();
+        let r = HashRequest {
+            pieces_root: f.pieces_root.unwrap(),
+            base_layer: 0,
+            index: idx * width,
+            length: width,
+            proof_layers: 0,
+        };
+        if let Some(p) = self
+            .peers
+            .values_mut()
+            .find(|p| p.state == ConnectionState::Active && p.supports_v2 && p.peer_has.has(piece))
+        {
+            p.send_message(PeerMessage::HashRequest(r));
+        }
+    }
+
+    // Block hashes asked for in request_block_hashes, they have to add up to the piece's hash
+    fn on_block_hashes(
+        self: &mut Self,
+        r: HashRequest,
+        hashes: Vec<Hash>,
+    ) -> Result<(), io::Error> {
+        if r.base_layer != 0 {
+            // We never ask for anything else
+            return Ok(());
+        }
+        let piece = match self.torrent.v2_file(&r.pieces_root) {
+            Some(f) => f.first_piece + r.index / r.length,
+            None => return Err(easy_err("got hashes of an unknown file")),
+        };
+        if self
+            .torrent
+            .v2_piece_file(piece)
+            .is_none_or(|(f, _)| f.pieces_root != Some(r.pieces_root))
+        {
+            return Err(easy_err("got hashes outside of the file"));
+        }
+
+        let (root, width) = match self.torrent.piece_hash(piece) {
+            PieceHash::V2 { root, width, .. } if width == r.length => (root, width),
+            _ => return Ok(()),
+        };
+        let blocks = &hashes[..width as usize];
+        if merkle::root(blocks, width as usize, merkle::ZERO_HASH) != root {
+            return Err(easy_err("got hashes that don't match the piece"));
+        }
+        self.block_hashes.insert(piece, blocks.to_vec());
+        Ok(())
+    }
+
+    fn serve_hash_request(self: &mut Self, peer: &mut Peer, r: HashRequest) {
+        match self.hashes_for(&r) {
+            Some(hashes) => peer.send_message(PeerMessage::Hashes {
+                request: r,
+                hashes: hashes,
+            }),
+            None => peer.send_message(PeerMessage::HashReject(r)),
+        }
+    }
+
+    // Piece layer hashes come from the torrent, block hashes are computed from pieces we have.
+    // The requested hashes are followed by their uncles. Block hashes of a piece that isn't in
+    // memory are rejected while the piece is read, the peer can ask again.
+    fn hashes_for(self: &mut Self, r: &HashRequest) -> Option<Vec<Hash>> {
+        let f = self.torrent.v2_file(&r.pieces_root)?;
+        let height = self.torrent.piece_layer_height();
+        let pad = merkle::pad_hash(height);
+        let (index, length) = (r.index as usize, r.length as usize);
+
+        if r.base_layer == height && !f.piece_layer.is_empty() {
+            let width = f.piece_layer.len().next_power_of_two();
+            if index + length > width {
+                return None;
+            }
+            let mut hashes: Vec<Hash> = (index..index + length)
+                .map(|i| f.piece_layer.get(i).copied().unwrap_or(pad))
+                .collect();
+            hashes.extend(merkle::proof(
+                &f.piece_layer,
+                width,
+                pad,
+                index,
+                length,
+                r.proof_layers,
+            ));
+            return Some(hashes);
+        }
+        if r.base_layer != 0 {
+            return None;
+        }
 
-                return (peer, true);
-            }));
+        // Block hashes of a single piece
+        let piece = f.first_piece + r.index / r.length;
+        let (pf, idx) = self.torrent.v2_piece_file(piece)?;
+        if pf.pieces_root != f.pieces_root || !self.picker.has(piece) {
+            return None;
         }
-        for _ in 0..ts.len() {
-            let t = ts.remove(0);
-            match t.join() {
-                Ok(p) => {
-                    if p.1 {
-                        self.active_peers.push(p.0);
+        let len = match self.torrent.piece_hash(piece) {
+            PieceHash::V2 { len, width, .. } if width == r.length => len,
+            _ => return None,
+        };
+        let mut hashes = merkle::block_hashes(&self.cached_piece(piece)?[..len as usize]);
+        hashes.resize(length, merkle::ZERO_HASH);
+        let f = self.torrent.v2_file(&r.pieces_root)?;
+        if !f.piece_layer.is_empty() {
+            let width = f.piece_layer.len().next_power_of_two();
+            hashes.extend(merkle::proof(
+                &f.piece_layer,
+                width,
+                pad,
+                idx as usize,
+                1,
+                r.proof_layers,
+            ));
+        }
+        Some(hashes)
+    }
+
+    // Lost pieces are downloaded again. If it's unknown what was lost, everything is rechecked.
+    fn on_disk_failure(self: &mut Self, f: DiskFailure) {
+        match f {
+            DiskFailure::Write(pieces, e) => {
+                println!("failed to write pieces {:?} {:?}", pieces, e);
+                for piece in pieces {
+                    if self.picker.has(piece) {
+                        self.picker.clear_have(piece);
                     } else {
-                        self.backlog_peers.push(p.0);
+                        self.picker.fail_piece(piece);
+                        self.disk.discard_piece(piece);
                     }
                 }
-                Err(e) => {
-                    println!("failed to join thread {:?}", e);
-                }
+            }
+            DiskFailure::Sync(e) => {
+                println!("failed to sync {:?}", e);
+                self.recheck();
             }
         }
     }
 
-    fn download(self: &mut Self) {
-        let pieces_left = self.get_pieces_left();
+    fn piece_passed(self: &mut Self, piece: u32) {
+        self.block_hashes.remove(&piece);
+        self.picker.mark_have(piece);
+        self.disk.piece_verified(piece);
+        self.new_pieces.push(piece);
 
-        let mut downloadable_peers: Vec<Peer> = self
-            .active_peers
-            .extract_if(.., |p| -> bool {
-                if !p.can_download() {
-                    return false;
-                }
+        if self.picker.is_complete() {
+            println!("torrent finished downloading");
+            self.disk.flush_all();
+            self.disk.sync();
+        }
+    }
 
-                for b in &pieces_left {
-                    if p.has_piece(b.clone()) {
-                        return true;
-                    }
-                }
+    fn store_block(self: &mut Self, block: &Block, data: Vec<u8>, from: SocketAddr) {
+        if !self.picker.claim_block(block) {
+            // Another peer was faster in endgame
+            return;
+        }
 
-                false
-            })
-            .collect();
+        let offset =
+            block.piece_index as u64 * self.torrent.piece_len as u64 + block.byte_offset as u64;
+        self.disk.write_block(block.piece_index, offset, data);
 
-        let ts = spawn_peer_threads(&mut self.active_peers, |mut p: Peer| -> Option<Peer> {
-            if let Err(e) = p.set_interested(false) {
-                println!("failed to set uninterested for peer {:?}", e);
-                return None;
+        if self.picker.block_written(block, from) {
+            println!("downloaded piece {}", block.piece_index);
+            self.verify_piece(block.piece_index);
+        }
+    }
+
+    fn download_from_web_seeds(self: &mut Self, global: &mut Throttle) {
+        for i in 0..self.web_seeds.len() {
+            if !self.web_seeds[i].is_ready() || self.disk.is_full() {
+                continue;
             }
-            Some(p)
-        });
-        for t in ts {
-            match t.join() {
-                Ok(p) => {
-                    if let Some(peer) = p {
-                        self.active_peers.push(peer);
-                    }
-                }
-                Err(e) => {
-                    println!("failed to join thread {:?}", e);
-                }
+            if !rate_limit::has_tokens(&mut [&mut global.download, &mut self.throttle.download]) {
+                break;
             }
+            let piece = match self.picker.request_piece() {
+                Some(p) => p,
+                None => break,
+            };
+            self.web_seeds[i].download(&self.torrent, piece);
         }
+    }
 
-        let ts = spawn_peer_threads(&mut downloadable_peers, |mut p: Peer| -> Option<Peer> {
-            if let Err(e) = p.set_interested(true) {
-                println!("failed to set interested for downloadble peer {:?}", e);
-                return None;
-            }
-            Some(p)
-        });
-        for t in ts {
-            match t.join() {
-                Ok(p) => {
-                    if let Some(peer) = p {
-                        downloadable_peers.push(peer);
-                    }
-                }
+    fn collect_web_seed_pieces(self: &mut Self, global: &mut Throttle) {
+        for i in 0..self.web_seeds.len() {
+            let r = match self.web_seeds[i].result() {
+                Some(r) => r,
+                None => continue,
+            };
+            let blocks = self.picker.piece_blocks(r.piece);
+            let (addr, data) = match r.result {
+                Ok(d) => d,
                 Err(e) => {
-                    println!("failed to join thread {:?}", e);
+                    println!(
+                        "web seed {} failed to send piece {} {:?}",
+                        self.web_seeds[i].url, r.piece, e
+                    );
+                    // Peers can pick the blocks up
+                    for b in &blocks {
+                        self.picker.cancel_request(b);
+                    }
+                    continue;
                 }
+            };
+
+            rate_limit::consume(
+                &mut [&mut global.download, &mut self.throttle.download],
+                data.len(),
+            );
+            for b in blocks {
+                let start = b.byte_offset as usize;
+                let end = start + b.requested_length as usize;
+                self.store_block(&b, data[start..end].to_vec(), addr);
             }
         }
+    }
 
-        let mut assigned_pieces = HashSet::new();
-
-        for mut peer in downloadable_peers {
-            let mut peer_piece = None;
-            for piece in &pieces_left {
-                if !assigned_pieces.contains(piece) {
-                    peer_piece = Some(*piece);
-                    break;
-                }
-            }
-            if peer_piece.is_none() {
+    // In endgame the same block is requested from several peers,
+    // once one of them delivers it the others are told to not send it
+    fn cancel_received_blocks(self: &mut Self) {
+        for p in self.peers.values_mut() {
+            if p.pipeline.is_empty() {
                 continue;
             }
-            assigned_pieces.insert(peer_piece.unwrap());
-
-            let piece_len = self.torrent.get_piece_len(peer_piece.unwrap());
-            self.downloading_threads.push(DownloadThread {
-                piece: peer_piece.unwrap(),
-                thread: thread::spawn(move || -> (Option<Vec<u8>>, Peer, bool) {
-                    match download_piece_from_peer(&mut peer, peer_piece.unwrap(), piece_len) {
-                        Ok(p) => {
-                            return (Some(p), peer, true);
-                        }
-                        Err(e) => {
-                            println!("failed to download piece from peer {:?}", e);
-                            return (None, peer, false);
-                        }
-                    }
-                }),
-            });
+            let received: Vec<Block> = p
+                .pipeline
+                .outstanding()
+                .into_iter()
+                .filter(|b| self.picker.is_received(b))
+                .collect();
+            for b in received {
+                p.send_message(PeerMessage::Cancel(b));
+                p.pipeline.remove(&b);
+            }
         }
+    }
 
-        self.pieces_in_progress.extend(assigned_pieces);
+    fn run_choke_algo(self: &mut Self) {
+        self.last_choke_update = time::Instant::now();
 
-        let done_threads: Vec<DownloadThread> = self
-            .downloading_threads
-            .extract_if(.., |dt| dt.thread.is_finished())
+        let candidates: Vec<ChokeCandidate> = self
+            .peers
+            .iter_mut()
+            .filter(|(_, p)| p.state == ConnectionState::Active)
+            .map(|(t, p)| {
+                p.prune_data_movements(CHOKE_RATE_INTERVAL);
+                ChokeCandidate {
+                    token: *t,
+                    interested: p.peer_interested,
+                    snubbed: p.is_snubbed(),
+                    download_rate: p.download_rate(CHOKE_RATE_INTERVAL),
+                    upload_rate: p.upload_rate(CHOKE_RATE_INTERVAL),
+                    connected_at: p.connection_started_at.unwrap_or(self.last_choke_update),
+                    last_unchoked_at: p.last_unchoked_at,
+                }
+            })
             .collect();
 
-        let mut pieces_downloaded = Vec::new();
+        let unchoke = self.choker.decide(&candidates, self.picker.is_complete());
+        for c in candidates {
+            let p = self.peers.get_mut(&c.token).unwrap();
+            p.set_choked(!unchoke.contains(&c.token));
+        }
+    }
 
-        for dt in done_threads {
-            match dt.thread.join() {
-                Ok(p) => {
-                    if !p.2 {
-                        self.backlog_peers.push(p.1);
-                        continue;
-                    }
-                    let piece_data = p.0.as_ref().unwrap();
-                    if self.torrent.piece_hashes.get(dt.piece as usize).unwrap()
-                        != sha1_smol::Sha1::from(piece_data).digest().bytes().as_ref()
-                    {
-                        println!("got false hash for piece {}", dt.piece);
-                        continue;
-                    }
-                    let mut f = fs::OpenOptions::new()
-                        .write(true)
-                        .read(false)
-                        .truncate(false)
-                        .open(&self.download_file_name)
-                        .unwrap();
-                    f.write_all_at(
-                        p.0.as_ref().unwrap(),
-                        (dt.piece * self.torrent.piece_len) as u64,
-                    )
-                    .unwrap();
-                    f.flush().unwrap();
-                    self.have_pieces.insert(dt.piece);
-                    self.active_peers.push(p.1);
-                    pieces_downloaded.push(dt.piece);
-                }
-                Err(e) => {
-                    println!("failed to join thread {:?}", e);
-                }
+    fn download(self: &mut Self, global: &mut Throttle) {
+        let tokens: Vec<Token> = self.peers.keys().copied().collect();
+        for token in tokens {
+            let mut peer = self.peers.remove(&token).unwrap();
+            if peer.state == ConnectionState::Active {
+                self.request_blocks(&mut peer, global);
             }
-            self.pieces_in_progress.remove(&dt.piece);
+            self.peers.insert(token, peer);
         }
+    }
 
-        if self.get_pieces_left().len() == 0 {
-            println!("torrent finished downloading");
+    // Fills the peer's pipeline. In endgame blocks already requested from
+    // other peers are requested again.
+    // Download limits are applied here, a block is paid for once it is requested.
+    fn request_blocks(self: &mut Self, peer: &mut Peer, global: &mut Throttle) {
+        if !peer.can_download() || !peer.am_interested {
+            return;
+        }
+        if self.disk.is_full() {
+            // Wait for the disk to catch up
+            return;
         }
 
-        let pieces_downloaded_shared = Arc::new(pieces_downloaded);
-        let mut ts = Vec::new();
-        for ap in self.active_peers.drain(..) {
-            let pieces_cloned = pieces_downloaded_shared.clone();
-            ts.push(thread::spawn(move || -> Option<Peer> {
-                for piece in pieces_cloned.as_ref() {
-                    if let Err(e) =
-                        ap.send_message(MessageType::Have, Some(&piece.to_be_bytes().to_vec()))
-                    {
-                        println!("failed to set have for peer {:?}", e);
-                        return None;
+        // While choked only pieces of the allowed fast set can be requested
+        let fast_only: Option<Bitfield> = peer.am_choked.then(|| {
+            let allowed = Bitfield::from_pieces(peer.peer_has.len(), peer.allowed_fast.clone());
+            allowed.intersection(&peer.peer_has)
+        });
+        peer.suggested.retain(|p| !self.picker.has(*p));
+
+        let mut free_slots = peer.pipeline.free_slots(peer.reqq);
+        while free_slots > 0 {
+            if !rate_limit::has_tokens(&mut [
+                &mut global.download,
+                &mut self.throttle.download,
+                &mut peer.throttle.download,
+            ]) {
+                break;
+            }
+
+            let available = fast_only.as_ref().unwrap_or(&peer.peer_has);
+            let block = match self.picker.request_block(available, &peer.suggested, true) {
+                Some(b) => b,
+                None => {
+                    if !self.picker.in_endgame() {
+                        break;
                     }
-                }
-                Some(ap)
-            }));
-        }
-        for t in ts {
-            match t.join() {
-                Ok(p) => {
-                    if let Some(peer) = p {
-                        self.active_peers.push(peer);
+                    match self
+                        .picker
+                        .request_endgame_block(available, &peer.pipeline.outstanding())
+                    {
+                        Some(b) => b,
+                        None => break,
                     }
                 }
-                Err(e) => {
-                    println!("failed to join thread {:?}", e);
-                }
+            };
+
+            if peer.pipeline.is_empty() {
+                // Start the snub timer
+                peer.last_piece_at = Some(time::Instant::now());
             }
+            peer.pipeline.on_request(block);
+            peer.send_message(PeerMessage::Request(block));
+            rate_limit::consume(
+                &mut [
+                    &mut global.download,
+                    &mut self.throttle.download,
+                    &mut peer.throttle.download,
+                ],
+                limited_len(block.requested_length, self.count_overhead),
+            );
+            free_slots -= 1;
         }
     }
 
-    fn upload(self: &mut Self) {
-        let uploadable_peers: Vec<Peer> = self
-            .active_peers
-            .extract_if(.., |ap| {
-                !ap.peer_choked
-                    && ap.peer_interested
-                    && ap
-                        .request_queue
-                        .iter()
-                        .any(|b| self.have_pieces.contains(&b.piece_index))
-            })
-            .collect();
-
-        for mut up in uploadable_peers {
-            let download_file_name = self.download_file_name.clone();
-            let piece_len = self.torrent.piece_len.clone();
-            self.uploading_threads.push(UploadThread {
-                thread: thread::spawn(move || -> (Peer, bool) {
-                    if up.request_queue.len() == 0 {
-                        return (up, true);
-                    }
-                    for rq in &up.request_queue {
-                        let f = fs::OpenOptions::new()
-                            .write(false)
-                            .read(true)
-                            .truncate(false)
-                            .open(&download_file_name)
-                            .unwrap();
-                        let mut data = vec![0 as u8; rq.requested_length as usize];
-                        f.read_exact_at(&mut data, (rq.piece_index * piece_len) as u64)
-                            .unwrap();
-                        let mut payload = Vec::new();
-                        payload.extend(rq.piece_index.to_be_bytes());
-                        payload.extend(rq.byte_offset.to_be_bytes());
-                        payload.extend(data);
-                        up.send_message(MessageType::Piece, Some(&payload)).unwrap();
-                        up.data_movements.push(DataMovement {
-                            data_len: rq.requested_length as usize,
-                            direction: DataDirection::UploadedToPeer,
-                            when: time::Instant::now(),
-                        });
-                    }
-
-                    return (up, true);
-                }),
-            });
+    // Sends HAVE for the pieces we got and drops interest in peers that have nothing left for us
+    fn announce_new_pieces(self: &mut Self) {
+        if self.new_pieces.is_empty() {
+            return;
         }
 
-        let done_threads: Vec<UploadThread> = self
-            .uploading_threads
-            .extract_if(.., |dt| dt.thread.is_finished())
-            .collect();
-
-        for dt in done_threads {
-            match dt.thread.join() {
-                Ok(p) => {
-                    if p.1 {
-                        self.active_peers.push(p.0);
-                    } else {
-                        self.backlog_peers.push(p.0);
-                    }
-                }
-                Err(e) => {
-                    println!("failed to join thread {:?}", e);
-                }
+        for p in self.peers.values_mut() {
+            if p.state != ConnectionState::Active {
+                continue;
             }
+            for piece in &self.new_pieces {
+                p.send_message(PeerMessage::Have(*piece));
+            }
+            p.set_interested(self.picker.is_interesting(&p.peer_has));
         }
+        self.new_pieces.clear();
     }
 
-    fn check_keep_alive(self: &mut Self) {
-        self.active_peers.retain(|ap| -> bool {
-            ap.last_message_at.is_none()
-                || ap.last_message_at.unwrap().elapsed() < KEEP_ALIVE_MAX_DURATION
-        });
-        // TODO: move these to backlog, also send keep alive messages
+    fn upload(self: &mut Self, global: &mut Throttle) {
+        let tokens: Vec<Token> = self.peers.keys().copied().collect();
+        for token in tokens {
+            let mut up = self.peers.remove(&token).unwrap();
+            self.upload_to_peer(&mut up, global);
+            self.peers.insert(token, up);
+        }
     }
 
-    fn attempt_backlog_connections(self: &mut Self) {
-        if self.active_peers.len() + self.downloading_threads.len() >= MAX_CONNECTIONS {
+    fn upload_to_peer(self: &mut Self, up: &mut Peer, global: &mut Throttle) {
+        // Requests of choked peers were dropped, except for allowed fast pieces
+        if !up.peer_interested {
             return;
         }
-        if self.backlog_peers.len() == 0 {
-            return;
-        }
-        let backlog: Vec<Peer> = self
-            .backlog_peers
-            .drain(
-                0..min(
-                    self.backlog_peers.len(),
-                    MAX_CONNECTIONS - (self.active_peers.len() + self.downloading_threads.len()),
-                ),
-            )
-            .collect();
-        println!("connecting to backlog peers {}", backlog.len());
-        self.connect_peers(backlog);
-        println!("done attempting connections");
-    }
 
-    fn count_pieces_left(&self) -> u32 {
-        self.torrent.get_total_piece_count()
-            - self.have_pieces.len() as u32
-            - self.pieces_in_progress.len() as u32
-    }
+        while up.pending_write_len() < MAX_PENDING_UPLOAD_BYTES && !up.request_queue.is_empty() {
+            if !rate_limit::has_tokens(&mut [
+                &mut global.upload,
+                &mut self.throttle.upload,
+                &mut up.throttle.upload,
+            ]) {
+                break;
+            }
 
-    fn get_pieces_left(&self) -> Vec<u32> {
-        let mut pl = HashSet::new();
+            // The request stays queued while the piece is read from disk
+            let data = match self.read_block(&up.request_queue[0]) {
+                Some(d) => d,
+                None => break,
+            };
+            let rq = up.request_queue.remove(0);
 
-        let piece_count = self.torrent.get_total_piece_count();
-        for i in 0..piece_count {
-            pl.insert(i);
+            up.send_message(PeerMessage::Piece {
+                piece_index: rq.piece_index,
+                byte_offset: rq.byte_offset,
+                data: data,
+            });
+            rate_limit::consume(
+                &mut [
+                    &mut global.upload,
+                    &mut self.throttle.upload,
+                    &mut up.throttle.upload,
+                ],
+                limited_len(rq.requested_length, self.count_overhead),
+            );
+            up.data_movements.push(DataMovement {
+                data_len: rq.requested_length as usize,
+                direction: DataDirection::UploadedToPeer,
+                when: time::Instant::now(),
+            });
         }
+    }
 
-        pl.retain(|i| !self.have_pieces.contains(i));
-        pl.retain(|i| !self.pieces_in_progress.contains(i));
-
-        // Wasteful way of randomizing peer order
-        pl.drain().collect()
+    // A validated block of a piece we have, whole pieces are cached. Pieces that
+    // aren't in memory are read by the disk threads, None until they are done.
+    fn read_block(self: &mut Self, b: &Block) -> Option<Vec<u8>> {
+        let start = b.byte_offset as usize;
+        let end = start + b.requested_length as usize;
+        let piece = self.cached_piece(b.piece_index)?;
+        Some(piece[start..end].to_vec())
     }
 
-    fn count_active_connections(&self) -> usize {
-        self.active_peers.len() + self.uploading_threads.len() + self.downloading_threads.len()
+    // Pieces that aren't fully in the write cache are read on the disk threads,
+    // None until they arrive in the read cache
+    fn cached_piece(self: &mut Self, piece: u32) -> Option<&Vec<u8>> {
+        if self.read_cache.get(piece).is_none() {
+            let offset = piece as u64 * self.torrent.piece_len as u64;
+            let len = self.torrent.get_piece_len(piece) as usize;
+            let read = self.disk.prepare_read(offset, len);
+            if !read.is_cached() {
+                self.disk.read_piece(piece, offset, len);
+                return None;
+            }
+            // Nothing to fail without the file
+            self.read_cache.insert(piece, read.read().unwrap());
+        }
+        self.read_cache.get(piece)
     }
-}
 
-fn download_piece_from_peer(
-    peer: &mut Peer,
-    piece: u32,
-    piece_len: u32,
-) -> Result<Vec<u8>, io::Error> {
-    let mut piece_data: Vec<u8> = Vec::new();
-    piece_data.reserve_exact(piece_len as usize);
+    // Peers waiting for the piece are dropped, like when any other read fails
+    fn on_read_failure(self: &mut Self, piece: u32, e: io::Error) {
+        println!("failed to read piece {} for upload {:?}", piece, e);
+        let tokens: Vec<Token> = self
+            .peers
+            .iter()
+            .filter(|(_, p)| p.request_queue.iter().any(|rq| rq.piece_index == piece))
+            .map(|(t, _)| *t)
+            .collect();
+        for token in tokens {
+            let peer = self.peers.remove(&token).unwrap();
+            self.close_peer(peer, true);
+        }
+    }
 
-    let mut block_start = 0;
-    let mut requested_blocks = HashSet::new();
+    fn replace_candidates(&self) -> Vec<ReplaceCandidate> {
+        let seeding = self.picker.is_complete();
+        let now = time::Instant::now();
+        self.peers
+            .iter()
+            .filter(|(_, p)| p.state == ConnectionState::Active)
+            .map(|(t, p)| ReplaceCandidate {
+                token: *t,
+                useful: p.am_interested || p.peer_interested,
+                rate: if seeding {
+                    p.upload_rate(CHOKE_RATE_INTERVAL)
+                } else {
+                    p.download_rate(CHOKE_RATE_INTERVAL)
+                },
+                connected_at: p.connection_started_at.unwrap_or(now),
+            })
+            .collect()
+    }
 
-    println!(
-        "requesting piece {} from peer {}",
-        piece,
-        peer.get_peer_id().unwrap().unwrap()
-    );
+    // Closes the worst peer that had its chance, returns false if there is none
+    fn replace_worst_peer(self: &mut Self) -> bool {
+        let worst =
+            connection_limits::replaceable_peer(&self.replace_candidates(), time::Instant::now());
+        match worst {
+            Some(token) => {
+                println!("replacing peer {:?}", self.peers[&token]);
+                self.close_gracefully(token);
+                true
+            }
+            None => false,
+        }
+    }
 
-    while block_start < piece_len {
-        println!("piece {} len {} start {}", piece, piece_len, block_start);
-        let mut block_len = DEFAULT_BLOCK_LENGTH;
-        if block_start + DEFAULT_BLOCK_LENGTH > piece_len {
-            block_len = piece_len - block_start;
+    // Sheds the worst peers after the limit was lowered
+    fn enforce_connection_limit(self: &mut Self) {
+        let (mut open, _) = self.connection_counts();
+        while open > self.limits.max_connections {
+            let worst = connection_limits::worst_peer(
+                &self.replace_candidates(),
+                None,
+                time::Instant::now(),
+            );
+            match worst {
+                Some(token) => self.close_gracefully(token),
+                None => return,
+            }
+            open -= 1;
         }
+    }
 
-        let b = Block::new(piece, block_start, block_len);
-        requested_blocks.insert(b);
+    // Gives back the requested blocks, the messages already queued for the peer are still sent
+    fn close_gracefully(self: &mut Self, token: Token) {
+        let peer = self.peers.get_mut(&token).unwrap();
+        for b in peer.pipeline.clear() {
+            self.picker.cancel_request(&b);
+        }
+        peer.close();
+    }
 
-        let mut payload = Vec::new();
-        payload.extend(b.to_bytes());
-        peer.send_message(MessageType::Request, Some(&payload))?;
+    fn check_timeouts(self: &mut Self) {
+        let timed_out: Vec<Token> = self
+            .peers
+            .iter()
+            .filter(|(_, p)| match p.state {
+                ConnectionState::Connecting => p.state_age() >= self.connect_timeout,
+                ConnectionState::Encrypting | ConnectionState::Handshaking => {
+                    p.state_age() >= HANDSHAKE_TIMEOUT
+                }
+                ConnectionState::Active => p
+                    .last_message_at
+                    .is_some_and(|t| t.elapsed() >= KEEP_ALIVE_MAX_DURATION),
+                // Done once everything queued is written
+                ConnectionState::Closing => {
+                    p.pending_write_len() == 0 || p.state_age() >= CLOSE_TIMEOUT
+                }
+                ConnectionState::Disconnected => true,
+            })
+            .map(|(t, _)| *t)
+            .collect();
 
-        block_start += DEFAULT_BLOCK_LENGTH;
+        for token in timed_out {
+            let peer = self.peers.remove(&token).unwrap();
+            match peer.state {
+                ConnectionState::Connecting
+                | ConnectionState::Encrypting
+                | ConnectionState::Handshaking => {
+                    println!("peer {:?} timed out while {:?}", peer, peer.state);
+                    self.connection_failed(peer)
+                }
+                ConnectionState::Active => {
+                    println!("peer {:?} was inactive for too long", peer);
+                    self.close_peer(peer, true)
+                }
+                ConnectionState::Closing => self.close_peer(peer, true),
+                ConnectionState::Disconnected => self.close_peer(peer, false),
+            }
+        }
     }
 
-    println!(
-        "requested piece {} from peer {}, waiting for download",
-        piece,
-        peer.get_peer_id().unwrap().unwrap()
-    );
-
-    while requested_blocks.len() > 0 {
-        handle_peer(peer)?;
-        peer.downloaded_blocks.iter().for_each(|b| {
-            if requested_blocks.remove(&Block {
-                piece_index: b.piece_index,
-                byte_offset: b.byte_offset,
-                requested_length: b.data.len() as u32,
-            }) {
-                println!("got block piece {} offset {}", b.piece_index, b.byte_offset);
+    // Requests a peer didn't answer for too long are requested from others again
+    fn expire_requests(self: &mut Self) {
+        for p in self.peers.values_mut() {
+            for b in p.pipeline.expire(REQUEST_TIMEOUT) {
+                println!("request {:?} to peer {:?} timed out", b, p);
+                self.picker.cancel_request(&b);
+                p.send_message(PeerMessage::Cancel(b));
             }
-        });
+        }
     }
 
-    println!(
-        "downloaded piece {} from peer {}",
-        piece,
-        peer.get_peer_id().unwrap().unwrap()
-    );
+    fn flush_peers(self: &mut Self) {
+        let failed: Vec<Token> = self
+            .peers
+            .iter_mut()
+            .filter(|(_, p)| p.pending_write_len() > 0)
+            .filter_map(|(t, p)| match p.flush() {
+                Ok(_) => None,
+                Err(e) => {
+                    println!("failed to write to peer {:?} {:?}", p, e);
+                    Some(*t)
+                }
+            })
+            .collect();
 
-    let mut piece_blocks: Vec<DownloadBlock> = peer
-        .downloaded_blocks
-        .extract_if(.., |db| db.piece_index == piece)
-        .collect();
-    piece_blocks.sort_by(|db1, db2| db1.byte_offset.cmp(&db2.byte_offset));
-    piece_blocks.iter().for_each(|pb| {
-        piece_data.extend(&pb.data);
-    });
+        for token in failed {
+            let peer = self.peers.remove(&token).unwrap();
+            self.close_peer(peer, true);
+        }
+    }
 
-    Ok(piece_data)
-}
+    // Disconnects the peer and gives back the blocks we won't get from it
+    fn close_peer(self: &mut Self, mut peer: Peer, to_backlog: bool) {
+        for b in peer.pipeline.clear() {
+            self.picker.cancel_request(&b);
+        }
+        if let Err(e) = peer.disconnect() {
+            println!("failed to disconnect peer {:?}", e);
+        }
+        if to_backlog && !self.trust.is_banned(&peer.addr().ip()) {
+            self.candidates.closed(peer);
+        } else {
+            self.candidates.remove(&peer.addr());
+        }
+    }
 
-fn handle_peer(peer: &mut Peer) -> Result<(), io::Error> {
-    let msg = match peer.receive_message() {
-        Ok(m) => m,
-        Err(e) => {
-            println!("failed to receive message {:?}", e);
-            return Err(e);
-        }
-    };
-    println!("got message type {:?}", msg.message_type);
-    match msg.message_type {
-        MessageType::KeepAlive => {}
-        MessageType::Choke => {
-            peer.am_choked = true;
-        }
-        MessageType::Unchoke => {
-            peer.am_choked = false;
-        }
-        MessageType::Interested => {
-            peer.peer_interested = true;
-        }
-        MessageType::NotInterested => {
-            peer.peer_interested = false;
-        }
-        MessageType::Have => {
-            let have_idx = u32::from_be_bytes(msg.payload.try_into().unwrap());
-            peer.peer_has.insert(have_idx);
-        }
-        MessageType::Bitfield => {
-            peer.use_bitfield(&msg.payload);
-            println!("peer gotbitfield {}", peer.peer_has.len());
-        }
-        MessageType::Request => {
-            peer.request_queue.push(Block::parse(&msg.payload).unwrap());
-        }
-        MessageType::Piece => {
-            let db = DownloadBlock::parse(&msg.payload).unwrap();
-            peer.data_movements.push(DataMovement {
-                data_len: db.data.len(),
-                direction: DataDirection::DownloadedFromPeer,
-                when: time::Instant::now(),
-            });
-            peer.downloaded_blocks.push(db);
+    fn connection_failed(self: &mut Self, mut peer: Peer) {
+        if peer.state == ConnectionState::Encrypting && self.encryption == EncryptionPolicy::Enabled
+        {
+            // The peer might not support MSE at all
+            peer.plaintext_only = true;
         }
-        MessageType::Cancel => {
-            let b = Block::parse(&msg.payload).unwrap();
-            if let Some(idx) = peer.request_queue.iter().position(|p| p.eq(&b)) {
-                peer.request_queue.swap_remove(idx);
-            }
+        if peer.is_utp() && peer.state != ConnectionState::Active {
+            // The peer might not speak uTP
+            peer.tcp_only = true;
         }
-        MessageType::Port => {
-            // TODO: dht
+        if let Err(e) = peer.disconnect() {
+            println!(
+                "failed to disconnect client bc of failed connection {:?}",
+                e
+            );
+        }
+        self.candidates.failed(peer);
+    }
+
+    fn ban(self: &mut Self, ip: IpAddr) {
+        if let Some(r) = self.trust.get(&ip) {
+            println!(
+                "banning {} after {} failed pieces, trust {}",
+                ip, r.hash_failures, r.trust
+            );
         }
-    };
-    peer.last_message_at = Some(time::Instant::now());
 
-    if let Some(t) = peer.last_message_at {
-        if t.elapsed() >= KEEP_ALIVE_MAX_DURATION {
-            return Err(easy_err("peer exceeded max keep alive duration"));
+        let banned: Vec<Token> = self
+            .peers
+            .iter()
+            .filter(|(_, p)| p.addr().ip() == ip)
+            .map(|(t, _)| *t)
+            .collect();
+        for token in banned {
+            let peer = self.peers.remove(&token).unwrap();
+            self.close_peer(peer, false);
         }
+        self.candidates.remove_ip(ip);
     }
 
-    Ok(())
+    fn count_pieces_left(&self) -> u32 {
+        self.picker.count_pieces_left()
+    }
 }
 
-fn spawn_peer_threads<F, T>(peers: &mut Vec<Peer>, f: F) -> Vec<JoinHandle<T>>
-where
-    F: Fn(Peer) -> T + Copy,
-    F: Send + 'static,
-    T: Send + 'static,
-{
-    let mut ts = Vec::new();
+// Connected, or connecting, but not done with the handshakes yet
+fn is_half_open(state: ConnectionState) -> bool {
+    matches!(
+        state,
+        ConnectionState::Connecting | ConnectionState::Encrypting | ConnectionState::Handshaking
+    )
+}
 
-    peers.drain(..).for_each(|p| {
-        ts.push(thread::spawn(move || -> T { f(p) }));
-    });
+// Requests outside of the torrent are protocol violations
+fn check_request(torrent: &Torrent, b: &Block) -> Result<(), io::Error> {
+    if b.requested_length == 0 || b.requested_length > MAX_REQUEST_LENGTH {
+        return Err(easy_err("invalid request length"));
+    }
+    if b.piece_index >= torrent.get_total_piece_count() {
+        return Err(easy_err("request for unknown piece"));
+    }
+    if b.byte_offset as u64 + b.requested_length as u64
+        > torrent.get_piece_len(b.piece_index) as u64
+    {
+        return Err(easy_err("request exceeds piece"));
+    }
+    Ok(())
+}
 
-    ts
+// Bytes a block counts against the rate limits
+fn limited_len(block_len: u32, count_overhead: bool) -> usize {
+    if count_overhead {
+        return block_len as usize + PIECE_MESSAGE_OVERHEAD;
+    }
+    block_len as usize
 }
 
-pub fn create_bitfield(piece_count: u32, have: &HashSet<u32>) -> Vec<u8> {
-    let mut data = Vec::new();
+fn check_piece_index(piece: u32, torrent: &Torrent) -> Result<(), io::Error> {
+    if piece >= torrent.get_total_piece_count() {
+        return Err(easy_err("piece index out of range"));
+    }
+    Ok(())
+}
 
-    let mut byte: u8 = 0;
-    for i in 0..piece_count {
-        if i > 0 && i % 8 == 0 {
-            data.push(byte);
-            byte = 0;
-        }
-        if have.contains(&i) {
-            byte |= 1 << (7 - (i % 8));
+// https://www.bittorrent.org/beps/bep_0006.html#allowed-fast
+// Derived from the peer's /24 network, so reconnecting doesn't get it a new set
+pub fn allowed_fast_set(ip: Ipv4Addr, info_hash: &[u8; 20], piece_count: u32, k: u32) -> Vec<u32> {
+    let k = k.min(piece_count);
+    let mut set = Vec::new();
+    let mut x = [ip.octets().as_slice(), info_hash].concat();
+    x[3] = 0;
+
+    while (set.len() as u32) < k {
+        x = sha1_smol::Sha1::from(&x).digest().bytes().to_vec();
+        for i in 0..5 {
+            if set.len() as u32 >= k {
+                break;
+            }
+            let y = u32::from_be_bytes(x[i * 4..i * 4 + 4].try_into().unwrap());
+            let index = y % piece_count;
+            if !set.contains(&index) {
+                set.push(index);
+            }
         }
     }
-    data.push(byte);
-
-    return data;
+    set
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::torrent::tests::single_file_torrent;
 
     #[test]
-    fn test_create_bitfield() {
-        let mut have = HashSet::new();
-        for i in 0..19 {
-            have.insert(i);
-        }
-        have.remove(&u32::from_str_radix("3", 10).unwrap());
-        let b = create_bitfield(have.len() as u32, &have);
-        println!("{:08b} {}", b.get(0).unwrap(), b.len());
-        println!("{:08b} {}", b.get(1).unwrap(), b.len());
-        println!("{:08b} {}", b.get(2).unwrap(), b.len());
+    fn test_check_request() {
+        let torrent =
+            single_file_torrent(5 * DEFAULT_BLOCK_LENGTH as u64, 4 * DEFAULT_BLOCK_LENGTH);
+        let ok =
+            |piece, offset, len| check_request(&torrent, &Block::new(piece, offset, len)).is_ok();
+
+        assert!(ok(0, 3 * DEFAULT_BLOCK_LENGTH, DEFAULT_BLOCK_LENGTH));
+        assert!(ok(1, 0, DEFAULT_BLOCK_LENGTH));
+        // Last piece is shorter
+        assert!(!ok(1, DEFAULT_BLOCK_LENGTH, 1));
+        assert!(!ok(2, 0, DEFAULT_BLOCK_LENGTH));
+        assert!(!ok(0, 0, 0));
+        assert!(!ok(0, 0, MAX_REQUEST_LENGTH + 1));
+        assert!(!ok(0, u32::MAX, DEFAULT_BLOCK_LENGTH));
+    }
+
+    #[test]
+    fn test_allowed_fast_set() {
+        // Example from BEP 6
+        let ip = Ipv4Addr::new(80, 4, 4, 200);
+        assert_eq!(
+            allowed_fast_set(ip, &[0xaa; 20], 1313, 7),
+            vec![1059, 431, 808, 1217, 287, 376, 1188]
+        );
+        assert_eq!(allowed_fast_set(ip, &[0xaa; 20], 1313, 9)[7..], [353, 508]);
+        assert_eq!(allowed_fast_set(ip, &[0xaa; 20], 3, 10).len(), 3);
     }
 }
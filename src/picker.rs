@@ -0,0 +1,394 @@
+use std::{cmp::min, collections::HashMap, net::SocketAddr};
+
+use crate::bitfield::Bitfield;
+use crate::torrent::{Block, DEFAULT_BLOCK_LENGTH};
+
+// Keeps track of which pieces we have and which blocks of the other pieces are being downloaded.
+// Any number of peers can contribute blocks to the same piece, each received block remembers
+// the peer it came from.
+pub struct PiecePicker {
+    piece_count: u32,
+    // Pieces are shorter at the end of the torrent, and at the end of every file in v2
+    piece_lens: Vec<u32>,
+    have: Bitfield,
+    in_progress: HashMap<u32, PartialPiece>,
+}
+
+struct PartialPiece {
+    len: u32,
+    blocks: Vec<BlockState>,
+    received: usize,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum BlockState {
+    Free,
+    // Number of peers this block is requested from, more than one in endgame
+    Requested(u32),
+    // The block arrived and is being handed to the disk cache
+    Writing,
+    Received(SocketAddr),
+}
+
+impl PiecePicker {
+    pub fn new(piece_lens: Vec<u32>) -> Self {
+        Self {
+            piece_count: piece_lens.len() as u32,
+            have: Bitfield::new(piece_lens.len() as u32),
+            piece_lens: piece_lens,
+            in_progress: HashMap::new(),
+        }
+    }
+
+    // Returns a block the peer has that nobody requested yet and marks it requested.
+    // Pieces other peers already started are continued first so they complete sooner,
+    // a new piece is only started if pick_new is set, the peer's suggestions first.
+    pub fn request_block(
+        &mut self,
+        peer_has: &Bitfield,
+        suggested: &[u32],
+        pick_new: bool,
+    ) -> Option<Block> {
+        let mut started: Vec<&u32> = self
+            .in_progress
+            .iter()
+            .filter(|(p, pp)| peer_has.has(**p) && pp.blocks.contains(&BlockState::Free))
+            .map(|(p, _)| p)
+            .collect();
+        started.sort();
+
+        let piece = match started.first() {
+            Some(p) => **p,
+            None => {
+                if !pick_new {
+                    return None;
+                }
+                self.pick(peer_has, suggested)?
+            }
+        };
+
+        let pp = self.in_progress.get_mut(&piece).unwrap();
+        let idx = pp.blocks.iter().position(|b| *b == BlockState::Free)?;
+        pp.blocks[idx] = BlockState::Requested(1);
+        Some(block_at(piece, pp.len, idx))
+    }
+
+    fn pick(&mut self, peer_has: &Bitfield, suggested: &[u32]) -> Option<u32> {
+        let pickable =
+            |p: &u32| peer_has.has(*p) && !self.have.has(*p) && !self.in_progress.contains_key(p);
+        let piece = match suggested.iter().copied().find(pickable) {
+            Some(p) => p,
+            None => (0..self.piece_count).find(pickable)?,
+        };
+        self.start_piece(piece);
+        Some(piece)
+    }
+
+    // Reserves every block of a piece nobody started yet, for sources that send
+    // whole pieces like web seeds
+    pub fn request_piece(&mut self) -> Option<u32> {
+        let piece = (0..self.piece_count)
+            .find(|p| !self.have.has(*p) && !self.in_progress.contains_key(p))?;
+        self.start_piece(piece);
+        let pp = self.in_progress.get_mut(&piece).unwrap();
+        pp.blocks.fill(BlockState::Requested(1));
+        Some(piece)
+    }
+
+    // All blocks of the piece, in order
+    pub fn piece_blocks(&self, piece: u32) -> Vec<Block> {
+        let len = self.get_piece_len(piece);
+        (0..len.div_ceil(DEFAULT_BLOCK_LENGTH) as usize)
+            .map(|idx| block_at(piece, len, idx))
+            .collect()
+    }
+
+    fn start_piece(&mut self, piece: u32) {
+        let len = self.get_piece_len(piece);
+        let block_count = len.div_ceil(DEFAULT_BLOCK_LENGTH) as usize;
+        self.in_progress.insert(
+            piece,
+            PartialPiece {
+                len: len,
+                blocks: vec![BlockState::Free; block_count],
+                received: 0,
+            },
+        );
+    }
+
+    // Endgame starts once every block of the remaining pieces has been requested
+    pub fn in_endgame(&self) -> bool {
+        self.have.count() as usize + self.in_progress.len() == self.piece_count as usize
+            && self
+                .in_progress
+                .values()
+                .all(|pp| !pp.blocks.contains(&BlockState::Free))
+    }
+
+    // Returns a block that is already requested from another peer, so that the
+    // last blocks don't have to wait for a single slow peer
+    pub fn request_endgame_block(
+        &mut self,
+        peer_has: &Bitfield,
+        already_requested: &[Block],
+    ) -> Option<Block> {
+        for (piece, pp) in self.in_progress.iter_mut() {
+            if !peer_has.has(*piece) {
+                continue;
+            }
+            for (idx, state) in pp.blocks.iter_mut().enumerate() {
+                let b = block_at(*piece, pp.len, idx);
+                if let BlockState::Requested(n) = state
+                    && !already_requested.contains(&b)
+                {
+                    *n += 1;
+                    return Some(b);
+                }
+            }
+        }
+        None
+    }
+
+    // The request for the block won't be answered, i.e. the peer choked us or was cancelled
+    pub fn cancel_request(&mut self, block: &Block) {
+        if let Some(state) = self.block_state_mut(block) {
+            *state = match *state {
+                BlockState::Requested(n) if n > 1 => BlockState::Requested(n - 1),
+                BlockState::Requested(_) => BlockState::Free,
+                s => s,
+            };
+        }
+    }
+
+    pub fn is_received(&self, block: &Block) -> bool {
+        let pp = match self.in_progress.get(&block.piece_index) {
+            Some(pp) => pp,
+            // Piece is either done or was thrown away, either way the block isn't needed
+            None => return true,
+        };
+        let idx = (block.byte_offset / DEFAULT_BLOCK_LENGTH) as usize;
+        matches!(
+            pp.blocks.get(idx),
+            Some(BlockState::Writing) | Some(BlockState::Received(_))
+        )
+    }
+
+    // Reserves the block for writing, returns false if another peer already delivered it
+    pub fn claim_block(&mut self, block: &Block) -> bool {
+        match self.block_state_mut(block) {
+            Some(state @ (BlockState::Free | BlockState::Requested(_))) => {
+                *state = BlockState::Writing;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Records that the claimed block is on disk, returns true once the whole piece is
+    pub fn block_written(&mut self, block: &Block, from: SocketAddr) -> bool {
+        match self.block_state_mut(block) {
+            Some(state @ BlockState::Writing) => *state = BlockState::Received(from),
+            _ => return false,
+        }
+
+        let pp = self.in_progress.get_mut(&block.piece_index).unwrap();
+        pp.received += block.requested_length as usize;
+        pp.received == pp.len as usize
+    }
+
+    // Throws the piece away so it is downloaded again, i.e. after a failed hash check.
+    // Returns the peers that sent blocks of it.
+    pub fn fail_piece(&mut self, piece: u32) -> Vec<SocketAddr> {
+        let contributors = self.contributors(piece);
+        self.in_progress.remove(&piece);
+        contributors
+    }
+
+    // Only the given blocks of the piece are downloaded again, i.e. after v2 block
+    // hashes showed which ones are bad. Returns the peers that sent them.
+    pub fn fail_blocks(&mut self, piece: u32, blocks: &[u32]) -> Vec<SocketAddr> {
+        let mut peers = Vec::new();
+        let pp = match self.in_progress.get_mut(&piece) {
+            Some(pp) => pp,
+            None => return peers,
+        };
+        for idx in blocks {
+            let state = match pp.blocks.get_mut(*idx as usize) {
+                Some(s) => s,
+                None => continue,
+            };
+            if let BlockState::Received(addr) = *state {
+                if !peers.contains(&addr) {
+                    peers.push(addr);
+                }
+                *state = BlockState::Free;
+                pp.received -= block_at(piece, pp.len, *idx as usize).requested_length as usize;
+            }
+        }
+        peers
+    }
+
+    pub fn contributors(&self, piece: u32) -> Vec<SocketAddr> {
+        let mut peers = Vec::new();
+        if let Some(pp) = self.in_progress.get(&piece) {
+            for b in &pp.blocks {
+                if let BlockState::Received(addr) = b
+                    && !peers.contains(addr)
+                {
+                    peers.push(*addr);
+                }
+            }
+        }
+        peers
+    }
+
+    pub fn mark_have(&mut self, piece: u32) {
+        self.in_progress.remove(&piece);
+        self.have.set(piece);
+    }
+
+    // The piece is missing after all, i.e. it failed a recheck
+    pub fn clear_have(&mut self, piece: u32) {
+        self.have.clear(piece);
+    }
+
+    pub fn has(&self, piece: u32) -> bool {
+        self.have.has(piece)
+    }
+
+    pub fn have_pieces(&self) -> &Bitfield {
+        &self.have
+    }
+
+    // Whether the peer has a piece we still need blocks of
+    pub fn is_interesting(&self, peer_has: &Bitfield) -> bool {
+        peer_has.iter().any(|p| {
+            p < self.piece_count
+                && !self.have.has(p)
+                && self
+                    .in_progress
+                    .get(&p)
+                    .is_none_or(|pp| pp.received < pp.len as usize)
+        })
+    }
+
+    // Pieces that are neither downloaded nor in progress
+    pub fn count_pieces_left(&self) -> u32 {
+        self.piece_count - self.have.count() - self.in_progress.len() as u32
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.have.is_full()
+    }
+
+    fn get_piece_len(&self, piece: u32) -> u32 {
+        self.piece_lens[piece as usize]
+    }
+
+    fn block_state_mut(&mut self, block: &Block) -> Option<&mut BlockState> {
+        let pp = self.in_progress.get_mut(&block.piece_index)?;
+        if !block.byte_offset.is_multiple_of(DEFAULT_BLOCK_LENGTH) {
+            return None;
+        }
+        let idx = (block.byte_offset / DEFAULT_BLOCK_LENGTH) as usize;
+        let expected = block_at(block.piece_index, pp.len, idx);
+        if idx >= pp.blocks.len() || expected.requested_length != block.requested_length {
+            return None;
+        }
+        pp.blocks.get_mut(idx)
+    }
+}
+
+fn block_at(piece: u32, piece_len: u32, idx: usize) -> Block {
+    let offset = idx as u32 * DEFAULT_BLOCK_LENGTH;
+    Block::new(
+        piece,
+        offset,
+        min(DEFAULT_BLOCK_LENGTH, piece_len.saturating_sub(offset)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn receive(picker: &mut PiecePicker, b: &Block, from: SocketAddr) -> bool {
+        assert!(picker.claim_block(b));
+        picker.block_written(b, from)
+    }
+
+    #[test]
+    fn test_request_and_fail_piece() {
+        let block_len = DEFAULT_BLOCK_LENGTH;
+        let mut picker = PiecePicker::new(vec![2 * block_len; 4]);
+        let peer_has = Bitfield::from_pieces(4, [1, 2]);
+        let a: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let b: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+
+        // Two peers share piece 1 before piece 2 is started
+        let first = picker.request_block(&peer_has, &[], true).unwrap();
+        let second = picker.request_block(&peer_has, &[], true).unwrap();
+        assert_eq!((first.piece_index, second.piece_index), (1, 1));
+        assert!(picker.request_block(&peer_has, &[], false).is_none());
+        assert_eq!(picker.count_pieces_left(), 3);
+
+        assert!(!receive(&mut picker, &first, a));
+        // Another peer already delivered it
+        assert!(!picker.claim_block(&first));
+        assert!(receive(&mut picker, &second, b));
+        assert!(!picker.is_interesting(&Bitfield::from_pieces(4, [1])));
+
+        let mut blamed = picker.fail_piece(1);
+        blamed.sort();
+        assert_eq!(blamed, vec![a, b]);
+        let first = picker.request_block(&peer_has, &[], true).unwrap();
+        let second = picker.request_block(&peer_has, &[], true).unwrap();
+        assert_eq!((first.piece_index, second.piece_index), (1, 1));
+
+        // Only the bad block of a piece is requested again
+        receive(&mut picker, &first, a);
+        receive(&mut picker, &second, b);
+        assert_eq!(picker.fail_blocks(1, &[1]), vec![b]);
+        assert!(picker.request_block(&peer_has, &[], false) == Some(second));
+        assert!(receive(&mut picker, &second, a));
+
+        picker.mark_have(2);
+        assert!(picker.has(2));
+
+        // Suggested pieces are started before others
+        let peer_has = Bitfield::from_pieces(4, [0, 3]);
+        let b = picker.request_block(&peer_has, &[3], true).unwrap();
+        assert_eq!(b.piece_index, 3);
+        assert!(!picker.is_complete());
+    }
+
+    #[test]
+    fn test_endgame() {
+        let block_len = DEFAULT_BLOCK_LENGTH;
+        // One piece of two blocks, the second one is shorter
+        let mut picker = PiecePicker::new(vec![block_len + 10]);
+        let peer_has = Bitfield::from_pieces(1, [0]);
+        let a: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+
+        let first = picker.request_block(&peer_has, &[], true).unwrap();
+        assert!(!picker.in_endgame());
+        let second = picker.request_block(&peer_has, &[], true).unwrap();
+        assert_eq!(second.requested_length, 10);
+        assert!(picker.in_endgame());
+
+        // A second peer requests the same blocks
+        let dup = picker.request_endgame_block(&peer_has, &[]).unwrap();
+        assert!(dup == first || dup == second);
+        assert!(
+            picker
+                .request_endgame_block(&peer_has, &[first, second])
+                .is_none()
+        );
+
+        assert!(!receive(&mut picker, &first, a));
+        assert!(picker.is_received(&first));
+        assert!(receive(&mut picker, &second, a));
+        picker.mark_have(0);
+        assert!(picker.is_complete());
+    }
+}
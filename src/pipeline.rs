@@ -0,0 +1,151 @@
+use std::{cmp, time};
+
+use crate::torrent::{Block, DEFAULT_BLOCK_LENGTH};
+
+pub const DEFAULT_MIN_REQUESTS_IN_FLIGHT: usize = 2;
+pub const DEFAULT_MAX_REQUESTS_IN_FLIGHT: usize = 250;
+const INITIAL_REQUESTS_IN_FLIGHT: usize = 8;
+
+// How many seconds worth of data we want to have requested from a peer at any time
+const TARGET_QUEUE_TIME: time::Duration = time::Duration::from_secs(2);
+const RATE_WINDOW: time::Duration = time::Duration::from_secs(1);
+
+// Block requests sent to a peer that we haven't received yet.
+// The number of requests kept in flight follows the measured download rate
+// so that roughly TARGET_QUEUE_TIME worth of blocks are always queued at the peer.
+pub struct RequestPipeline {
+    // With the time each request was sent
+    outstanding: Vec<(Block, time::Instant)>,
+    min_depth: usize,
+    max_depth: usize,
+    target_depth: usize,
+
+    window_start: time::Instant,
+    window_bytes: usize,
+}
+
+impl RequestPipeline {
+    pub fn new(min_depth: usize, max_depth: usize) -> Self {
+        Self {
+            outstanding: Vec::new(),
+            min_depth: min_depth,
+            max_depth: max_depth,
+            target_depth: INITIAL_REQUESTS_IN_FLIGHT.clamp(min_depth, max_depth),
+            window_start: time::Instant::now(),
+            window_bytes: 0,
+        }
+    }
+
+    // Number of requests we may send right now, reqq is the peer's advertised request queue size
+    pub fn free_slots(&self, reqq: Option<u32>) -> usize {
+        let mut depth = self.target_depth;
+        if let Some(r) = reqq {
+            depth = cmp::min(depth, cmp::max(r as usize, 1));
+        }
+        depth.saturating_sub(self.outstanding.len())
+    }
+
+    pub fn on_request(&mut self, block: Block) {
+        if self.outstanding.is_empty() {
+            // Don't count the time the pipeline was idle against the peer
+            self.window_start = time::Instant::now();
+            self.window_bytes = 0;
+        }
+        self.outstanding.push((block, time::Instant::now()));
+    }
+
+    // Returns false if the block wasn't requested by us
+    pub fn on_block(&mut self, block: &Block) -> bool {
+        let idx = match self.outstanding.iter().position(|(b, _)| b == block) {
+            Some(i) => i,
+            None => return false,
+        };
+        self.outstanding.remove(idx);
+
+        self.window_bytes += block.requested_length as usize;
+        let elapsed = self.window_start.elapsed();
+        if elapsed >= RATE_WINDOW {
+            self.adapt(elapsed);
+        }
+
+        true
+    }
+
+    pub fn remove(&mut self, block: &Block) -> bool {
+        let len = self.outstanding.len();
+        self.outstanding.retain(|(b, _)| b != block);
+        len != self.outstanding.len()
+    }
+
+    pub fn outstanding(&self) -> Vec<Block> {
+        self.outstanding.iter().map(|(b, _)| *b).collect()
+    }
+
+    // Forgets all outstanding requests, i.e. when the peer chokes us
+    pub fn clear(&mut self) -> Vec<Block> {
+        self.outstanding.drain(..).map(|(b, _)| b).collect()
+    }
+
+    // Forgets the requests that weren't answered within the timeout and returns them
+    pub fn expire(&mut self, timeout: time::Duration) -> Vec<Block> {
+        let mut expired = Vec::new();
+        self.outstanding.retain(|(b, sent)| {
+            if sent.elapsed() < timeout {
+                return true;
+            }
+            expired.push(*b);
+            false
+        });
+        expired
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.outstanding.is_empty()
+    }
+
+    fn adapt(&mut self, elapsed: time::Duration) {
+        let rate = self.window_bytes as f64 / elapsed.as_secs_f64();
+        let wanted = (rate * TARGET_QUEUE_TIME.as_secs_f64() / DEFAULT_BLOCK_LENGTH as f64).ceil();
+        self.target_depth = (wanted as usize).clamp(self.min_depth, self.max_depth);
+
+        self.window_start = time::Instant::now();
+        self.window_bytes = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_free_slots_honors_reqq() {
+        let mut p = RequestPipeline::new(2, 100);
+        assert_eq!(p.free_slots(None), INITIAL_REQUESTS_IN_FLIGHT);
+        assert_eq!(p.free_slots(Some(3)), 3);
+
+        p.on_request(Block::new(0, 0, DEFAULT_BLOCK_LENGTH));
+        assert_eq!(p.free_slots(Some(3)), 2);
+        assert!(p.on_block(&Block::new(0, 0, DEFAULT_BLOCK_LENGTH)));
+        assert!(!p.on_block(&Block::new(0, 0, DEFAULT_BLOCK_LENGTH)));
+        assert!(p.is_empty());
+
+        let b = Block::new(1, 0, DEFAULT_BLOCK_LENGTH);
+        p.on_request(b);
+        assert!(p.expire(time::Duration::from_secs(60)).is_empty());
+        assert_eq!(p.expire(time::Duration::ZERO), vec![b]);
+        assert!(p.is_empty());
+    }
+
+    #[test]
+    fn test_adapt_grows_with_rate() {
+        let mut p = RequestPipeline::new(2, 100);
+        // 40 blocks per second should keep 80 blocks in flight
+        p.window_bytes = 40 * DEFAULT_BLOCK_LENGTH as usize;
+        p.adapt(time::Duration::from_secs(1));
+        assert_eq!(p.target_depth, 80);
+
+        p.window_bytes = 0;
+        p.adapt(time::Duration::from_secs(1));
+        assert_eq!(p.target_depth, 2);
+    }
+}
@@ -0,0 +1,132 @@
+use std::time;
+
+use crate::torrent::DEFAULT_BLOCK_LENGTH;
+
+// Header of a piece message plus the request message it answers
+pub const PIECE_MESSAGE_OVERHEAD: usize = 13 + 17;
+
+// Bytes per second, None means unlimited
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RateLimit {
+    pub upload: Option<u64>,
+    pub download: Option<u64>,
+}
+
+// Upload and download buckets of a session, torrent or peer
+pub struct Throttle {
+    pub upload: TokenBucket,
+    pub download: TokenBucket,
+}
+
+impl Throttle {
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            upload: TokenBucket::new(limit.upload),
+            download: TokenBucket::new(limit.download),
+        }
+    }
+
+    pub fn set_limit(self: &mut Self, limit: RateLimit) {
+        self.upload.set_rate(limit.upload);
+        self.download.set_rate(limit.download);
+    }
+}
+
+// Refills at rate bytes per second and holds at most a second worth of them.
+// A block is let through as long as there are any tokens left, so the bucket can
+// go into debt which is paid off before the next block passes.
+pub struct TokenBucket {
+    rate: Option<u64>,
+    tokens: f64,
+    last_refill: time::Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: Option<u64>) -> Self {
+        Self {
+            rate: rate,
+            tokens: capacity(rate),
+            last_refill: time::Instant::now(),
+        }
+    }
+
+    pub fn set_rate(self: &mut Self, rate: Option<u64>) {
+        self.refill();
+        self.rate = rate;
+        self.tokens = self.tokens.min(capacity(rate));
+    }
+
+    pub fn has_tokens(self: &mut Self) -> bool {
+        if self.rate.is_none() {
+            return true;
+        }
+        self.refill();
+        self.tokens > 0.0
+    }
+
+    pub fn consume(self: &mut Self, n: usize) {
+        if self.rate.is_some() {
+            self.tokens -= n as f64;
+        }
+    }
+
+    fn refill(self: &mut Self) {
+        let elapsed = self.last_refill.elapsed();
+        self.last_refill = time::Instant::now();
+        if let Some(rate) = self.rate {
+            self.tokens =
+                (self.tokens + rate as f64 * elapsed.as_secs_f64()).min(capacity(self.rate));
+        }
+    }
+}
+
+fn capacity(rate: Option<u64>) -> f64 {
+    match rate {
+        // Always room for at least one block, otherwise tiny limits would stall
+        Some(r) => r.max(DEFAULT_BLOCK_LENGTH as u64) as f64,
+        None => 0.0,
+    }
+}
+
+// Whether every bucket lets the next block through
+pub fn has_tokens(buckets: &mut [&mut TokenBucket]) -> bool {
+    buckets.iter_mut().all(|b| b.has_tokens())
+}
+
+pub fn consume(buckets: &mut [&mut TokenBucket], n: usize) {
+    for b in buckets.iter_mut() {
+        b.consume(n);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let mut unlimited = TokenBucket::new(None);
+        unlimited.consume(1 << 30);
+        assert!(unlimited.has_tokens());
+
+        let rate = 4 * DEFAULT_BLOCK_LENGTH as u64;
+        let mut b = TokenBucket::new(Some(rate));
+        let mut passed = 0;
+        while has_tokens(&mut [&mut b, &mut unlimited]) {
+            consume(&mut [&mut b, &mut unlimited], DEFAULT_BLOCK_LENGTH as usize);
+            passed += 1;
+        }
+        // The bucket keeps refilling while the loop runs
+        assert!((4..=5).contains(&passed));
+
+        // Half a second refills two blocks
+        b.last_refill -= time::Duration::from_millis(500);
+        b.consume(1);
+        assert!(b.has_tokens());
+
+        b.set_rate(None);
+        assert!(b.has_tokens());
+        b.set_rate(Some(rate));
+        assert!(b.tokens <= rate as f64);
+    }
+}
@@ -0,0 +1,87 @@
+use std::collections::{HashMap, VecDeque};
+
+pub const DEFAULT_READ_CACHE_SIZE: usize = 16 * 1024 * 1024;
+
+// Keeps recently uploaded pieces in memory so that requests for the blocks of
+// a popular piece don't hit the disk every time. The least recently used
+// pieces are evicted once the cache holds more than max_size bytes.
+pub struct ReadCache {
+    max_size: usize,
+    size: usize,
+    pieces: HashMap<u32, Vec<u8>>,
+    // Front is the least recently used piece
+    order: VecDeque<u32>,
+}
+
+impl ReadCache {
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            max_size: max_size,
+            size: 0,
+            pieces: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    pub fn get(self: &mut Self, piece: u32) -> Option<&Vec<u8>> {
+        if !self.pieces.contains_key(&piece) {
+            return None;
+        }
+        self.touch(piece);
+        self.pieces.get(&piece)
+    }
+
+    pub fn insert(self: &mut Self, piece: u32, data: Vec<u8>) {
+        if data.len() > self.max_size {
+            return;
+        }
+        if let Some(old) = self.pieces.remove(&piece) {
+            self.size -= old.len();
+        }
+
+        self.size += data.len();
+        self.pieces.insert(piece, data);
+        self.touch(piece);
+
+        while self.size > self.max_size {
+            let lru = match self.order.pop_front() {
+                Some(p) => p,
+                None => break,
+            };
+            if let Some(d) = self.pieces.remove(&lru) {
+                self.size -= d.len();
+            }
+        }
+    }
+
+    fn touch(self: &mut Self, piece: u32) {
+        if let Some(idx) = self.order.iter().position(|p| *p == piece) {
+            self.order.remove(idx);
+        }
+        self.order.push_back(piece);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let mut c = ReadCache::new(30);
+        c.insert(0, vec![0; 10]);
+        c.insert(1, vec![1; 10]);
+        c.insert(2, vec![2; 10]);
+        assert!(c.get(0).is_some());
+
+        c.insert(3, vec![3; 10]);
+        assert!(c.get(1).is_none());
+        assert_eq!(c.get(0).unwrap()[0], 0);
+        assert_eq!(c.get(3).unwrap()[0], 3);
+
+        // Too big to ever fit
+        c.insert(4, vec![4; 31]);
+        assert!(c.get(4).is_none());
+        assert_eq!(c.size, 30);
+    }
+}
@@ -1,14 +1,19 @@
-use std::{io, net::TcpListener};
+use std::{io, net};
+
+use mio::net::TcpListener;
 
 pub struct Server {
     pub s: TcpListener,
 }
 
 impl Server {
-    pub fn start() -> Result<Self, io::Error> {
-        let s = TcpListener::bind("0.0.0.0:6881")?;
+    pub fn start(addr: net::SocketAddr) -> Result<Self, io::Error> {
+        let s = TcpListener::bind(addr)?;
 
-        println!("started server, accepting connections at *:6881");
+        println!(
+            "started server, accepting connections at {}",
+            s.local_addr()?
+        );
 
         Ok(Server { s: s })
     }
@@ -0,0 +1,809 @@
+use crate::{
+    candidates::PeerSource,
+    choker::SeedChokeMode,
+    connection_limits::{
+        ConnectionLimits, ConnectionSlots, DEFAULT_SESSION_LIMITS, DEFAULT_TORRENT_LIMITS,
+    },
+    disk::{DiskConfig, DiskIo, DiskThreads},
+    hasher::{DEFAULT_HASH_THREADS, HashThreads, Hasher},
+    mse::EncryptionPolicy,
+    peer::{ConnectionState, HANDSHAKE_TIMEOUT, Peer},
+    peer_pool::{DEFAULT_CHOKE_INTERVAL, DEFAULT_CONNECT_TIMEOUT, PeerPool},
+    rate_limit::{RateLimit, Throttle},
+    server::Server,
+    torrent::Torrent,
+    util::easy_err,
+    utp::{SharedUtpSocket, UtpSocket, UtpStream},
+};
+use mio::{Events, Interest, Poll, Token};
+use std::{cell::RefCell, collections::HashMap, io, net, rc::Rc, time};
+
+// Tokens are split in two, the upper bits are the id of the torrent owning the
+// connection. Id 0 belongs to the session itself, it owns the listener and
+// incoming connections until their handshake tells us which torrent they want.
+pub const TORRENT_TOKEN_SHIFT: usize = 32;
+const LISTENER: Token = Token(0);
+const UTP_SOCKET: Token = Token(1);
+// Timers like choking and keep alives are checked at least this often
+const POLL_INTERVAL: time::Duration = time::Duration::from_millis(100);
+
+// Runs any number of torrents in one process. All of them share the listening
+// socket, the peer id and the event loop.
+pub struct Session {
+    peer_id: [u8; 20],
+    poll: Poll,
+    server: Server,
+    // Listens on the same port as the server, over UDP
+    utp: SharedUtpSocket,
+    // Whether outgoing connections try uTP before TCP
+    outgoing_utp: bool,
+    torrents: HashMap<usize, PeerPool>,
+    next_torrent_id: usize,
+    // Incoming connections we haven't read the handshake of yet
+    incoming: HashMap<Token, Peer>,
+    next_token: usize,
+    // Limits shared by all torrents
+    throttle: Throttle,
+    limits: ConnectionLimits,
+    // Applied to torrents when they are added
+    torrent_limits: ConnectionLimits,
+    connect_timeout: time::Duration,
+    choke_interval: time::Duration,
+    count_overhead: bool,
+    encryption: EncryptionPolicy,
+    disk_config: DiskConfig,
+    // Declared after the torrents so that they are dropped, and flush their caches, first
+    disk_threads: DiskThreads,
+    hash_threads: HashThreads,
+    // TODO: dht
+}
+
+impl Session {
+    pub fn new(peer_id: [u8; 20], listen: net::SocketAddr) -> Result<Session, io::Error> {
+        let poll = Poll::new()?;
+        let mut server = Server::start(listen)?;
+        poll.registry()
+            .register(&mut server.s, LISTENER, Interest::READABLE)?;
+        // The listener picked the port if none was given
+        let mut utp = UtpSocket::bind(server.s.local_addr()?)?;
+        utp.register(poll.registry(), UTP_SOCKET)?;
+
+        Ok(Session {
+            peer_id: peer_id,
+            poll: poll,
+            server: server,
+            utp: Rc::new(RefCell::new(utp)),
+            outgoing_utp: false,
+            torrents: HashMap::new(),
+            next_torrent_id: 1,
+            incoming: HashMap::new(),
+            next_token: UTP_SOCKET.0 + 1,
+            throttle: Throttle::new(RateLimit::default()),
+            limits: DEFAULT_SESSION_LIMITS,
+            torrent_limits: DEFAULT_TORRENT_LIMITS,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            choke_interval: DEFAULT_CHOKE_INTERVAL,
+            count_overhead: false,
+            encryption: EncryptionPolicy::default(),
+            disk_config: DiskConfig::default(),
+            disk_threads: DiskThreads::new(DiskConfig::default().io_threads),
+            hash_threads: HashThreads::new(DEFAULT_HASH_THREADS),
+        })
+    }
+
+    pub fn peer_id(&self) -> [u8; 20] {
+        self.peer_id
+    }
+
+    // Returns the info hash the torrent is managed by
+    pub fn add_torrent(
+        self: &mut Self,
+        torrent: Torrent,
+        download_file_name: String,
+    ) -> Result<[u8; 20], io::Error> {
+        let info_hash = torrent.info_hash;
+        if self.find_torrent(&info_hash).is_some() {
+            return Err(easy_err("torrent is already added"));
+        }
+
+        let id = self.next_torrent_id;
+        let disk = DiskIo::new(
+            &download_file_name,
+            self.disk_threads.sender(),
+            self.disk_config,
+        )?;
+        println!(
+            "downloading {} to {}",
+            torrent.get_info_hash_str(),
+            download_file_name
+        );
+        let hasher = Hasher::new(self.hash_threads.sender());
+        let mut pool = PeerPool::new(id, self.peer_id, torrent, disk, hasher);
+        pool.set_connection_limits(self.torrent_limits);
+        pool.set_connect_timeout(self.connect_timeout);
+        pool.set_choke_interval(self.choke_interval);
+        pool.set_count_overhead(self.count_overhead);
+        pool.set_encryption_policy(self.encryption);
+        pool.set_utp(self.outgoing_utp.then(|| self.utp.clone()));
+        self.torrents.insert(id, pool);
+        self.next_torrent_id += 1;
+
+        Ok(info_hash)
+    }
+
+    // Disconnects all peers of the torrent and forgets it, downloaded data is kept on disk
+    pub fn remove_torrent(self: &mut Self, info_hash: &[u8; 20]) -> Result<(), io::Error> {
+        let id = self.find_torrent(info_hash).ok_or(unknown_torrent())?;
+        let mut pool = self.torrents.remove(&id).unwrap();
+        pool.pause();
+        Ok(())
+    }
+
+    pub fn pause_torrent(self: &mut Self, info_hash: &[u8; 20]) -> Result<(), io::Error> {
+        let id = self.find_torrent(info_hash).ok_or(unknown_torrent())?;
+        self.torrents.get_mut(&id).unwrap().pause();
+        Ok(())
+    }
+
+    pub fn resume_torrent(self: &mut Self, info_hash: &[u8; 20]) -> Result<(), io::Error> {
+        let id = self.find_torrent(info_hash).ok_or(unknown_torrent())?;
+        self.torrents.get_mut(&id).unwrap().resume();
+        Ok(())
+    }
+
+    // Checks the data already on disk, e.g. when resuming a download
+    pub fn recheck_torrent(self: &mut Self, info_hash: &[u8; 20]) -> Result<(), io::Error> {
+        let id = self.find_torrent(info_hash).ok_or(unknown_torrent())?;
+        self.torrents.get_mut(&id).unwrap().recheck();
+        Ok(())
+    }
+
+    // Pieces that were checked already keep their result
+    pub fn cancel_recheck(self: &mut Self, info_hash: &[u8; 20]) -> Result<(), io::Error> {
+        let id = self.find_torrent(info_hash).ok_or(unknown_torrent())?;
+        self.torrents.get_mut(&id).unwrap().cancel_recheck();
+        Ok(())
+    }
+
+    // Checked and total piece count, None if the torrent isn't being rechecked
+    pub fn recheck_progress(&self, info_hash: &[u8; 20]) -> Result<Option<(u32, u32)>, io::Error> {
+        let id = self.find_torrent(info_hash).ok_or(unknown_torrent())?;
+        Ok(self.torrents[&id].recheck_progress())
+    }
+
+    // Can only be changed while there are no torrents, they share the io threads
+    pub fn set_disk_config(self: &mut Self, config: DiskConfig) -> Result<(), io::Error> {
+        if !self.torrents.is_empty() {
+            return Err(easy_err(
+                "disk config can't change while torrents are running",
+            ));
+        }
+        if config.io_threads != self.disk_config.io_threads {
+            self.disk_threads = DiskThreads::new(config.io_threads);
+        }
+        self.disk_config = config;
+        Ok(())
+    }
+
+    pub fn set_rate_limit(self: &mut Self, limit: RateLimit) {
+        self.throttle.set_limit(limit);
+    }
+
+    pub fn set_torrent_rate_limit(
+        self: &mut Self,
+        info_hash: &[u8; 20],
+        limit: RateLimit,
+    ) -> Result<(), io::Error> {
+        let id = self.find_torrent(info_hash).ok_or(unknown_torrent())?;
+        self.torrents.get_mut(&id).unwrap().set_rate_limit(limit);
+        Ok(())
+    }
+
+    // Limits every peer of the torrent on its own
+    pub fn set_peer_rate_limit(
+        self: &mut Self,
+        info_hash: &[u8; 20],
+        limit: RateLimit,
+    ) -> Result<(), io::Error> {
+        let id = self.find_torrent(info_hash).ok_or(unknown_torrent())?;
+        self.torrents
+            .get_mut(&id)
+            .unwrap()
+            .set_peer_rate_limit(limit);
+        Ok(())
+    }
+
+    pub fn set_upload_slots(
+        self: &mut Self,
+        info_hash: &[u8; 20],
+        slots: usize,
+    ) -> Result<(), io::Error> {
+        let id = self.find_torrent(info_hash).ok_or(unknown_torrent())?;
+        self.torrents.get_mut(&id).unwrap().set_upload_slots(slots);
+        Ok(())
+    }
+
+    // How peers are unchoked once the torrent is complete
+    pub fn set_seed_choke_mode(
+        self: &mut Self,
+        info_hash: &[u8; 20],
+        mode: SeedChokeMode,
+    ) -> Result<(), io::Error> {
+        let id = self.find_torrent(info_hash).ok_or(unknown_torrent())?;
+        self.torrents
+            .get_mut(&id)
+            .unwrap()
+            .set_seed_choke_mode(mode);
+        Ok(())
+    }
+
+    // Limits the connections of all torrents together, pending incoming ones included.
+    // Torrents close their own connections above their limits, the session doesn't.
+    pub fn set_connection_limits(
+        self: &mut Self,
+        limits: ConnectionLimits,
+    ) -> Result<(), io::Error> {
+        limits.validate()?;
+        self.limits = limits;
+        Ok(())
+    }
+
+    // Limits of torrents added from now on
+    pub fn set_default_torrent_connection_limits(
+        self: &mut Self,
+        limits: ConnectionLimits,
+    ) -> Result<(), io::Error> {
+        limits.validate()?;
+        self.torrent_limits = limits;
+        Ok(())
+    }
+
+    // Also for running torrents, connections above the new limit are closed on the next tick
+    pub fn set_torrent_connection_limits(
+        self: &mut Self,
+        info_hash: &[u8; 20],
+        limits: ConnectionLimits,
+    ) -> Result<(), io::Error> {
+        limits.validate()?;
+        let id = self.find_torrent(info_hash).ok_or(unknown_torrent())?;
+        self.torrents
+            .get_mut(&id)
+            .unwrap()
+            .set_connection_limits(limits);
+        Ok(())
+    }
+
+    // Whether message framing counts against the limits, by default only piece data does
+    pub fn set_count_overhead(self: &mut Self, count_overhead: bool) {
+        self.count_overhead = count_overhead;
+        for pool in self.torrents.values_mut() {
+            pool.set_count_overhead(count_overhead);
+        }
+    }
+
+    // Applies to new connections only
+    pub fn set_encryption_policy(self: &mut Self, policy: EncryptionPolicy) {
+        self.encryption = policy;
+        for pool in self.torrents.values_mut() {
+            pool.set_encryption_policy(policy);
+        }
+    }
+
+    // Incoming uTP connections are always accepted, this only affects outgoing ones.
+    // Peers that don't answer over uTP are retried over TCP.
+    pub fn set_outgoing_utp(self: &mut Self, enabled: bool) {
+        self.outgoing_utp = enabled;
+        for pool in self.torrents.values_mut() {
+            pool.set_utp(enabled.then(|| self.utp.clone()));
+        }
+    }
+
+    // Outgoing connections that aren't established after this are given up
+    pub fn set_connect_timeout(self: &mut Self, timeout: time::Duration) {
+        self.connect_timeout = timeout;
+        for pool in self.torrents.values_mut() {
+            pool.set_connect_timeout(timeout);
+        }
+    }
+
+    // How often the peers to unchoke are picked again
+    pub fn set_choke_interval(self: &mut Self, interval: time::Duration) {
+        self.choke_interval = interval;
+        for pool in self.torrents.values_mut() {
+            pool.set_choke_interval(interval);
+        }
+    }
+
+    pub fn connect_peers(
+        self: &mut Self,
+        info_hash: &[u8; 20],
+        peers: Vec<Peer>,
+        source: PeerSource,
+    ) -> Result<(), io::Error> {
+        let id = self.find_torrent(info_hash).ok_or(unknown_torrent())?;
+        let mut slots = self.free_slots();
+        self.torrents.get_mut(&id).unwrap().connect_peers(
+            self.poll.registry(),
+            peers,
+            source,
+            &mut slots,
+        );
+        Ok(())
+    }
+
+    pub fn is_complete(&self, info_hash: &[u8; 20]) -> Result<bool, io::Error> {
+        let id = self.find_torrent(info_hash).ok_or(unknown_torrent())?;
+        Ok(self.torrents[&id].is_complete())
+    }
+
+    // Polls the connections once, callers control the torrents in between
+    pub fn run_once(self: &mut Self) -> Result<(), io::Error> {
+        let mut events = Events::with_capacity(1024);
+        self.poll_once(&mut events, POLL_INTERVAL)
+    }
+
+    fn poll_once(
+        self: &mut Self,
+        events: &mut Events,
+        timeout: time::Duration,
+    ) -> Result<(), io::Error> {
+        if let Err(e) = self.poll.poll(events, Some(timeout)) {
+            if e.kind() == io::ErrorKind::Interrupted {
+                return Ok(());
+            }
+            return Err(e);
+        }
+
+        for event in events.iter() {
+            match event.token() {
+                LISTENER => self.accept_connections(),
+                UTP_SOCKET => {
+                    let ready = self.utp.borrow_mut().receive();
+                    self.accept_utp_connections();
+                    self.handle_utp_ready(ready);
+                }
+                token if token.0 >> TORRENT_TOKEN_SHIFT == 0 => {
+                    self.handle_incoming(token, event.is_readable() || event.is_read_closed())
+                }
+                token => {
+                    if let Some(pool) = self.torrents.get_mut(&(token.0 >> TORRENT_TOKEN_SHIFT)) {
+                        pool.handle_event(event, &mut self.throttle);
+                    }
+                }
+            }
+        }
+        // Retransmissions and acks of the uTP connections
+        let ready = self.utp.borrow_mut().tick();
+        self.handle_utp_ready(ready);
+
+        self.drop_stale_incoming();
+        let mut slots = self.free_slots();
+        for pool in self.torrents.values_mut() {
+            pool.tick(self.poll.registry(), &mut self.throttle, &mut slots);
+        }
+
+        Ok(())
+    }
+
+    fn accept_connections(self: &mut Self) {
+        loop {
+            let (conn, addr) = match self.server.s.accept() {
+                Ok(c) => c,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    println!("failed to accept {:?}", e);
+                    return;
+                }
+            };
+
+            let mut peer = match addr.ip() {
+                net::IpAddr::V4(v4) => Peer::new(u32::from_be_bytes(v4.octets()), addr.port()),
+                net::IpAddr::V6(_) => {
+                    continue;
+                }
+            };
+
+            // Pending handshakes are half open connections as well
+            if self.incoming.len() >= self.limits.max_half_open {
+                println!(
+                    "dropping incoming connection from {}, too many pending",
+                    addr
+                );
+                continue;
+            }
+            let token = Token(self.next_token);
+            self.next_token += 1;
+            if let Err(e) = peer.accept(conn, self.poll.registry(), token) {
+                println!("failed to accept {:?}", e);
+                continue;
+            }
+            self.incoming.insert(token, peer);
+        }
+    }
+
+    fn accept_utp_connections(self: &mut Self) {
+        while let Some(conn) = UtpStream::accept(&self.utp) {
+            let addr = conn.peer_addr();
+            let mut peer = match addr.ip() {
+                net::IpAddr::V4(v4) => Peer::new(u32::from_be_bytes(v4.octets()), addr.port()),
+                net::IpAddr::V6(_) => {
+                    continue;
+                }
+            };
+
+            if self.incoming.len() >= self.limits.max_half_open {
+                println!(
+                    "dropping incoming connection from {}, too many pending",
+                    addr
+                );
+                continue;
+            }
+            let token = Token(self.next_token);
+            self.next_token += 1;
+            peer.accept_utp(conn, token);
+            self.incoming.insert(token, peer);
+            // The handshake might have arrived with the connection already
+            self.handle_incoming(token, true);
+        }
+    }
+
+    // The socket isn't borrowed here anymore, the peers use it for reading
+    fn handle_utp_ready(self: &mut Self, tokens: Vec<Token>) {
+        for token in tokens {
+            if token.0 >> TORRENT_TOKEN_SHIFT == 0 {
+                self.handle_incoming(token, true);
+            } else if let Some(pool) = self.torrents.get_mut(&(token.0 >> TORRENT_TOKEN_SHIFT)) {
+                pool.handle_utp_ready(token, &mut self.throttle);
+            }
+        }
+    }
+
+    // Reads the handshake of an incoming connection and passes it to the torrent it asks for.
+    // Encrypted connections tell which torrent they want during the MSE handshake already.
+    fn handle_incoming(self: &mut Self, token: Token, readable: bool) {
+        let info_hashes: Vec<[u8; 20]> = self.torrents.values().map(|p| p.info_hash()).collect();
+        let peer = match self.incoming.get_mut(&token) {
+            Some(p) => p,
+            None => return,
+        };
+        if !readable {
+            return;
+        }
+
+        let result = peer
+            .fill_read_buf()
+            .and_then(|_| peer.detect_encryption(self.encryption))
+            .and_then(|_| peer.continue_encryption(&info_hashes))
+            .and_then(|_| peer.flush());
+        if let Err(e) = result {
+            println!("failed to read handshake of {:?} {:?}", peer, e);
+            self.incoming.remove(&token);
+            return;
+        }
+        if peer.state == ConnectionState::Encrypting {
+            return;
+        }
+        let info_hash = match peer.handshake_info_hash() {
+            Some(h) => h,
+            None => return,
+        };
+
+        let peer = self.incoming.remove(&token).unwrap();
+        let mut slots = self.free_slots();
+        match self
+            .find_torrent(&info_hash)
+            .and_then(|id| self.torrents.get_mut(&id))
+        {
+            Some(pool) if !pool.is_paused() => {
+                pool.add_incoming(self.poll.registry(), &mut self.throttle, &mut slots, peer)
+            }
+            _ => println!("dropping {:?}, we don't serve its torrent", peer),
+        }
+    }
+
+    fn drop_stale_incoming(self: &mut Self) {
+        self.incoming.retain(|_, p| {
+            p.connection_started_at
+                .is_some_and(|t| t.elapsed() < HANDSHAKE_TIMEOUT)
+        });
+    }
+
+    // What's left of the session limits, incoming connections waiting for their handshake count as half open
+    fn free_slots(&self) -> ConnectionSlots {
+        let (mut open, mut half_open) = (self.incoming.len(), self.incoming.len());
+        for pool in self.torrents.values() {
+            let (o, h) = pool.connection_counts();
+            open += o;
+            half_open += h;
+        }
+        self.limits.free(open, half_open)
+    }
+
+    fn find_torrent(&self, info_hash: &[u8; 20]) -> Option<usize> {
+        self.torrents
+            .iter()
+            .find(|(_, pool)| pool.info_hash() == *info_hash)
+            .map(|(id, _)| *id)
+    }
+}
+
+fn unknown_torrent() -> io::Error {
+    easy_err("torrent is not in the session")
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::{mse, torrent::tests::single_file_torrent};
+    use std::io::{Read, Write};
+
+    // Empty data file of the given size, removed by the test
+    pub fn temp_file(name: &str, len: u64) -> String {
+        let file_name = std::env::temp_dir()
+            .join(format!("{}-{}", name, std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        std::fs::File::create(&file_name)
+            .unwrap()
+            .set_len(len)
+            .unwrap();
+        file_name
+    }
+
+    pub fn any_addr() -> net::SocketAddr {
+        net::SocketAddr::from(([0, 0, 0, 0], 0))
+    }
+
+    fn handshake(info_hash: [u8; 20], peer_id: [u8; 20]) -> Vec<u8> {
+        let mut buf = vec![19];
+        buf.extend(b"BitTorrent protocol");
+        buf.extend([0; 8]);
+        buf.extend(info_hash);
+        buf.extend(peer_id);
+        buf
+    }
+
+    #[test]
+    fn test_route_incoming_by_info_hash() {
+        let file_name = temp_file("session-test", 16384);
+
+        let mut session = Session::new([b'a'; 20], any_addr()).unwrap();
+        let torrent = single_file_torrent(16384, 16384);
+        let info_hash = session
+            .add_torrent(torrent.clone(), file_name.clone())
+            .unwrap();
+        assert!(session.add_torrent(torrent, file_name.clone()).is_err());
+
+        let port = session.server.s.local_addr().unwrap().port();
+        let mut served = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let mut unknown = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let mut ourselves = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+        served.write_all(&handshake(info_hash, [b'b'; 20])).unwrap();
+        unknown.write_all(&handshake([2; 20], [b'c'; 20])).unwrap();
+        ourselves
+            .write_all(&handshake(info_hash, session.peer_id()))
+            .unwrap();
+
+        let mut events = Events::with_capacity(16);
+        for _ in 0..10 {
+            session
+                .poll_once(&mut events, time::Duration::from_millis(50))
+                .unwrap();
+        }
+
+        let timeout = Some(time::Duration::from_secs(1));
+        served.set_read_timeout(timeout).unwrap();
+        let mut reply = [0; 68];
+        served.read_exact(&mut reply).unwrap();
+        assert_eq!(reply[28..48], info_hash);
+        assert_eq!(reply[48..68], session.peer_id());
+
+        // Connections for torrents we don't have and to ourselves are closed without a reply
+        unknown.set_read_timeout(timeout).unwrap();
+        assert_eq!(unknown.read(&mut reply).unwrap(), 0);
+        ourselves.set_read_timeout(timeout).unwrap();
+        assert_eq!(ourselves.read(&mut reply).unwrap(), 0);
+
+        session.pause_torrent(&info_hash).unwrap();
+        session.remove_torrent(&info_hash).unwrap();
+        assert!(session.resume_torrent(&info_hash).is_err());
+        std::fs::remove_file(&file_name).unwrap();
+    }
+
+    #[test]
+    fn test_torrent_connection_limits() {
+        let file_name = temp_file("session-limits-test", 16384);
+        let mut session = Session::new([b'a'; 20], any_addr()).unwrap();
+        let info_hash = session
+            .add_torrent(single_file_torrent(16384, 16384), file_name.clone())
+            .unwrap();
+        let port = session.server.s.local_addr().unwrap().port();
+        let mut events = Events::with_capacity(16);
+
+        let mut conns = Vec::new();
+        for id in [b'b', b'c'] {
+            let mut c = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+            c.write_all(&handshake(info_hash, [id; 20])).unwrap();
+            conns.push(c);
+        }
+        for _ in 0..10 {
+            session
+                .poll_once(&mut events, time::Duration::from_millis(20))
+                .unwrap();
+        }
+
+        let limits = ConnectionLimits {
+            max_connections: 1,
+            max_half_open: 1,
+        };
+        session
+            .set_torrent_connection_limits(&info_hash, limits)
+            .unwrap();
+        assert!(
+            session
+                .set_torrent_connection_limits(&[3; 20], limits)
+                .is_err()
+        );
+        for _ in 0..10 {
+            session
+                .poll_once(&mut events, time::Duration::from_millis(20))
+                .unwrap();
+        }
+
+        // Whatever was queued is read first, then only one of them sees the end
+        let closed = conns
+            .iter_mut()
+            .map(|c| {
+                c.set_read_timeout(Some(time::Duration::from_millis(200)))
+                    .unwrap();
+                let mut buf = [0; 1024];
+                loop {
+                    match c.read(&mut buf) {
+                        Ok(0) => return true,
+                        Ok(_) => {}
+                        Err(_) => return false,
+                    }
+                }
+            })
+            .filter(|closed| *closed)
+            .count();
+        assert_eq!(closed, 1);
+        std::fs::remove_file(&file_name).unwrap();
+    }
+
+    #[test]
+    fn test_recheck() {
+        let file_name = temp_file("session-recheck-test", 64 * 16384);
+        let mut session = Session::new([b'a'; 20], any_addr()).unwrap();
+        let info_hash = session
+            .add_torrent(single_file_torrent(64 * 16384, 16384), file_name.clone())
+            .unwrap();
+        assert!(session.recheck_progress(&info_hash).unwrap().is_none());
+
+        // Cancelled right after it started, the results still queued are dropped
+        session.recheck_torrent(&info_hash).unwrap();
+        assert_eq!(session.recheck_progress(&info_hash).unwrap(), Some((0, 64)));
+        session.run_once().unwrap();
+        assert!(session.recheck_progress(&info_hash).unwrap().is_some());
+        session.cancel_recheck(&info_hash).unwrap();
+        session.run_once().unwrap();
+        assert!(session.recheck_progress(&info_hash).unwrap().is_none());
+
+        // Zeroed data doesn't match the zeroed hashes, nothing is complete afterwards
+        session.recheck_torrent(&info_hash).unwrap();
+        let mut checked = 0;
+        while let Some((c, total)) = session.recheck_progress(&info_hash).unwrap() {
+            assert!(c >= checked && total == 64);
+            checked = c;
+            session.run_once().unwrap();
+        }
+        assert!(!session.is_complete(&info_hash).unwrap());
+        assert!(session.cancel_recheck(&[3; 20]).is_err());
+        std::fs::remove_file(&file_name).unwrap();
+    }
+
+    #[test]
+    fn test_encrypted_incoming() {
+        let file_name = temp_file("session-mse-test", 16384);
+
+        let mut session = Session::new([b'a'; 20], any_addr()).unwrap();
+        session.set_encryption_policy(EncryptionPolicy::Forced);
+        let torrent = single_file_torrent(16384, 16384);
+        let info_hash = session.add_torrent(torrent, file_name.clone()).unwrap();
+
+        let port = session.server.s.local_addr().unwrap().port();
+        let mut events = Events::with_capacity(16);
+        let wait = time::Duration::from_millis(10);
+
+        // Plaintext isn't accepted
+        let mut plain = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+        plain.write_all(&handshake(info_hash, [b'b'; 20])).unwrap();
+
+        let mut conn = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+        conn.set_read_timeout(Some(wait)).unwrap();
+        let mut mse = mse::Handshake::outgoing(info_hash, EncryptionPolicy::Forced);
+        let (mut input, mut output) = (Vec::new(), Vec::new());
+        let mut established = None;
+        let mut buf = [0; 4096];
+        for _ in 0..50 {
+            if established.is_none() {
+                established = mse.step(&mut input, &mut output, &[]).unwrap();
+            }
+            conn.write_all(&output).unwrap();
+            output.clear();
+            session.poll_once(&mut events, wait).unwrap();
+            if let Ok(n) = conn.read(&mut buf) {
+                input.extend(&buf[..n]);
+            }
+            if established.is_some() {
+                break;
+            }
+        }
+        let mut cipher = established.unwrap().cipher.unwrap();
+
+        let mut msg = handshake(info_hash, [b'b'; 20]);
+        cipher.encrypt.apply(&mut msg);
+        conn.write_all(&msg).unwrap();
+        for _ in 0..10 {
+            session.poll_once(&mut events, wait).unwrap();
+        }
+
+        conn.set_read_timeout(Some(time::Duration::from_secs(1)))
+            .unwrap();
+        // Whatever followed the mse handshake is decrypted already
+        let mut reply = input;
+        while reply.len() < 68 {
+            let n = conn.read(&mut buf).unwrap();
+            cipher.decrypt.apply(&mut buf[..n]);
+            reply.extend(&buf[..n]);
+        }
+        assert_eq!(reply[28..48], info_hash);
+        assert_eq!(reply[48..68], session.peer_id());
+
+        plain
+            .set_read_timeout(Some(time::Duration::from_secs(1)))
+            .unwrap();
+        assert_eq!(plain.read(&mut buf).unwrap(), 0);
+        std::fs::remove_file(&file_name).unwrap();
+    }
+
+    #[test]
+    fn test_utp_incoming() {
+        let file_name = temp_file("session-utp-test", 16384);
+
+        let mut session = Session::new([b'a'; 20], any_addr()).unwrap();
+        let torrent = single_file_torrent(16384, 16384);
+        let info_hash = session.add_torrent(torrent, file_name.clone()).unwrap();
+
+        let port = session.server.s.local_addr().unwrap().port();
+        let client = Rc::new(RefCell::new(UtpSocket::bind(any_addr()).unwrap()));
+        let addr = net::SocketAddr::from(([127, 0, 0, 1], port));
+        let conn = UtpStream::connect(&client, addr, Token(0)).unwrap();
+        let mut events = Events::with_capacity(16);
+        let wait = time::Duration::from_millis(10);
+
+        let msg = handshake(info_hash, [b'b'; 20]);
+        let mut written = 0;
+        let mut reply = Vec::<u8>::new();
+        let mut buf = [0; 4096];
+        for _ in 0..200 {
+            session.poll_once(&mut events, wait).unwrap();
+            client.borrow_mut().receive();
+            client.borrow_mut().tick();
+            if written < msg.len()
+                && let Ok(n) = conn.write(&msg[written..])
+            {
+                written += n;
+            }
+            if let Ok(n) = conn.read(&mut buf) {
+                reply.extend(&buf[..n]);
+            }
+            if reply.len() >= 68 {
+                break;
+            }
+        }
+        assert_eq!(reply[28..48], info_hash);
+        assert_eq!(reply[48..68], session.peer_id());
+        std::fs::remove_file(&file_name).unwrap();
+    }
+}
@@ -1,7 +1,10 @@
-use crate::{bencoding, util::easy_err};
+use crate::{
+    bencoding,
+    merkle::{self, Hash},
+    util::easy_err,
+};
 use percent_encoding::{self, NON_ALPHANUMERIC};
-use sha1_smol;
-use std::io;
+use std::{collections::HashMap, io};
 
 pub const DEFAULT_BLOCK_LENGTH: u32 = 16384;
 
@@ -10,11 +13,59 @@ pub struct Torrent {
     pub info_hash: [u8; 20],
     pub announce_urls: Vec<String>,
     pub piece_len: u32,
+    // Empty for v2 only torrents
     pub piece_hashes: Vec<[u8; 20]>,
     pub total_size: u64,
+    // Set for v2 and hybrid torrents
+    pub v2: Option<V2Info>,
+    pub name: String,
+    // In the order they are stored in, including pad files
+    pub files: Vec<TorrentFile>,
+    // Web seeds https://www.bittorrent.org/beps/bep_0019.html
+    pub url_list: Vec<String>,
+    // Hash based web seeds https://www.bittorrent.org/beps/bep_0017.html
+    pub http_seeds: Vec<String>,
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Clone, Debug, PartialEq)]
+pub struct TorrentFile {
+    // Starts with the torrent name, single file torrents only have that
+    pub path: Vec<String>,
+    pub length: u64,
+    // Padding between files so that they start at a piece boundary, all zeros
+    pub pad: bool,
+}
+
+// https://www.bittorrent.org/beps/bep_0052.html
+// Every file starts at a piece boundary. v2 only torrents are stored the same way
+// as hybrid ones, with the gaps between files where v1 would put pad files.
+#[derive(Clone)]
+pub struct V2Info {
+    // The wire uses the first 20 bytes of it for v2 only torrents
+    pub info_hash: Hash,
+    pub files: Vec<V2File>,
+}
+
+#[derive(Clone)]
+pub struct V2File {
+    pub path: Vec<String>,
+    pub length: u64,
+    // None for empty files
+    pub pieces_root: Option<Hash>,
+    // Empty if the file fits into a single piece, pieces_root covers it then
+    pub piece_layer: Vec<Hash>,
+    pub first_piece: u32,
+}
+
+// What a piece is checked against
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PieceHash {
+    V1([u8; 20]),
+    // Root over the block hashes of the first len bytes, padded to width leaves
+    V2 { root: Hash, len: u32, width: u32 },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub struct Block {
     pub piece_index: u32,
     pub byte_offset: u32,
@@ -36,7 +87,7 @@ impl Block {
         }
     }
 
-    pub fn parse(payload: &Vec<u8>) -> Result<Block, io::Error> {
+    pub fn parse(payload: &[u8]) -> Result<Block, io::Error> {
         if payload.len() < 12 {
             return Err(easy_err("payload too short to be block"));
         }
@@ -48,8 +99,8 @@ impl Block {
         })
     }
 
-    pub fn to_bytes(&self) -> [u8; 12] {
-        let mut b = [0 as u8; 12];
+    pub fn to_bytes(self) -> [u8; 12] {
+        let mut b = [0_u8; 12];
 
         b[0..4].copy_from_slice(&self.piece_index.to_be_bytes());
         b[4..8].copy_from_slice(&self.byte_offset.to_be_bytes());
@@ -59,23 +110,9 @@ impl Block {
     }
 }
 
-impl DownloadBlock {
-    pub fn parse(payload: &Vec<u8>) -> Result<DownloadBlock, io::Error> {
-        if payload.len() < 9 {
-            return Err(easy_err("payload too short to be download block"));
-        }
-
-        Ok(DownloadBlock {
-            piece_index: u32::from_be_bytes(payload.get(0..4).unwrap().try_into().unwrap()),
-            byte_offset: u32::from_be_bytes(payload.get(4..8).unwrap().try_into().unwrap()),
-            data: payload.get(8..).unwrap().to_vec(),
-        })
-    }
-}
-
 impl Torrent {
     pub fn parse(buf: Vec<u8>) -> Result<Self, io::Error> {
-        let statements = bencoding::parse(&buf).unwrap();
+        let statements = bencoding::parse(&buf).map_err(|e| easy_err(&e))?;
 
         if statements.len() == 0 {
             return Err(easy_err("got no statements"));
@@ -104,10 +141,8 @@ impl Torrent {
                                 for ss in sublist {
                                     match ss {
                                         bencoding::Statement::ByteString(str) => {
-                                            announce_urls.push(
-                                                String::try_from(str::from_utf8(str).unwrap())
-                                                    .unwrap(),
-                                            );
+                                            announce_urls
+                                                .push(String::from_utf8_lossy(str).to_string());
                                         }
                                         _ => {
                                             return Err(easy_err(
@@ -129,67 +164,46 @@ impl Torrent {
             }
         }
 
+        // Trackerless torrents have neither
         if !metainfo.contains_key("announce-list".as_bytes()) {
-            let announce_url = match &metainfo.get("announce".as_bytes()).unwrap() {
-                bencoding::Statement::ByteString(link) => {
-                    String::try_from(str::from_utf8(link).unwrap()).unwrap()
+            match metainfo.get("announce".as_bytes()) {
+                Some(bencoding::Statement::ByteString(link)) => {
+                    announce_urls.push(String::from_utf8_lossy(link).to_string());
                 }
-                _ => {
+                Some(_) => {
                     return Err(easy_err("announce url is not string"));
                 }
-            };
-            announce_urls.push(announce_url);
+                None => {}
+            }
         }
 
-        println!("got announce url {:?}", announce_urls);
-
-        let info = match &metainfo.get("info".as_bytes()).unwrap() {
-            bencoding::Statement::Dictionary(map) => map,
+        let info = match metainfo.get("info".as_bytes()) {
+            Some(bencoding::Statement::Dictionary(map)) => map,
             _ => {
                 return Err(easy_err("info dict is not dict"));
             }
         };
 
-        if let bencoding::Statement::ByteString(name) = &info.get("name".as_bytes()).unwrap() {
-            println!("got file name {}", str::from_utf8(name).unwrap());
-        }
+        let name = match info.get("name".as_bytes()) {
+            Some(bencoding::Statement::ByteString(name)) => {
+                String::from_utf8_lossy(name).to_string()
+            }
+            _ => return Err(easy_err("name is missing")),
+        };
 
-        let info_hash_bs =
-            sha1_smol::Sha1::from(bencoding::marshal(metainfo.get("info".as_bytes()).unwrap()))
-                .digest()
-                .bytes();
+        let url_list = parse_urls(metainfo, "url-list");
+        let http_seeds = parse_urls(metainfo, "httpseeds");
 
-        let mut total_bytes: u64 = 0;
-        match &info.get("length".as_bytes()) {
-            Some(l) => {
-                if let bencoding::Statement::Integer(len) = l {
-                    total_bytes = *len as u64;
-                }
-            }
-            _ => {}
-        }
-        if total_bytes == 0 {
-            if let bencoding::Statement::List(fs) = &info.get("files".as_bytes()).unwrap() {
-                fs.iter().for_each(|s| {
-                    if let bencoding::Statement::Dictionary(dict) = s {
-                        if let bencoding::Statement::Integer(len) =
-                            &dict.get("length".as_bytes()).unwrap()
-                        {
-                            total_bytes += *len as u64;
-                        }
-                    }
-                });
-            }
-        }
+        let info_bytes = bencoding::marshal(metainfo.get("info".as_bytes()).unwrap());
+        let mut info_hash_bs = sha1_smol::Sha1::from(&info_bytes).digest().bytes();
 
-        let mut piece_length = 0;
-        if let bencoding::Statement::Integer(pl) = &info.get("piece length".as_bytes()).unwrap() {
-            piece_length = *pl;
-            println!("got piece length {piece_length}");
-        }
+        let piece_length = match info.get("piece length".as_bytes()) {
+            Some(bencoding::Statement::Integer(pl)) if *pl > 0 && *pl <= u32::MAX as i64 => *pl,
+            _ => return Err(easy_err("piece length is missing")),
+        };
 
         let mut pieces = Vec::new();
-        if let bencoding::Statement::ByteString(s) = &info.get("pieces".as_bytes()).unwrap() {
+        if let Some(bencoding::Statement::ByteString(s)) = &info.get("pieces".as_bytes()) {
             if s.len() % 20 != 0 {
                 return Err(easy_err("pieces length is not a multiple of 20"));
             }
@@ -200,42 +214,44 @@ impl Torrent {
             }
         }
 
-        println!("got piece hashes {}", pieces.len());
-
-        let mut total_len = 0;
-        if info.contains_key("files".as_bytes()) {
-            if let bencoding::Statement::List(ld) = &info.get("files".as_bytes()).unwrap() {
-                for d in ld {
-                    if let Some(num) = get_file_len(d) {
-                        total_len += num;
-                    }
-                }
-            }
-        } else {
-            if let Some(num) = get_file_len(&statements[0]) {
-                total_len += num;
+        let mut files = parse_files(info, &name)?;
+        let mut total_len = files.iter().map(|f| f.length).sum();
+
+        let mut v2 = None;
+        if let Some(bencoding::Statement::Integer(2)) = info.get("meta version".as_bytes()) {
+            let v = V2Info::parse(metainfo, info, &info_bytes, piece_length as u32)?;
+            if pieces.is_empty() {
+                // v2 only, stored with the files aligned to pieces
+                info_hash_bs = v.info_hash[..20].try_into().unwrap();
+                total_len = v
+                    .files
+                    .last()
+                    .map_or(0, |f| f.first_piece as u64 * piece_length as u64 + f.length);
+                files = v.storage_files(&name, piece_length as u64);
             }
+            v2 = Some(v);
+        } else if pieces.is_empty() {
+            return Err(easy_err("torrent has no piece hashes"));
         }
 
-        println!("got total size {total_len}");
-
         let s = Self {
             info_hash: info_hash_bs,
             announce_urls: announce_urls,
             piece_len: piece_length as u32,
             total_size: total_len,
             piece_hashes: pieces,
+            v2: v2,
+            name: name,
+            files: files,
+            url_list: url_list,
+            http_seeds: http_seeds,
         };
 
-        println!(
-            "got total bytes {} and hash {}",
-            total_bytes,
-            s.get_info_hash_str()
-        );
-
-        println!("got total pieces {}", s.get_total_piece_count());
-
-        if s.get_total_piece_count() != s.piece_hashes.len() as u32 {
+        // Piece lengths are computed from the last piece
+        if s.get_total_piece_count() == 0 {
+            return Err(easy_err("torrent has no pieces"));
+        }
+        if !s.piece_hashes.is_empty() && s.get_total_piece_count() != s.piece_hashes.len() as u32 {
             return Err(easy_err(
                 "total piece count is not equal to piece hashes length",
             ));
@@ -249,11 +265,85 @@ impl Torrent {
     }
 
     pub fn get_piece_len(self: &Self, piece: u32) -> u32 {
+        // The last piece of every file is cut short, unless v1 fills it with a pad file
+        if self.piece_hashes.is_empty()
+            && let Some((f, idx)) = self.v2_piece_file(piece)
+        {
+            return (f.length - idx as u64 * self.piece_len as u64).min(self.piece_len as u64)
+                as u32;
+        }
         if piece == self.get_total_piece_count() - 1 {
-            return (self.total_size as u32 - ((piece - 1) * self.piece_len)) as u32;
+            return (self.total_size - piece as u64 * self.piece_len as u64) as u32;
+        }
+
+        self.piece_len
+    }
+
+    // Splits a range of the storage into (file index, offset in file, length) parts
+    pub fn file_ranges(self: &Self, offset: u64, len: u64) -> Vec<(usize, u64, u64)> {
+        let mut ranges = Vec::new();
+        let mut file_start = 0;
+        let end = offset + len;
+        for (i, f) in self.files.iter().enumerate() {
+            let file_end = file_start + f.length;
+            if file_end > offset && file_start < end {
+                let start = offset.max(file_start);
+                ranges.push((i, start - file_start, end.min(file_end) - start));
+            }
+            file_start = file_end;
+        }
+        ranges
+    }
+
+    // v2 hashes are preferred, their block hashes let us find single bad blocks
+    pub fn piece_hash(self: &Self, piece: u32) -> PieceHash {
+        let (f, idx) = match self.v2_piece_file(piece) {
+            Some(f) => f,
+            None => return PieceHash::V1(self.piece_hashes[piece as usize]),
+        };
+
+        let len = (f.length - idx as u64 * self.piece_len as u64).min(self.piece_len as u64) as u32;
+        if f.piece_layer.is_empty() {
+            let blocks = len.div_ceil(DEFAULT_BLOCK_LENGTH);
+            return PieceHash::V2 {
+                root: f.pieces_root.unwrap(),
+                len: len,
+                width: blocks.next_power_of_two(),
+            };
         }
+        PieceHash::V2 {
+            root: f.piece_layer[idx as usize],
+            len: len,
+            width: self.piece_len / DEFAULT_BLOCK_LENGTH,
+        }
+    }
+
+    // The v2 file a piece belongs to and the index of the piece within it
+    pub fn v2_piece_file(self: &Self, piece: u32) -> Option<(&V2File, u32)> {
+        let files = &self.v2.as_ref()?.files;
+        let f = files
+            .iter()
+            .rev()
+            .find(|f| f.length > 0 && f.first_piece <= piece)?;
+        let idx = piece - f.first_piece;
+        if idx as u64 * self.piece_len as u64 >= f.length {
+            // Pad after the file, only hybrid torrents have pieces there
+            return None;
+        }
+        Some((f, idx))
+    }
 
-        self.piece_len as u32
+    pub fn v2_file(self: &Self, pieces_root: &Hash) -> Option<&V2File> {
+        self.v2
+            .as_ref()?
+            .files
+            .iter()
+            .find(|f| f.pieces_root.as_ref() == Some(pieces_root))
+    }
+
+    // Height of the piece layer above the block hashes
+    pub fn piece_layer_height(self: &Self) -> u32 {
+        (self.piece_len / DEFAULT_BLOCK_LENGTH).trailing_zeros()
     }
 
     pub fn get_info_hash_str(self: &Self) -> String {
@@ -261,16 +351,437 @@ impl Torrent {
     }
 }
 
-fn get_file_len(d: &bencoding::Statement) -> Option<u64> {
-    if let bencoding::Statement::Dictionary(dict) = d {
-        dict.get("length".as_bytes()).and_then(|len_stmt| {
-            if let bencoding::Statement::Integer(n) = len_stmt {
-                Some(*n as u64)
-            } else {
-                None
+impl V2Info {
+    fn parse(
+        metainfo: &HashMap<&[u8], bencoding::Statement>,
+        info: &HashMap<&[u8], bencoding::Statement>,
+        info_bytes: &[u8],
+        piece_len: u32,
+    ) -> Result<V2Info, io::Error> {
+        if piece_len < DEFAULT_BLOCK_LENGTH || !piece_len.is_power_of_two() {
+            return Err(easy_err(
+                "v2 piece length is not a power of two of at least 16 KiB",
+            ));
+        }
+
+        let mut files = Vec::new();
+        match info.get("file tree".as_bytes()) {
+            Some(bencoding::Statement::Dictionary(tree)) => {
+                parse_file_tree(tree, &mut Vec::new(), &mut files)?
+            }
+            _ => return Err(easy_err("file tree is not dict")),
+        }
+
+        let layers = match metainfo.get("piece layers".as_bytes()) {
+            Some(bencoding::Statement::Dictionary(l)) => Some(l),
+            None => None,
+            _ => return Err(easy_err("piece layers is not dict")),
+        };
+
+        let height = (piece_len / DEFAULT_BLOCK_LENGTH).trailing_zeros();
+        let mut next_piece = 0;
+        for f in files.iter_mut() {
+            f.first_piece = next_piece;
+            let piece_count = f.length.div_ceil(piece_len as u64) as u32;
+            next_piece += piece_count;
+            if piece_count <= 1 {
+                continue;
+            }
+
+            let root = f.pieces_root.unwrap();
+            let layer = match layers.and_then(|l| l.get(&root[..])) {
+                Some(bencoding::Statement::ByteString(l)) => *l,
+                _ => return Err(easy_err("piece layer is missing")),
+            };
+            if layer.len() != piece_count as usize * 32 {
+                return Err(easy_err("piece layer has the wrong length"));
             }
+            f.piece_layer = layer.chunks(32).map(|h| h.try_into().unwrap()).collect();
+
+            let width = (piece_count as usize).next_power_of_two();
+            if merkle::root(&f.piece_layer, width, merkle::pad_hash(height)) != root {
+                return Err(easy_err("piece layer doesn't match pieces root"));
+            }
+        }
+
+        Ok(V2Info {
+            info_hash: merkle::hash_data(info_bytes),
+            files: files,
         })
-    } else {
-        None
+    }
+}
+
+impl V2Info {
+    // Files with the gaps in between, the way they are laid out in storage
+    fn storage_files(self: &Self, name: &str, piece_len: u64) -> Vec<TorrentFile> {
+        // Single file torrents name the file after the torrent
+        let single = self.files.len() == 1 && self.files[0].path == [name];
+        let mut files = Vec::new();
+        let mut offset = 0;
+        for f in self.files.iter().filter(|f| f.length > 0) {
+            let start = f.first_piece as u64 * piece_len;
+            if start > offset {
+                files.push(TorrentFile {
+                    path: vec![name.to_string(), ".pad".to_string()],
+                    length: start - offset,
+                    pad: true,
+                });
+            }
+            let mut path = Vec::new();
+            if !single {
+                path.push(name.to_string());
+            }
+            path.extend(f.path.iter().cloned());
+            files.push(TorrentFile {
+                path: path,
+                length: f.length,
+                pad: false,
+            });
+            offset = start + f.length;
+        }
+        files
+    }
+}
+
+// Files are leaves with an empty name, everything else is a directory
+fn parse_file_tree(
+    tree: &HashMap<&[u8], bencoding::Statement>,
+    path: &mut Vec<String>,
+    files: &mut Vec<V2File>,
+) -> Result<(), io::Error> {
+    let mut names: Vec<&&[u8]> = tree.keys().collect();
+    names.sort();
+    for name in names {
+        let node = match &tree[*name] {
+            bencoding::Statement::Dictionary(d) => d,
+            _ => return Err(easy_err("file tree node is not dict")),
+        };
+
+        if !name.is_empty() {
+            path.push(String::from_utf8_lossy(name).to_string());
+            parse_file_tree(node, path, files)?;
+            path.pop();
+            continue;
+        }
+
+        let length = match node.get("length".as_bytes()) {
+            Some(bencoding::Statement::Integer(l)) if *l >= 0 => *l as u64,
+            _ => return Err(easy_err("file length is missing")),
+        };
+        let pieces_root = match node.get("pieces root".as_bytes()) {
+            Some(bencoding::Statement::ByteString(r)) if r.len() == 32 => {
+                Some((*r).try_into().unwrap())
+            }
+            None if length == 0 => None,
+            _ => return Err(easy_err("invalid pieces root")),
+        };
+        files.push(V2File {
+            path: path.clone(),
+            length: length,
+            pieces_root: pieces_root,
+            piece_layer: Vec::new(),
+            first_piece: 0,
+        });
+    }
+    Ok(())
+}
+
+// A single url or a list of them, empty ones are dropped
+fn parse_urls(metainfo: &HashMap<&[u8], bencoding::Statement>, key: &str) -> Vec<String> {
+    let mut urls = Vec::new();
+    match metainfo.get(key.as_bytes()) {
+        Some(bencoding::Statement::ByteString(url)) => {
+            urls.push(String::from_utf8_lossy(url).to_string())
+        }
+        Some(bencoding::Statement::List(list)) => {
+            for u in list {
+                if let bencoding::Statement::ByteString(url) = u {
+                    urls.push(String::from_utf8_lossy(url).to_string());
+                }
+            }
+        }
+        _ => {}
+    }
+    urls.retain(|u| !u.is_empty());
+    urls
+}
+
+// https://www.bittorrent.org/beps/bep_0047.html for pad files
+fn parse_files(
+    info: &HashMap<&[u8], bencoding::Statement>,
+    name: &str,
+) -> Result<Vec<TorrentFile>, io::Error> {
+    let list = match info.get("files".as_bytes()) {
+        Some(bencoding::Statement::List(l)) => l,
+        _ => {
+            let length = match info.get("length".as_bytes()) {
+                Some(bencoding::Statement::Integer(l)) if *l >= 0 => *l as u64,
+                // v2 only torrents describe their files in the file tree
+                _ => return Ok(Vec::new()),
+            };
+            return Ok(vec![TorrentFile {
+                path: vec![name.to_string()],
+                length: length,
+                pad: false,
+            }]);
+        }
+    };
+
+    let mut files = Vec::new();
+    for f in list {
+        let dict = match f {
+            bencoding::Statement::Dictionary(d) => d,
+            _ => return Err(easy_err("file is not dict")),
+        };
+        let length = match dict.get("length".as_bytes()) {
+            Some(bencoding::Statement::Integer(l)) if *l >= 0 => *l as u64,
+            _ => return Err(easy_err("file length is missing")),
+        };
+        let mut path = vec![name.to_string()];
+        if let Some(bencoding::Statement::List(parts)) = dict.get("path".as_bytes()) {
+            for p in parts {
+                if let bencoding::Statement::ByteString(p) = p {
+                    path.push(String::from_utf8_lossy(p).to_string());
+                }
+            }
+        }
+        let pad = match dict.get("attr".as_bytes()) {
+            Some(bencoding::Statement::ByteString(attr)) => attr.contains(&b'p'),
+            _ => false,
+        };
+        files.push(TorrentFile {
+            path: path,
+            length: length,
+            pad: pad,
+        });
+    }
+    Ok(files)
+}
+
+// Aim for about this many pieces when creating torrents
+const TARGET_PIECE_COUNT: u64 = 1500;
+const MAX_PIECE_LENGTH: u32 = 16 * 1024 * 1024;
+
+// Power of two piece length between 16 KiB and 16 MiB giving about the target piece count
+pub fn default_piece_len(total_size: u64) -> u32 {
+    let len = (total_size / TARGET_PIECE_COUNT).next_power_of_two();
+    len.clamp(DEFAULT_BLOCK_LENGTH as u64, MAX_PIECE_LENGTH as u64) as u32
+}
+
+// Bencoded v1 metainfo of a single file. The first tracker is the announce url,
+// all of them go into the announce list in a tier each.
+pub fn create_metainfo(
+    name: &str,
+    data: &mut impl io::Read,
+    trackers: &[String],
+    piece_len: u32,
+) -> Result<Vec<u8>, io::Error> {
+    let mut pieces = Vec::new();
+    let mut total_size = 0;
+    let mut buf = vec![0; piece_len as usize];
+    loop {
+        let mut filled = 0;
+        while filled < buf.len() {
+            match data.read(&mut buf[filled..])? {
+                0 => break,
+                n => filled += n,
+            }
+        }
+        if filled == 0 {
+            break;
+        }
+        pieces.extend(sha1_smol::Sha1::from(&buf[..filled]).digest().bytes());
+        total_size += filled as u64;
+    }
+    if total_size == 0 {
+        return Err(easy_err("can't create a torrent of an empty file"));
+    }
+
+    use bencoding::Statement;
+    let info = Statement::Dictionary(HashMap::from([
+        (&b"name"[..], Statement::ByteString(name.as_bytes())),
+        (&b"length"[..], Statement::Integer(total_size as i64)),
+        (&b"piece length"[..], Statement::Integer(piece_len as i64)),
+        (&b"pieces"[..], Statement::ByteString(&pieces)),
+    ]));
+    Ok(wrap_info(info, trackers))
+}
+
+// Bencoded metainfo of an info dict fetched from peers, with the trackers of the magnet link
+pub fn metainfo_of_info(info: &[u8], trackers: &[String]) -> Result<Vec<u8>, io::Error> {
+    let (dict, len) = bencoding::parse_first(info).map_err(|e| easy_err(&e))?;
+    if len != info.len() || !matches!(dict, bencoding::Statement::Dictionary(_)) {
+        return Err(easy_err("info is not a single dict"));
+    }
+    Ok(wrap_info(dict, trackers))
+}
+
+fn wrap_info(info: bencoding::Statement, trackers: &[String]) -> Vec<u8> {
+    use bencoding::Statement;
+    let mut metainfo = HashMap::from([(&b"info"[..], info)]);
+    if let Some(first) = trackers.first() {
+        metainfo.insert(b"announce", Statement::ByteString(first.as_bytes()));
+        let tiers = trackers
+            .iter()
+            .map(|t| Statement::List(vec![Statement::ByteString(t.as_bytes())]))
+            .collect();
+        metainfo.insert(b"announce-list", Statement::List(tiers));
+    }
+    bencoding::marshal(&Statement::Dictionary(metainfo))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use bencoding::Statement;
+
+    // Zeroed hashes, tests override the fields they care about
+    pub fn single_file_torrent(total_size: u64, piece_len: u32) -> Torrent {
+        Torrent {
+            info_hash: [0; 20],
+            announce_urls: Vec::new(),
+            piece_len: piece_len,
+            piece_hashes: vec![[0; 20]; total_size.div_ceil(piece_len as u64) as usize],
+            total_size: total_size,
+            v2: None,
+            name: String::new(),
+            files: Vec::new(),
+            url_list: Vec::new(),
+            http_seeds: Vec::new(),
+        }
+    }
+
+    fn dict<'a>(entries: Vec<(&'a [u8], Statement<'a>)>) -> Statement<'a> {
+        Statement::Dictionary(entries.into_iter().collect())
+    }
+
+    #[test]
+    fn test_parse_v2() {
+        let piece_len = 2 * DEFAULT_BLOCK_LENGTH;
+        let a = vec![1_u8; 40000];
+        let blocks = merkle::block_hashes(&a);
+        let layer = vec![
+            merkle::root(&blocks[0..2], 2, merkle::ZERO_HASH),
+            merkle::root(&blocks[2..], 2, merkle::ZERO_HASH),
+        ];
+        let root_a = merkle::root(&layer, 2, merkle::pad_hash(1));
+        let root_b = merkle::hash_data(&[2; 100]);
+
+        let file = |len, root: &'static [u8]| {
+            dict(vec![(
+                b"",
+                dict(vec![
+                    (b"length", Statement::Integer(len)),
+                    (b"pieces root", Statement::ByteString(root)),
+                ]),
+            )])
+        };
+        let root_a: &'static [u8] = Box::leak(Box::new(root_a));
+        let root_b: &'static [u8] = Box::leak(Box::new(root_b));
+        let layer_bytes = layer.concat();
+        let info = dict(vec![
+            (b"name", Statement::ByteString(b"test")),
+            (b"meta version", Statement::Integer(2)),
+            (b"piece length", Statement::Integer(piece_len as i64)),
+            (
+                b"file tree",
+                dict(vec![(b"b", file(100, root_b)), (b"a", file(40000, root_a))]),
+            ),
+        ]);
+        let info_bytes = bencoding::marshal(&info);
+        let metainfo = dict(vec![
+            (b"announce", Statement::ByteString(b"udp://tracker")),
+            (b"info", info),
+            (
+                b"httpseeds",
+                Statement::List(vec![
+                    Statement::ByteString(b"http://seed/seed.php"),
+                    Statement::ByteString(b""),
+                ]),
+            ),
+            (
+                b"piece layers",
+                dict(vec![(root_a, Statement::ByteString(&layer_bytes))]),
+            ),
+        ]);
+
+        let t = Torrent::parse(bencoding::marshal(&metainfo)).unwrap();
+        let v2 = t.v2.as_ref().unwrap();
+        assert_eq!(t.info_hash, merkle::hash_data(&info_bytes)[..20]);
+        assert_eq!(v2.files[0].path, vec!["a"]);
+        assert_eq!(t.http_seeds, vec!["http://seed/seed.php"]);
+        assert!(t.url_list.is_empty());
+        assert_eq!(v2.files[1].first_piece, 2);
+        // Files start at piece boundaries
+        assert_eq!(t.total_size, 2 * piece_len as u64 + 100);
+        assert_eq!(t.get_piece_len(1), 40000 - piece_len);
+        assert_eq!(t.get_piece_len(2), 100);
+        assert_eq!(
+            t.piece_hash(1),
+            PieceHash::V2 {
+                root: layer[1],
+                len: 40000 - piece_len,
+                width: 2
+            }
+        );
+        assert_eq!(
+            t.piece_hash(2),
+            PieceHash::V2 {
+                root: root_b.try_into().unwrap(),
+                len: 100,
+                width: 1
+            }
+        );
+
+        let mut bad = bencoding::marshal(&metainfo);
+        let idx = bad
+            .windows(layer_bytes.len())
+            .position(|w| w == layer_bytes)
+            .unwrap();
+        bad[idx] ^= 1;
+        assert!(Torrent::parse(bad).is_err());
+
+        // Only empty files, there are no pieces at all
+        let empty_file = dict(vec![(b"", dict(vec![(b"length", Statement::Integer(0))]))]);
+        let empty = dict(vec![
+            (b"announce", Statement::ByteString(b"udp://tracker")),
+            (
+                b"info",
+                dict(vec![
+                    (b"name", Statement::ByteString(b"empty")),
+                    (b"meta version", Statement::Integer(2)),
+                    (b"piece length", Statement::Integer(piece_len as i64)),
+                    (b"file tree", dict(vec![(b"e", empty_file)])),
+                ]),
+            ),
+        ]);
+        assert!(Torrent::parse(bencoding::marshal(&empty)).is_err());
+    }
+
+    #[test]
+    fn test_create_metainfo() {
+        let data: Vec<u8> = (0..40000_u32).map(|i| i as u8).collect();
+        let trackers = vec!["udp://a:1".to_string(), "udp://b:2".to_string()];
+        let metainfo =
+            create_metainfo("test", &mut &data[..], &trackers, DEFAULT_BLOCK_LENGTH).unwrap();
+
+        let t = Torrent::parse(metainfo).unwrap();
+        assert_eq!(t.name, "test");
+        assert_eq!(t.announce_urls, trackers);
+        assert_eq!(t.total_size, 40000);
+        assert_eq!(t.get_total_piece_count(), 3);
+        assert_eq!(
+            t.piece_hash(2),
+            PieceHash::V1(
+                sha1_smol::Sha1::from(&data[2 * DEFAULT_BLOCK_LENGTH as usize..])
+                    .digest()
+                    .bytes()
+            )
+        );
+        assert!(create_metainfo("empty", &mut &[][..], &[], DEFAULT_BLOCK_LENGTH).is_err());
+
+        assert_eq!(default_piece_len(1000), DEFAULT_BLOCK_LENGTH);
+        assert_eq!(default_piece_len(1500 * 1024 * 1024), 1024 * 1024);
+        assert_eq!(default_piece_len(u64::MAX / 2), MAX_PIECE_LENGTH);
     }
 }
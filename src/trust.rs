@@ -0,0 +1,95 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+};
+
+// Peers are banned by ip after sending this many pieces that failed the hash check
+const MAX_HASH_FAILURES: u32 = 3;
+const BAN_TRUST_THRESHOLD: i32 = -7;
+const MAX_TRUST: i32 = 20;
+// A peer that sent every block of a bad piece is certainly at fault
+const SOLE_CONTRIBUTOR_PENALTY: i32 = 4;
+const SHARED_CONTRIBUTOR_PENALTY: i32 = 2;
+
+// Remembers how well the data of each peer ip verified, bans ips sending corrupt data
+pub struct PeerTrust {
+    records: HashMap<IpAddr, TrustRecord>,
+    banned: HashSet<IpAddr>,
+}
+
+#[derive(Default)]
+pub struct TrustRecord {
+    pub hash_failures: u32,
+    pub pieces_passed: u32,
+    pub trust: i32,
+}
+
+impl PeerTrust {
+    pub fn new() -> Self {
+        Self {
+            records: HashMap::new(),
+            banned: HashSet::new(),
+        }
+    }
+
+    pub fn piece_passed(&mut self, contributors: &[SocketAddr]) {
+        for addr in contributors {
+            let r = self.records.entry(addr.ip()).or_default();
+            r.pieces_passed += 1;
+            r.trust = (r.trust + 1).min(MAX_TRUST);
+        }
+    }
+
+    // Returns the ips that got banned because of this piece
+    pub fn piece_failed(&mut self, contributors: &[SocketAddr]) -> Vec<IpAddr> {
+        let penalty = match contributors.len() {
+            1 => SOLE_CONTRIBUTOR_PENALTY,
+            _ => SHARED_CONTRIBUTOR_PENALTY,
+        };
+
+        let mut newly_banned = Vec::new();
+        for addr in contributors {
+            let ip = addr.ip();
+            let r = self.records.entry(ip).or_default();
+            r.hash_failures += 1;
+            r.trust -= penalty;
+
+            if (r.hash_failures >= MAX_HASH_FAILURES || r.trust <= BAN_TRUST_THRESHOLD)
+                && self.banned.insert(ip)
+            {
+                newly_banned.push(ip);
+            }
+        }
+        newly_banned
+    }
+
+    pub fn is_banned(&self, ip: &IpAddr) -> bool {
+        self.banned.contains(ip)
+    }
+
+    pub fn get(&self, ip: &IpAddr) -> Option<&TrustRecord> {
+        self.records.get(ip)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ban_after_repeated_failures() {
+        let mut trust = PeerTrust::new();
+        let good: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let bad: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+
+        trust.piece_passed(&[good]);
+        assert!(trust.piece_failed(&[good, bad]).is_empty());
+        assert!(trust.piece_failed(&[bad]).is_empty());
+        assert_eq!(trust.piece_failed(&[bad]), vec![bad.ip()]);
+
+        assert!(trust.is_banned(&bad.ip()));
+        assert!(!trust.is_banned(&good.ip()));
+        assert_eq!(trust.get(&good.ip()).unwrap().trust, -1);
+        assert_eq!(trust.get(&bad.ip()).unwrap().hash_failures, 3);
+    }
+}
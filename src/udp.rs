@@ -5,25 +5,41 @@ use crate::peer;
 
 // https://www.bittorrent.org/beps/bep_0015.html
 
+pub const DEFAULT_TRACKER_TIMEOUT: Duration = Duration::from_secs(5);
+pub const EVENT_STARTED: u32 = 2;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
 pub struct Tracker {
     connection_id: Option<u64>,
     socket: UdpSocket,
+    // How long we wait for each response
+    timeout: Duration,
 
     downloaded: Option<u64>,
     left: Option<u64>,
     uploaded: Option<u64>,
 }
 
+#[derive(Debug, PartialEq)]
+pub struct ScrapeStats {
+    pub seeders: u32,
+    // Number of times the download was completed
+    pub completed: u32,
+    pub leechers: u32,
+}
+
 struct Packet<const PACKET_SIZE: usize> {
     tx_id: u32,
     bytes: [u8; PACKET_SIZE],
 }
 
 impl Tracker {
-    pub fn new() -> Result<Self, io::Error> {
+    pub fn new(timeout: Duration) -> Result<Self, io::Error> {
         Ok(Tracker {
             connection_id: None,
             socket: UdpSocket::bind("0.0.0.0:0")?,
+            timeout: timeout,
             downloaded: None,
             left: None,
             uploaded: None,
@@ -36,7 +52,7 @@ impl Tracker {
         let conn_packet = self.create_connect_packet();
         self.socket.send(&conn_packet.bytes)?;
 
-        self.socket.set_read_timeout(Some(Duration::from_secs(5)))?;
+        self.socket.set_read_timeout(Some(self.timeout))?;
 
         let mut buf = [0; 128];
         self.socket.recv(&mut buf)?;
@@ -45,7 +61,7 @@ impl Tracker {
         let tx_id = u32::from_be_bytes(buf[4..8].try_into().unwrap());
 
         if tx_id != conn_packet.tx_id {
-            return Err(io::Error::new(io::ErrorKind::Other, "got unexpected tx id"));
+            return Err(io::Error::other("got unexpected tx id"));
         }
 
         println!("received connection id {}", connection_id);
@@ -60,11 +76,13 @@ impl Tracker {
         info_hash: [u8; 20],
         peer_id: &[u8; 20],
         event: u32,
+        // The one we accept peer connections on
+        port: u16,
     ) -> Result<Vec<peer::Peer>, io::Error> {
-        let packet = self.create_announce_packet(info_hash, peer_id, event);
+        let packet = self.create_announce_packet(info_hash, peer_id, event, port);
         self.socket.send(&packet.bytes)?;
 
-        self.socket.set_read_timeout(Some(Duration::from_secs(5)))?;
+        self.socket.set_read_timeout(Some(self.timeout))?;
 
         let mut buf: [u8; 8192] = [0; 8192];
         let len_read = self.socket.recv(&mut buf)?;
@@ -73,13 +91,10 @@ impl Tracker {
         let action = u32::from_be_bytes(buf[0..4].try_into().unwrap());
 
         if action == 3 {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!(
-                    "got error response {}",
-                    str::from_utf8(&buf[8..256]).unwrap()
-                ),
-            ));
+            return Err(io::Error::other(format!(
+                "got error response {}",
+                str::from_utf8(&buf[8..256]).unwrap()
+            )));
         }
 
         println!("{:?}", &buf[..len_read]);
@@ -90,7 +105,7 @@ impl Tracker {
         let seeders = u32::from_be_bytes(buf[16..20].try_into().unwrap());
 
         if tx_id != packet.tx_id {
-            return Err(io::Error::new(io::ErrorKind::Other, "got unexpected tx id"));
+            return Err(io::Error::other("got unexpected tx id"));
         }
 
         // TODO: interval
@@ -117,6 +132,40 @@ impl Tracker {
         Ok(seeders)
     }
 
+    pub fn scrape(self: &mut Self, info_hash: [u8; 20]) -> Result<ScrapeStats, io::Error> {
+        let packet = self.create_scrape_packet(info_hash);
+        self.socket.send(&packet.bytes)?;
+
+        self.socket.set_read_timeout(Some(self.timeout))?;
+
+        let mut buf = [0; 512];
+        let len_read = self.socket.recv(&mut buf)?;
+        if len_read < 8 {
+            return Err(io::Error::other("scrape response is too short"));
+        }
+
+        let action = u32::from_be_bytes(buf[0..4].try_into().unwrap());
+        let tx_id = u32::from_be_bytes(buf[4..8].try_into().unwrap());
+        if tx_id != packet.tx_id {
+            return Err(io::Error::other("got unexpected tx id"));
+        }
+        if action == ACTION_ERROR {
+            return Err(io::Error::other(format!(
+                "got error response {}",
+                String::from_utf8_lossy(&buf[8..len_read])
+            )));
+        }
+        if action != ACTION_SCRAPE || len_read < 20 {
+            return Err(io::Error::other("got invalid scrape response"));
+        }
+
+        Ok(ScrapeStats {
+            seeders: u32::from_be_bytes(buf[8..12].try_into().unwrap()),
+            completed: u32::from_be_bytes(buf[12..16].try_into().unwrap()),
+            leechers: u32::from_be_bytes(buf[16..20].try_into().unwrap()),
+        })
+    }
+
     fn create_connect_packet(self: &mut Self) -> Packet<16> {
         const PROTOCOL_ID: u64 = 0x41727101980;
         let mut buf = [0; 16];
@@ -143,6 +192,7 @@ impl Tracker {
         info_hash: [u8; 20],
         peer_id: &[u8; 20],
         event: u32,
+        port: u16,
     ) -> Packet<98> {
         let mut buf = [0; 98];
 
@@ -173,7 +223,6 @@ impl Tracker {
         let numwant: i32 = -1;
         buf[92..96].copy_from_slice(&numwant.to_be_bytes());
 
-        let port: u16 = 6881;
         buf[96..98].copy_from_slice(&port.to_be_bytes());
 
         Packet {
@@ -181,4 +230,67 @@ impl Tracker {
             bytes: buf,
         }
     }
+
+    fn create_scrape_packet(self: &mut Self, info_hash: [u8; 20]) -> Packet<36> {
+        let mut buf = [0; 36];
+
+        buf[0..8].copy_from_slice(&self.connection_id.unwrap_or(0).to_be_bytes());
+        buf[8..12].copy_from_slice(&ACTION_SCRAPE.to_be_bytes());
+
+        let tx_id: u32 = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as u32;
+        buf[12..16].copy_from_slice(&tx_id.to_be_bytes());
+
+        buf[16..36].copy_from_slice(&info_hash);
+
+        Packet {
+            tx_id: tx_id,
+            bytes: buf,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scrape() {
+        // Answers a connect and a scrape request
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap().to_string();
+        let handle = std::thread::spawn(move || {
+            let mut buf = [0; 128];
+            let (_, from) = server.recv_from(&mut buf).unwrap();
+            let mut reply = vec![0, 0, 0, 0];
+            reply.extend(&buf[12..16]);
+            reply.extend(7_u64.to_be_bytes());
+            server.send_to(&reply, from).unwrap();
+
+            let (len, from) = server.recv_from(&mut buf).unwrap();
+            assert_eq!(len, 36);
+            assert_eq!(buf[0..8], 7_u64.to_be_bytes());
+            assert_eq!(buf[16..36], [1; 20]);
+            let mut reply = ACTION_SCRAPE.to_be_bytes().to_vec();
+            reply.extend(&buf[12..16]);
+            for n in [10_u32, 20, 30] {
+                reply.extend(n.to_be_bytes());
+            }
+            server.send_to(&reply, from).unwrap();
+        });
+
+        let mut t = Tracker::new(Duration::from_secs(1)).unwrap();
+        t.initiate(&addr).unwrap();
+        assert_eq!(
+            t.scrape([1; 20]).unwrap(),
+            ScrapeStats {
+                seeders: 10,
+                completed: 20,
+                leechers: 30
+            }
+        );
+        handle.join().unwrap();
+    }
 }
@@ -0,0 +1,963 @@
+use std::{
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
+    io,
+    net::SocketAddr,
+    rc::Rc,
+    time,
+};
+
+use mio::{Interest, Registry, Token, net::UdpSocket};
+
+use crate::util::easy_err;
+
+// https://www.bittorrent.org/beps/bep_0029.html
+// uTP runs reliable streams over a single UDP socket. The send window follows
+// LEDBAT, it shrinks as soon as our packets queue up anywhere on the path, so
+// uploads give way to other traffic on the link.
+const HEADER_LEN: usize = 20;
+const VERSION: u8 = 1;
+const ST_DATA: u8 = 0;
+const ST_FIN: u8 = 1;
+const ST_STATE: u8 = 2;
+const ST_RESET: u8 = 3;
+const ST_SYN: u8 = 4;
+const EXT_SELECTIVE_ACK: u8 = 1;
+// Payload of a data packet, keeps datagrams below common path MTUs
+const MAX_PAYLOAD: usize = 1380;
+// Queuing delay LEDBAT aims for
+const TARGET_DELAY_US: f64 = 100_000.0;
+// Window growth per round trip while there is no queuing delay at all
+const MAX_WINDOW_INCREASE: f64 = 3000.0;
+const MIN_WINDOW: f64 = MAX_PAYLOAD as f64;
+const MAX_WINDOW: f64 = 1024.0 * 1024.0;
+const INITIAL_WINDOW: f64 = 2.0 * MAX_PAYLOAD as f64;
+// Our advertised receive window and the most we buffer for sending
+const RECV_WINDOW: usize = 1024 * 1024;
+const SEND_BUFFER: usize = 1024 * 1024;
+const MIN_RTO: time::Duration = time::Duration::from_millis(500);
+const INITIAL_RTO: time::Duration = time::Duration::from_secs(1);
+const MAX_RTO: time::Duration = time::Duration::from_secs(30);
+// The connection fails once a packet was sent this often without an ack
+const MAX_TRANSMISSIONS: u32 = 6;
+// Packets sacked behind an unacked one before it is considered lost
+const DUPLICATE_ACKS: u32 = 3;
+// The base delay is the lowest delay seen in the last two of these
+const BASE_DELAY_INTERVAL: time::Duration = time::Duration::from_secs(60);
+// Closed connections wait this long for their FIN to be acked
+const LINGER_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
+struct Packet {
+    kind: u8,
+    conn_id: u16,
+    timestamp: u32,
+    timestamp_diff: u32,
+    wnd: u32,
+    seq: u16,
+    ack: u16,
+    // Bit i acks seq ack + 2 + i
+    sack: Option<Vec<u8>>,
+    payload: Vec<u8>,
+}
+
+impl Packet {
+    fn parse(buf: &[u8]) -> Option<Packet> {
+        if buf.len() < HEADER_LEN || buf[0] & 0x0f != VERSION || buf[0] >> 4 > ST_SYN {
+            return None;
+        }
+        let u16_at = |i: usize| u16::from_be_bytes(buf[i..i + 2].try_into().unwrap());
+        let u32_at = |i: usize| u32::from_be_bytes(buf[i..i + 4].try_into().unwrap());
+
+        let mut sack = None;
+        let mut ext = buf[1];
+        let mut i = HEADER_LEN;
+        while ext != 0 {
+            if buf.len() < i + 2 || buf.len() < i + 2 + buf[i + 1] as usize {
+                return None;
+            }
+            let len = buf[i + 1] as usize;
+            if ext == EXT_SELECTIVE_ACK {
+                sack = Some(buf[i + 2..i + 2 + len].to_vec());
+            }
+            ext = buf[i];
+            i += 2 + len;
+        }
+
+        Some(Packet {
+            kind: buf[0] >> 4,
+            conn_id: u16_at(2),
+            timestamp: u32_at(4),
+            timestamp_diff: u32_at(8),
+            wnd: u32_at(12),
+            seq: u16_at(16),
+            ack: u16_at(18),
+            sack: sack,
+            payload: buf[i..].to_vec(),
+        })
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut b = Vec::with_capacity(HEADER_LEN + self.payload.len());
+        b.push(self.kind << 4 | VERSION);
+        b.push(if self.sack.is_some() {
+            EXT_SELECTIVE_ACK
+        } else {
+            0
+        });
+        b.extend(self.conn_id.to_be_bytes());
+        b.extend(self.timestamp.to_be_bytes());
+        b.extend(self.timestamp_diff.to_be_bytes());
+        b.extend(self.wnd.to_be_bytes());
+        b.extend(self.seq.to_be_bytes());
+        b.extend(self.ack.to_be_bytes());
+        if let Some(sack) = &self.sack {
+            b.push(0);
+            b.push(sack.len() as u8);
+            b.extend(sack);
+        }
+        b.extend(&self.payload);
+        b
+    }
+}
+
+// Whether a comes before b, sequence numbers wrap around
+fn seq_before(a: u16, b: u16) -> bool {
+    a != b && b.wrapping_sub(a) < 0x8000
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    SynSent,
+    Connected,
+    // Reset by the peer or timed out
+    Failed(io::ErrorKind),
+}
+
+// A packet waiting for its ack
+struct Sent {
+    kind: u8,
+    seq: u16,
+    payload: Vec<u8>,
+    sent_at: time::Instant,
+    transmissions: u32,
+    // Packets acked behind this one
+    acked_after: u32,
+    resend: bool,
+}
+
+struct Conn {
+    addr: SocketAddr,
+    recv_id: u16,
+    send_id: u16,
+    token: Option<Token>,
+    state: State,
+    // Next sequence number we send
+    seq_nr: u16,
+    // Last sequence number we received in order
+    ack_nr: u16,
+    in_flight: VecDeque<Sent>,
+    send_buf: VecDeque<u8>,
+    recv_buf: VecDeque<u8>,
+    out_of_order: HashMap<u16, Vec<u8>>,
+    // Sequence number of the peer's FIN
+    fin_seq: Option<u16>,
+    eof: bool,
+    // Set once the stream was shut down on our side, the FIN goes out after the queued data
+    closing: bool,
+    fin_sent: bool,
+    // The stream was dropped, the connection goes away once its FIN is acked
+    orphaned: bool,
+
+    window: f64,
+    slow_start: bool,
+    peer_window: usize,
+    // Sequence number sent last when the window was cut, one cut per round trip
+    cut_at: Option<u16>,
+    rtt: Option<time::Duration>,
+    rtt_var: time::Duration,
+    rto: time::Duration,
+    // Delay of the last packet we received, echoed back to the peer
+    reply_delay: u32,
+    base_delays: [u32; 2],
+    base_delay_rotated_at: time::Instant,
+    closed_at: Option<time::Instant>,
+
+    ack_needed: bool,
+    // Readable, writable or failed since the last notification
+    changed: bool,
+}
+
+impl Conn {
+    fn new(addr: SocketAddr, recv_id: u16, send_id: u16, seq_nr: u16) -> Self {
+        let now = time::Instant::now();
+        Conn {
+            addr: addr,
+            recv_id: recv_id,
+            send_id: send_id,
+            token: None,
+            state: State::SynSent,
+            seq_nr: seq_nr,
+            ack_nr: 0,
+            in_flight: VecDeque::new(),
+            send_buf: VecDeque::new(),
+            recv_buf: VecDeque::new(),
+            out_of_order: HashMap::new(),
+            fin_seq: None,
+            eof: false,
+            closing: false,
+            fin_sent: false,
+            orphaned: false,
+            window: INITIAL_WINDOW,
+            slow_start: true,
+            peer_window: RECV_WINDOW,
+            cut_at: None,
+            rtt: None,
+            rtt_var: time::Duration::ZERO,
+            rto: INITIAL_RTO,
+            reply_delay: 0,
+            base_delays: [u32::MAX; 2],
+            base_delay_rotated_at: now,
+            closed_at: None,
+            ack_needed: false,
+            changed: false,
+        }
+    }
+
+    fn bytes_in_flight(&self) -> usize {
+        self.in_flight.iter().map(|s| s.payload.len()).sum()
+    }
+
+    fn on_packet(self: &mut Self, p: Packet, now_us: u32) {
+        self.reply_delay = now_us.wrapping_sub(p.timestamp);
+        self.peer_window = p.wnd as usize;
+
+        match p.kind {
+            ST_RESET => {
+                self.state = State::Failed(io::ErrorKind::ConnectionReset);
+                self.changed = true;
+                return;
+            }
+            // A retransmitted SYN, our ack got lost
+            ST_SYN => {
+                self.ack_needed = true;
+                return;
+            }
+            ST_STATE if self.state == State::SynSent => {
+                // The seq of the ack is the first one the peer sends data with
+                self.ack_nr = p.seq.wrapping_sub(1);
+                self.state = State::Connected;
+                self.changed = true;
+            }
+            _ => {}
+        }
+
+        self.on_ack(&p);
+
+        if p.kind == ST_FIN {
+            self.fin_seq = Some(p.seq);
+            self.ack_needed = true;
+        }
+        if p.kind == ST_DATA && !p.payload.is_empty() {
+            self.ack_needed = true;
+            if seq_before(self.ack_nr, p.seq) && self.recv_buf.len() < RECV_WINDOW {
+                self.out_of_order.insert(p.seq, p.payload);
+            }
+        }
+        self.deliver();
+    }
+
+    // Moves in order data to the receive buffer
+    fn deliver(self: &mut Self) {
+        loop {
+            let next = self.ack_nr.wrapping_add(1);
+            if let Some(data) = self.out_of_order.remove(&next) {
+                self.recv_buf.extend(data);
+                self.ack_nr = next;
+                self.changed = true;
+            } else if self.fin_seq == Some(next) {
+                self.ack_nr = next;
+                self.eof = true;
+                self.changed = true;
+            } else {
+                return;
+            }
+        }
+    }
+
+    fn on_ack(self: &mut Self, p: &Packet) {
+        let now = time::Instant::now();
+        let mut acked_bytes = 0;
+        let mut rtt_sample = None;
+        let mut acked = |s: &Sent| {
+            acked_bytes += s.payload.len();
+            // Karn's algorithm, retransmitted packets don't tell the round trip time
+            if s.transmissions == 1 {
+                rtt_sample = Some(now - s.sent_at);
+            }
+        };
+
+        while let Some(s) = self.in_flight.front() {
+            if seq_before(p.ack, s.seq) {
+                break;
+            }
+            acked(&self.in_flight.pop_front().unwrap());
+        }
+
+        if let Some(sack) = &p.sack {
+            let is_sacked = |seq: u16| {
+                let i = seq.wrapping_sub(p.ack).wrapping_sub(2) as usize;
+                i < sack.len() * 8 && sack[i / 8] & (1 << (i % 8)) != 0
+            };
+            let mut kept = VecDeque::new();
+            let mut sacked = 0;
+            // Walk from the newest packet so that we know how many were acked behind each one
+            while let Some(mut s) = self.in_flight.pop_back() {
+                if is_sacked(s.seq) {
+                    acked(&s);
+                    sacked += 1;
+                    continue;
+                }
+                s.acked_after = sacked;
+                kept.push_front(s);
+            }
+            self.in_flight = kept;
+        }
+
+        if let Some(sample) = rtt_sample {
+            self.update_rtt(sample);
+        }
+        if acked_bytes > 0 {
+            self.changed = true;
+            self.update_window(acked_bytes, p.timestamp_diff);
+        }
+
+        // Fast retransmit of packets the peer skipped
+        let mut lost = false;
+        for s in &mut self.in_flight {
+            if s.acked_after >= DUPLICATE_ACKS && s.transmissions == 1 && !s.resend {
+                s.resend = true;
+                lost = true;
+            }
+        }
+        if lost {
+            self.on_loss();
+        }
+    }
+
+    fn update_rtt(self: &mut Self, sample: time::Duration) {
+        match self.rtt {
+            None => {
+                self.rtt = Some(sample);
+                self.rtt_var = sample / 2;
+            }
+            Some(rtt) => {
+                let diff = rtt.abs_diff(sample);
+                self.rtt_var = (self.rtt_var * 3 + diff) / 4;
+                self.rtt = Some((rtt * 7 + sample) / 8);
+            }
+        }
+        self.rto = (self.rtt.unwrap() + self.rtt_var * 4).clamp(MIN_RTO, MAX_RTO);
+    }
+
+    // LEDBAT, grows the window while the queuing delay is below target and shrinks it above
+    fn update_window(self: &mut Self, acked_bytes: usize, delay: u32) {
+        if self.base_delay_rotated_at.elapsed() >= BASE_DELAY_INTERVAL {
+            self.base_delays = [self.base_delays[1], u32::MAX];
+            self.base_delay_rotated_at = time::Instant::now();
+        }
+        let mut queuing_delay = 0.0;
+        if delay > 0 {
+            self.base_delays[1] = self.base_delays[1].min(delay);
+            let base = self.base_delays[0].min(self.base_delays[1]);
+            queuing_delay = delay.saturating_sub(base) as f64;
+        }
+
+        if self.slow_start && queuing_delay < TARGET_DELAY_US / 2.0 {
+            self.window += acked_bytes as f64;
+        } else {
+            self.slow_start = false;
+            let off_target = (TARGET_DELAY_US - queuing_delay) / TARGET_DELAY_US;
+            self.window += MAX_WINDOW_INCREASE * off_target * acked_bytes as f64 / self.window;
+        }
+        self.window = self.window.clamp(MIN_WINDOW, MAX_WINDOW);
+    }
+
+    fn on_loss(self: &mut Self) {
+        if self
+            .cut_at
+            .is_some_and(|s| seq_before(self.ack_nr_sent(), s))
+        {
+            return;
+        }
+        self.cut_at = Some(self.seq_nr);
+        self.slow_start = false;
+        self.window = (self.window / 2.0).max(MIN_WINDOW);
+    }
+
+    // Highest seq the peer acked, the oldest packet in flight is the next one
+    fn ack_nr_sent(&self) -> u16 {
+        match self.in_flight.front() {
+            Some(s) => s.seq.wrapping_sub(1),
+            None => self.seq_nr.wrapping_sub(1),
+        }
+    }
+
+    fn on_timeout(self: &mut Self) {
+        let s = match self.in_flight.front_mut() {
+            Some(s) if s.sent_at.elapsed() >= self.rto => s,
+            _ => return,
+        };
+        if s.transmissions >= MAX_TRANSMISSIONS {
+            self.state = State::Failed(io::ErrorKind::TimedOut);
+            self.changed = true;
+            return;
+        }
+        s.resend = true;
+        self.window = MIN_WINDOW;
+        self.slow_start = false;
+        self.rto = (self.rto * 2).min(MAX_RTO);
+    }
+
+    fn header(&self, kind: u8, seq: u16, now_us: u32) -> Packet {
+        Packet {
+            kind: kind,
+            conn_id: if kind == ST_SYN {
+                self.recv_id
+            } else {
+                self.send_id
+            },
+            timestamp: now_us,
+            timestamp_diff: self.reply_delay,
+            wnd: RECV_WINDOW.saturating_sub(self.recv_buf.len()) as u32,
+            seq: seq,
+            ack: self.ack_nr,
+            sack: None,
+            payload: Vec::new(),
+        }
+    }
+
+    // Bit i is set if ack_nr + 2 + i arrived
+    fn selective_ack(&self) -> Option<Vec<u8>> {
+        let offsets: Vec<usize> = self
+            .out_of_order
+            .keys()
+            .map(|s| s.wrapping_sub(self.ack_nr).wrapping_sub(2) as usize)
+            .filter(|i| *i < 256)
+            .collect();
+        let max = offsets.iter().max()?;
+        // Multiple of 4 bytes
+        let mut mask = vec![0_u8; (max / 32 + 1) * 4];
+        for i in offsets {
+            mask[i / 8] |= 1 << (i % 8);
+        }
+        Some(mask)
+    }
+
+    // Sends what the windows allow, retransmissions and acks
+    fn flush(self: &mut Self, socket: &UdpSocket, now_us: u32) {
+        let mut sent_any = false;
+        let now = time::Instant::now();
+
+        for i in 0..self.in_flight.len() {
+            if !self.in_flight[i].resend {
+                continue;
+            }
+            let s = &self.in_flight[i];
+            let mut p = self.header(s.kind, s.seq, now_us);
+            p.payload = s.payload.clone();
+            send(socket, self.addr, &p);
+            let s = &mut self.in_flight[i];
+            s.resend = false;
+            s.transmissions += 1;
+            s.sent_at = now;
+            sent_any = true;
+        }
+
+        if self.state == State::Connected {
+            let window = (self.window as usize).min(self.peer_window);
+            while !self.send_buf.is_empty() {
+                let len = self.send_buf.len().min(MAX_PAYLOAD);
+                let in_flight = self.bytes_in_flight();
+                // One packet may always be in flight, otherwise a zero window would never reopen
+                if in_flight > 0 && in_flight + len > window {
+                    break;
+                }
+                let payload: Vec<u8> = self.send_buf.drain(..len).collect();
+                self.send_new(socket, ST_DATA, payload, now_us);
+                sent_any = true;
+            }
+            if self.closing && !self.fin_sent && self.send_buf.is_empty() {
+                self.send_new(socket, ST_FIN, Vec::new(), now_us);
+                self.fin_sent = true;
+                sent_any = true;
+            }
+        }
+
+        let sack = self.selective_ack();
+        if self.ack_needed && (!sent_any || sack.is_some()) {
+            let mut p = self.header(ST_STATE, self.seq_nr, now_us);
+            p.sack = sack;
+            send(socket, self.addr, &p);
+        }
+        self.ack_needed = false;
+    }
+
+    fn send_new(self: &mut Self, socket: &UdpSocket, kind: u8, payload: Vec<u8>, now_us: u32) {
+        let mut p = self.header(kind, self.seq_nr, now_us);
+        p.payload = payload;
+        send(socket, self.addr, &p);
+        self.in_flight.push_back(Sent {
+            kind: kind,
+            seq: self.seq_nr,
+            payload: p.payload,
+            sent_at: time::Instant::now(),
+            transmissions: 1,
+            acked_after: 0,
+            resend: false,
+        });
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+    }
+
+    // Orphaned connections stay until their data and FIN are acked
+    fn is_done(&self) -> bool {
+        if let State::Failed(_) = self.state {
+            return self.orphaned;
+        }
+        self.orphaned
+            && (self.in_flight.is_empty() && self.send_buf.is_empty()
+                || self
+                    .closed_at
+                    .is_some_and(|t| t.elapsed() >= LINGER_TIMEOUT))
+    }
+}
+
+// Lost packets are noticed by the missing acks
+fn send(socket: &UdpSocket, addr: SocketAddr, p: &Packet) {
+    if let Err(e) = socket.send_to(&p.to_bytes(), addr)
+        && e.kind() != io::ErrorKind::WouldBlock
+    {
+        println!("failed to send utp packet to {} {:?}", addr, e);
+    }
+}
+
+fn random_u16() -> u16 {
+    let mut b = [0_u8; 2];
+    getrandom::getrandom(&mut b).expect("no randomness for utp");
+    u16::from_be_bytes(b)
+}
+
+pub type SharedUtpSocket = Rc<RefCell<UtpSocket>>;
+
+// Owns the UDP socket and every uTP connection on it. Connections have no
+// events of their own, the socket reports the tokens of the ones that changed.
+pub struct UtpSocket {
+    socket: UdpSocket,
+    started_at: time::Instant,
+    // By remote address and the id of the packets we receive
+    conns: HashMap<(SocketAddr, u16), Conn>,
+    // Incoming connections no stream was created for yet
+    accepted: VecDeque<(SocketAddr, u16)>,
+}
+
+impl UtpSocket {
+    pub fn bind(addr: SocketAddr) -> Result<Self, io::Error> {
+        let socket = UdpSocket::bind(addr)?;
+        Ok(UtpSocket {
+            socket: socket,
+            started_at: time::Instant::now(),
+            conns: HashMap::new(),
+            accepted: VecDeque::new(),
+        })
+    }
+
+    pub fn register(self: &mut Self, registry: &Registry, token: Token) -> Result<(), io::Error> {
+        registry.register(&mut self.socket, token, Interest::READABLE)
+    }
+
+    // The session binds the same port as its listener, only tests ask for it
+    #[cfg(test)]
+    pub fn local_addr(&self) -> Result<SocketAddr, io::Error> {
+        self.socket.local_addr()
+    }
+
+    fn now_us(&self) -> u32 {
+        self.started_at.elapsed().as_micros() as u32
+    }
+
+    // Reads every waiting datagram, returns the tokens of the connections that changed
+    pub fn receive(self: &mut Self) -> Vec<Token> {
+        let mut buf = [0_u8; 2048];
+        let mut touched = Vec::new();
+        loop {
+            let (n, addr) = match self.socket.recv_from(&mut buf) {
+                Ok(r) => r,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                // i.e. icmp port unreachable of an earlier send
+                Err(_) => continue,
+            };
+            let p = match Packet::parse(&buf[..n]) {
+                Some(p) => p,
+                None => continue,
+            };
+            if let Some(key) = self.on_packet(addr, p)
+                && !touched.contains(&key)
+            {
+                touched.push(key);
+            }
+        }
+
+        let now_us = self.now_us();
+        for key in &touched {
+            if let Some(c) = self.conns.get_mut(key) {
+                c.flush(&self.socket, now_us);
+            }
+        }
+        self.take_changed()
+    }
+
+    fn on_packet(self: &mut Self, addr: SocketAddr, p: Packet) -> Option<(SocketAddr, u16)> {
+        let now_us = self.now_us();
+        if p.kind == ST_SYN {
+            let key = (addr, p.conn_id.wrapping_add(1));
+            // A repeated SYN means our ack was lost
+            let c = self.conns.entry(key).or_insert_with(|| {
+                let mut c = Conn::new(addr, key.1, p.conn_id, random_u16());
+                c.state = State::Connected;
+                c.ack_nr = p.seq;
+                self.accepted.push_back(key);
+                c
+            });
+            c.reply_delay = now_us.wrapping_sub(p.timestamp);
+            c.ack_needed = true;
+            return Some(key);
+        }
+
+        let key = (addr, p.conn_id);
+        match self.conns.get_mut(&key) {
+            Some(c) => {
+                c.on_packet(p, now_us);
+                Some(key)
+            }
+            None => {
+                if p.kind != ST_RESET {
+                    let mut reset = Conn::new(addr, p.conn_id, p.conn_id, 0).header(
+                        ST_RESET,
+                        random_u16(),
+                        now_us,
+                    );
+                    reset.ack = p.seq;
+                    send(&self.socket, addr, &reset);
+                }
+                None
+            }
+        }
+    }
+
+    // Retransmits and times out connections, returns the tokens of the ones that changed
+    pub fn tick(self: &mut Self) -> Vec<Token> {
+        let now_us = self.now_us();
+        for c in self.conns.values_mut() {
+            c.on_timeout();
+            c.flush(&self.socket, now_us);
+        }
+        self.conns.retain(|_, c| !c.is_done());
+        self.take_changed()
+    }
+
+    fn take_changed(self: &mut Self) -> Vec<Token> {
+        let mut tokens = Vec::new();
+        for c in self.conns.values_mut() {
+            if c.changed && !c.orphaned {
+                c.changed = false;
+                if let Some(t) = c.token {
+                    tokens.push(t);
+                }
+            }
+        }
+        tokens
+    }
+}
+
+// One connection of the shared socket, reads and writes never block
+pub struct UtpStream {
+    socket: SharedUtpSocket,
+    key: (SocketAddr, u16),
+}
+
+impl UtpStream {
+    // The connection is writable once the peer acked the SYN
+    pub fn connect(
+        socket: &SharedUtpSocket,
+        addr: SocketAddr,
+        token: Token,
+    ) -> Result<Self, io::Error> {
+        let mut s = socket.borrow_mut();
+        let mut recv_id = random_u16();
+        while s.conns.contains_key(&(addr, recv_id)) {
+            recv_id = random_u16();
+        }
+
+        let mut c = Conn::new(addr, recv_id, recv_id.wrapping_add(1), 1);
+        c.token = Some(token);
+        let now_us = s.now_us();
+        c.send_new(&s.socket, ST_SYN, Vec::new(), now_us);
+        s.conns.insert((addr, recv_id), c);
+
+        Ok(UtpStream {
+            socket: socket.clone(),
+            key: (addr, recv_id),
+        })
+    }
+
+    pub fn accept(socket: &SharedUtpSocket) -> Option<Self> {
+        let key = socket.borrow_mut().accepted.pop_front()?;
+        Some(UtpStream {
+            socket: socket.clone(),
+            key: key,
+        })
+    }
+
+    pub fn set_token(&self, token: Token) {
+        if let Some(c) = self.socket.borrow_mut().conns.get_mut(&self.key) {
+            c.token = Some(token);
+        }
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.key.0
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.socket
+            .borrow()
+            .conns
+            .get(&self.key)
+            .is_some_and(|c| c.state == State::Connected)
+    }
+
+    pub fn take_error(&self) -> Option<io::Error> {
+        match self.socket.borrow().conns.get(&self.key) {
+            Some(c) => match c.state {
+                State::Failed(kind) => Some(io::Error::from(kind)),
+                _ => None,
+            },
+            None => Some(io::Error::from(io::ErrorKind::NotConnected)),
+        }
+    }
+
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        let mut s = self.socket.borrow_mut();
+        let now_us = s.now_us();
+        let UtpSocket { socket, conns, .. } = &mut *s;
+        let c = conns
+            .get_mut(&self.key)
+            .ok_or(io::Error::from(io::ErrorKind::NotConnected))?;
+        if c.recv_buf.is_empty() {
+            if let State::Failed(kind) = c.state {
+                return Err(io::Error::from(kind));
+            }
+            if c.eof {
+                return Ok(0);
+            }
+            return Err(io::Error::from(io::ErrorKind::WouldBlock));
+        }
+
+        let window_was_full = c.recv_buf.len() >= RECV_WINDOW;
+        let n = buf.len().min(c.recv_buf.len());
+        for (b, d) in buf.iter_mut().zip(c.recv_buf.drain(..n)) {
+            *b = d;
+        }
+        // Tells the peer about the reopened window
+        if window_was_full {
+            c.ack_needed = true;
+            c.flush(socket, now_us);
+        }
+        Ok(n)
+    }
+
+    pub fn write(&self, buf: &[u8]) -> Result<usize, io::Error> {
+        let mut s = self.socket.borrow_mut();
+        let now_us = s.now_us();
+        let UtpSocket { socket, conns, .. } = &mut *s;
+        let c = conns
+            .get_mut(&self.key)
+            .ok_or(io::Error::from(io::ErrorKind::NotConnected))?;
+        match c.state {
+            State::Failed(kind) => return Err(io::Error::from(kind)),
+            State::SynSent => return Err(io::Error::from(io::ErrorKind::WouldBlock)),
+            State::Connected if c.closing => return Err(easy_err("utp stream is shut down")),
+            State::Connected => {}
+        }
+
+        let n = buf.len().min(SEND_BUFFER - c.send_buf.len());
+        if n == 0 {
+            return Err(io::Error::from(io::ErrorKind::WouldBlock));
+        }
+        c.send_buf.extend(&buf[..n]);
+        c.flush(socket, now_us);
+        Ok(n)
+    }
+
+    // Sends a FIN once the queued data is out
+    pub fn shutdown(&self) {
+        let mut s = self.socket.borrow_mut();
+        let now_us = s.now_us();
+        let UtpSocket { socket, conns, .. } = &mut *s;
+        if let Some(c) = conns.get_mut(&self.key)
+            && !c.closing
+        {
+            c.closing = true;
+            c.closed_at = Some(time::Instant::now());
+            c.flush(socket, now_us);
+        }
+    }
+}
+
+impl Drop for UtpStream {
+    fn drop(&mut self) {
+        self.shutdown();
+        if let Some(c) = self.socket.borrow_mut().conns.get_mut(&self.key) {
+            c.orphaned = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn socket() -> SharedUtpSocket {
+        Rc::new(RefCell::new(
+            UtpSocket::bind(SocketAddr::from(([0, 0, 0, 0], 0))).unwrap(),
+        ))
+    }
+
+    fn local(s: &SharedUtpSocket) -> SocketAddr {
+        let port = s.borrow().local_addr().unwrap().port();
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn pump(a: &SharedUtpSocket, b: &SharedUtpSocket) {
+        std::thread::sleep(time::Duration::from_millis(1));
+        a.borrow_mut().receive();
+        b.borrow_mut().receive();
+        a.borrow_mut().tick();
+        b.borrow_mut().tick();
+    }
+
+    #[test]
+    fn test_transfer() {
+        let (a, b) = (socket(), socket());
+        let client = UtpStream::connect(&a, local(&b), Token(7)).unwrap();
+        let mut server = None;
+        for _ in 0..100 {
+            pump(&a, &b);
+            if server.is_none() {
+                server = UtpStream::accept(&b);
+            }
+            if client.is_connected() && server.is_some() {
+                break;
+            }
+        }
+        let server = server.unwrap();
+        assert_eq!(server.peer_addr(), local(&a));
+
+        // More than fits in the initial window, both ways
+        let data: Vec<u8> = (0..300_000).map(|i| (i * 31 % 251) as u8).collect();
+        let (mut sent, mut got, mut back) = (0, Vec::<u8>::new(), Vec::<u8>::new());
+        let mut buf = [0_u8; 8192];
+        for _ in 0..5000 {
+            if sent < data.len()
+                && let Ok(n) = client.write(&data[sent..])
+            {
+                sent += n;
+            }
+            if sent == data.len() {
+                client.shutdown();
+            }
+            pump(&a, &b);
+            while let Ok(n) = server.read(&mut buf) {
+                if n == 0 {
+                    break;
+                }
+                got.extend(&buf[..n]);
+                let _ = server.write(&buf[..n]);
+            }
+            while let Ok(n) = client.read(&mut buf) {
+                if n == 0 {
+                    break;
+                }
+                back.extend(&buf[..n]);
+            }
+            if back.len() == data.len() {
+                break;
+            }
+        }
+        assert!(got == data);
+        assert!(back == data);
+        // The FIN arrived after the data
+        assert_eq!(server.read(&mut buf).unwrap(), 0);
+        assert!(server.take_error().is_none());
+    }
+
+    #[test]
+    fn test_selective_ack() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 1));
+        let mut c = Conn::new(addr, 1, 2, 1);
+        c.state = State::Connected;
+        c.ack_nr = 9;
+        let packet = |seq, payload: &[u8]| Packet {
+            kind: ST_DATA,
+            conn_id: 1,
+            timestamp: 0,
+            timestamp_diff: 0,
+            wnd: RECV_WINDOW as u32,
+            seq: seq,
+            ack: 0,
+            sack: None,
+            payload: payload.to_vec(),
+        };
+
+        // 10 is missing, 11 and 13 are acked selectively
+        c.on_packet(packet(11, b"b"), 0);
+        c.on_packet(packet(13, b"d"), 0);
+        assert!(c.recv_buf.is_empty());
+        assert_eq!(c.selective_ack().unwrap(), vec![0b101, 0, 0, 0]);
+        c.on_packet(packet(10, b"a"), 0);
+        c.on_packet(packet(12, b"c"), 0);
+        assert_eq!(c.recv_buf, b"abcd");
+        assert_eq!(c.ack_nr, 13);
+        assert!(c.selective_ack().is_none());
+
+        // The header survives a round trip
+        let mut p = packet(5, b"x");
+        p.sack = Some(vec![1, 2, 3, 4]);
+        let parsed = Packet::parse(&p.to_bytes()).unwrap();
+        assert_eq!(parsed.sack, p.sack);
+        assert_eq!(parsed.payload, b"x");
+        assert_eq!(parsed.seq, 5);
+
+        // Three packets sacked behind an unacked one trigger a resend
+        for seq in 1..6 {
+            c.in_flight.push_back(Sent {
+                kind: ST_DATA,
+                seq: seq,
+                payload: vec![0; 100],
+                sent_at: time::Instant::now(),
+                transmissions: 1,
+                acked_after: 0,
+                resend: false,
+            });
+        }
+        let window = c.window;
+        let mut ack = packet(0, b"");
+        ack.kind = ST_STATE;
+        ack.ack = 1;
+        ack.sack = Some(vec![0b111, 0, 0, 0]);
+        c.on_packet(ack, 0);
+        assert_eq!(c.in_flight.len(), 1);
+        assert!(c.in_flight[0].resend);
+        assert!(c.window < window);
+    }
+}
@@ -0,0 +1,400 @@
+use std::{io, net::SocketAddr, sync::mpsc, thread, time};
+
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, percent_encode, utf8_percent_encode};
+
+use crate::{
+    http,
+    torrent::{Torrent, TorrentFile},
+    util::easy_err,
+};
+
+const WEB_SEED_TIMEOUT: time::Duration = time::Duration::from_secs(30);
+// Waiting time after the first failure, doubled with every further one
+const MIN_BACKOFF: time::Duration = time::Duration::from_secs(10);
+const MAX_BACKOFF: time::Duration = time::Duration::from_secs(600);
+// Characters of file names that are kept as they are in urls
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+// Part of a piece that lies in a single file, pad files have no url
+struct FileRange {
+    url: Option<String>,
+    offset: u64,
+    len: u64,
+}
+
+enum Request {
+    Files(Vec<FileRange>),
+    // Full url of the piece and its length
+    Piece(String, u64),
+}
+
+struct WebSeedJob {
+    piece: u32,
+    request: Request,
+}
+
+// Address of the server and the piece data
+type PieceData = Result<(SocketAddr, Vec<u8>), io::Error>;
+
+pub struct WebSeedResult {
+    pub piece: u32,
+    pub result: PieceData,
+    // Set when a busy server asked to come back later
+    retry_after: Option<time::Duration>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum WebSeedKind {
+    // https://www.bittorrent.org/beps/bep_0019.html
+    UrlList,
+    // https://www.bittorrent.org/beps/bep_0017.html
+    HttpSeed,
+}
+
+// Downloads whole pieces from an http server, either a plain one with range
+// requests or a script that serves pieces by info hash. Requests run on the
+// seed's own thread, the pool collects the results in its tick.
+pub struct WebSeed {
+    pub url: String,
+    kind: WebSeedKind,
+    jobs: mpsc::Sender<WebSeedJob>,
+    done: mpsc::Receiver<WebSeedResult>,
+    // Piece being downloaded
+    piece: Option<u32>,
+    failures: u32,
+    retry_at: Option<time::Instant>,
+}
+
+impl WebSeed {
+    pub fn new(url: String, kind: WebSeedKind) -> Self {
+        let (jobs, rx) = mpsc::channel::<WebSeedJob>();
+        let (done_tx, done) = mpsc::channel();
+
+        // Ends once the seed is dropped
+        thread::spawn(move || {
+            for job in rx {
+                let (result, retry_after) = match &job.request {
+                    Request::Files(ranges) => (fetch(ranges), None),
+                    Request::Piece(url, len) => fetch_piece(url, *len),
+                };
+                let result = WebSeedResult {
+                    piece: job.piece,
+                    result: result,
+                    retry_after: retry_after,
+                };
+                if done_tx.send(result).is_err() {
+                    return;
+                }
+            }
+        });
+
+        Self {
+            url: url,
+            kind: kind,
+            jobs: jobs,
+            done: done,
+            piece: None,
+            failures: 0,
+            retry_at: None,
+        }
+    }
+
+    // Whether a piece can be requested, the seed might be backing off
+    pub fn is_ready(&self) -> bool {
+        self.piece.is_none() && self.retry_at.is_none_or(|t| time::Instant::now() >= t)
+    }
+
+    pub fn download(self: &mut Self, torrent: &Torrent, piece: u32) {
+        let len = torrent.get_piece_len(piece) as u64;
+        let request = match self.kind {
+            WebSeedKind::UrlList => {
+                let offset = piece as u64 * torrent.piece_len as u64;
+                let ranges = torrent
+                    .file_ranges(offset, len)
+                    .into_iter()
+                    .map(|(i, offset, len)| {
+                        let f = &torrent.files[i];
+                        FileRange {
+                            url: (!f.pad).then(|| file_url(&self.url, f)),
+                            offset: offset,
+                            len: len,
+                        }
+                    })
+                    .collect();
+                Request::Files(ranges)
+            }
+            WebSeedKind::HttpSeed => {
+                Request::Piece(piece_url(&self.url, &torrent.info_hash, piece, len), len)
+            }
+        };
+
+        self.piece = Some(piece);
+        let job = WebSeedJob {
+            piece: piece,
+            request: request,
+        };
+        if self.jobs.send(job).is_err() {
+            println!("web seed thread of {} is gone", self.url);
+        }
+    }
+
+    pub fn result(self: &mut Self) -> Option<WebSeedResult> {
+        let r = self.done.try_recv().ok()?;
+        self.piece = None;
+        match (&r.result, r.retry_after) {
+            (Ok(_), _) => {
+                self.failures = 0;
+                self.retry_at = None;
+            }
+            // Busy isn't a failure, the server said how long to wait
+            (Err(_), Some(after)) => {
+                self.retry_at = Some(time::Instant::now() + after.min(MAX_BACKOFF));
+            }
+            (Err(_), None) => self.back_off(),
+        }
+        Some(r)
+    }
+
+    // Waits before the next request, longer with every failure in a row
+    fn back_off(self: &mut Self) {
+        self.failures += 1;
+        let backoff = MIN_BACKOFF
+            .saturating_mul(1 << (self.failures - 1).min(16))
+            .min(MAX_BACKOFF);
+        self.retry_at = Some(time::Instant::now() + backoff);
+    }
+}
+
+// <url>?info_hash=<hash>&piece=<index>&ranges=<start>-<end>, the range is inclusive
+fn piece_url(base: &str, info_hash: &[u8; 20], piece: u32, len: u64) -> String {
+    let sep = if base.contains('?') { '&' } else { '?' };
+    format!(
+        "{}{}info_hash={}&piece={}&ranges=0-{}",
+        base,
+        sep,
+        percent_encode(info_hash, NON_ALPHANUMERIC),
+        piece,
+        len - 1
+    )
+}
+
+// Single file torrents use the url as is, unless it names a directory
+fn file_url(base: &str, f: &TorrentFile) -> String {
+    if f.path.len() == 1 && !base.ends_with('/') {
+        return base.to_string();
+    }
+    let mut url = base.to_string();
+    if !url.ends_with('/') {
+        url.push('/');
+    }
+    let path: Vec<String> = f
+        .path
+        .iter()
+        .map(|p| utf8_percent_encode(p, PATH_SEGMENT).to_string())
+        .collect();
+    url + &path.join("/")
+}
+
+fn fetch(ranges: &[FileRange]) -> PieceData {
+    let mut data = Vec::new();
+    let mut addr = SocketAddr::from(([0, 0, 0, 0], 0));
+    for r in ranges {
+        let url = match &r.url {
+            Some(u) => u,
+            None => {
+                data.resize(data.len() + r.len as usize, 0);
+                continue;
+            }
+        };
+
+        let range = format!("bytes={}-{}", r.offset, r.offset + r.len - 1);
+        let resp = http::get(url, &[("Range", range)], r.len as usize, WEB_SEED_TIMEOUT)?;
+        match resp.status {
+            206 => {}
+            // Servers without range support send the whole file
+            200 if r.offset == 0 => {}
+            s => return Err(easy_err(&format!("web seed answered with status {}", s))),
+        }
+        if resp.body.len() as u64 != r.len {
+            return Err(easy_err("web seed sent the wrong length"));
+        }
+        data.extend(resp.body);
+        addr = resp.addr;
+    }
+    Ok((addr, data))
+}
+
+// Hash based seeds answer 503 with the seconds to wait as the body when they are busy
+fn fetch_piece(url: &str, len: u64) -> (PieceData, Option<time::Duration>) {
+    let resp = match http::get(url, &[], len as usize, WEB_SEED_TIMEOUT) {
+        Ok(r) => r,
+        Err(e) => return (Err(e), None),
+    };
+    match resp.status {
+        200 if resp.body.len() as u64 == len => (Ok((resp.addr, resp.body)), None),
+        200 => (Err(easy_err("http seed sent the wrong length")), None),
+        503 => {
+            let after = String::from_utf8_lossy(&resp.body)
+                .trim()
+                .parse::<u64>()
+                .ok();
+            (
+                Err(easy_err("http seed is busy")),
+                after.map(time::Duration::from_secs),
+            )
+        }
+        s => (
+            Err(easy_err(&format!("http seed answered with status {}", s))),
+            None,
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::tests::single_file_torrent;
+    use std::{
+        collections::HashMap,
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+    };
+
+    // Answers every request with the response of handle for its path and range
+    fn serve(
+        mut handle: impl FnMut(&str, Option<(usize, usize)>) -> Vec<u8> + Send + 'static,
+    ) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for conn in listener.incoming() {
+                let mut conn = conn.unwrap();
+                let mut lines = BufReader::new(conn.try_clone().unwrap()).lines();
+                let path = lines
+                    .next()
+                    .unwrap()
+                    .unwrap()
+                    .split(' ')
+                    .nth(1)
+                    .unwrap()
+                    .to_string();
+                let mut range = None;
+                for l in lines {
+                    let l = l.unwrap();
+                    if l.is_empty() {
+                        break;
+                    }
+                    if let Some(r) = l.strip_prefix("Range: bytes=") {
+                        let (a, b) = r.split_once('-').unwrap();
+                        range = Some((a.parse::<usize>().unwrap(), b.parse::<usize>().unwrap()));
+                    }
+                }
+
+                conn.write_all(&handle(&path, range)).unwrap();
+            }
+        });
+        addr
+    }
+
+    fn response(status: &str, body: &[u8]) -> Vec<u8> {
+        let mut r = format!(
+            "HTTP/1.1 {}\r\nContent-Length: {}\r\n\r\n",
+            status,
+            body.len()
+        )
+        .into_bytes();
+        r.extend(body);
+        r
+    }
+
+    fn torrent(files: Vec<TorrentFile>) -> Torrent {
+        Torrent {
+            name: "t".to_string(),
+            files: files,
+            ..single_file_torrent(50000, 32768)
+        }
+    }
+
+    fn wait_for(seed: &mut WebSeed) -> WebSeedResult {
+        loop {
+            if let Some(r) = seed.result() {
+                return r;
+            }
+            thread::sleep(time::Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn test_download_multi_file_piece() {
+        let a: Vec<u8> = (0..20000).map(|i| i as u8).collect();
+        let b: Vec<u8> = (0..30000).map(|i| (i * 7) as u8).collect();
+        let files = HashMap::from([("/t/a%20b", a.clone()), ("/t/d/c", b.clone())]);
+        let addr = serve(move |path, range| match (files.get(path), range) {
+            (Some(f), Some((a, b))) => response("206 Partial Content", &f[a..=b]),
+            _ => response("404 Not Found", b""),
+        });
+
+        let file = |path: &[&str], len| TorrentFile {
+            path: path.iter().map(|p| p.to_string()).collect(),
+            length: len,
+            pad: false,
+        };
+        let torrent = torrent(vec![
+            file(&["t", "a b"], 20000),
+            file(&["t", "d", "c"], 30000),
+        ]);
+
+        // The first piece spans both files
+        let mut seed = WebSeed::new(format!("http://{}", addr), WebSeedKind::UrlList);
+        seed.download(&torrent, 0);
+        assert!(!seed.is_ready());
+        let r = wait_for(&mut seed);
+        assert_eq!(r.piece, 0);
+        assert_eq!(r.result.unwrap().1, [a, b].concat()[..32768]);
+        assert!(seed.is_ready());
+
+        // Failures back off
+        let mut seed = WebSeed::new(format!("http://{}/missing/", addr), WebSeedKind::UrlList);
+        seed.download(&torrent, 1);
+        assert!(wait_for(&mut seed).result.is_err());
+        assert!(!seed.is_ready());
+    }
+
+    #[test]
+    fn test_http_seed_retry_after() {
+        let mut torrent = torrent(Vec::new());
+        torrent.info_hash[0] = b' ';
+        let data = vec![7_u8; 50000 - 32768];
+
+        // Busy on the first request
+        let mut busy = true;
+        let piece = data.clone();
+        let addr = serve(move |path, _| {
+            if busy {
+                busy = false;
+                return response("503 Service Unavailable", b"0");
+            }
+            match path {
+                "/seed?info_hash=%20%00%00%00%00%00%00%00%00%00%00%00%00%00%00%00%00%00%00%00&piece=1&ranges=0-17231" => {
+                    response("200 OK", &piece)
+                }
+                _ => response("404 Not Found", b""),
+            }
+        });
+
+        let mut seed = WebSeed::new(format!("http://{}/seed", addr), WebSeedKind::HttpSeed);
+        seed.download(&torrent, 1);
+        assert!(wait_for(&mut seed).result.is_err());
+        // The server asked to come back right away
+        assert!(seed.is_ready());
+
+        seed.download(&torrent, 1);
+        let r = wait_for(&mut seed);
+        assert_eq!(r.piece, 1);
+        assert_eq!(r.result.unwrap().1, data);
+    }
+}